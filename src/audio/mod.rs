@@ -3,6 +3,8 @@
 // ============================================
 use cpal::platform::Host;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use opus::{Encoder, Decoder, Application};
@@ -11,6 +13,17 @@ use crate::log;
 
 const SAMPLE_RATE: u32 = 48000;
 const CHANNELS: Channels = Channels::Mono;
+// Transmit timeout warning tone
+const WARNING_TONE_HZ: f32 = 1000.0;
+const WARNING_TONE_MS: u32 = 300;
+// Default attenuation applied to everything else while a priority talker speaks
+const DEFAULT_DUCKING_DB: f32 = -12.0;
+// Largest Opus frame, 120 ms at 48 kHz
+const MAX_FRAME_SAMPLES: usize = 5760;
+// Audio kept per talker when playback falls behind, half a second
+const MAX_QUEUED_SAMPLES: usize = SAMPLE_RATE as usize / 2;
+// Longer gaps aren't worth papering over, the stream just resumes
+const MAX_CONCEALED_FRAMES: u64 = 3;
 
 pub type FormattedAudio = Result<Vec<u8>, opus::Error>;
 
@@ -195,4 +208,368 @@ pub fn decode_opus_to_pcm(opus_data: &[u8]) -> Result<Vec<f32>, opus::Error> {
     pcm_data.truncate(decoded_samples * 1);
     Ok(pcm_data)
 }
+// Samples an Opus packet decodes to, used to advance frame timestamps
+pub fn opus_packet_samples(opus_data: &[u8]) -> u32 {
+    opus::packet::get_nb_samples(opus_data, SAMPLE_RATE).unwrap_or(0) as u32
+}
+// ============================================
+//        Ducking Rules
+// Which talkers attenuate everything else and
+// by how much.
+// ============================================
+#[derive(Debug, Clone)]
+pub struct DuckingRules {
+    // Talkers in these groups duck the rest of the playback
+    pub priority_groups: Vec<String>,
+    // Admins duck the rest of the playback whatever the group
+    pub duck_for_admins: bool,
+    // Attenuation in dB, negative values make things quieter
+    pub attenuation_db: f32,
+}
+
+impl Default for DuckingRules {
+    fn default() -> Self {
+        Self {
+            priority_groups: Vec::new(),
+            duck_for_admins: true,
+            attenuation_db: DEFAULT_DUCKING_DB,
+        }
+    }
+}
+// ============================================
+//        Ducker
+// Tracks who is talking and hands out the gain
+// each source should be played at.
+// ============================================
+#[derive(Debug, Default)]
+pub struct Ducker {
+    rules: DuckingRules,
+    scan_priority: Option<String>,
+    // Talkers currently ducking everyone else: (PeerId, Group)
+    ducking_sources: HashSet<(String, String)>,
+}
+
+impl Ducker {
+    pub fn new(rules: DuckingRules) -> Self {
+        Self {
+            rules,
+            scan_priority: None,
+            ducking_sources: HashSet::new(),
+        }
+    }
+    pub fn set_rules(&mut self, rules: DuckingRules) {
+        self.rules = rules;
+    }
+    // The priority group of a scan in duck mode, on top of the rules
+    pub fn set_scan_priority(&mut self, group: Option<String>) {
+        self.scan_priority = group;
+    }
+    pub fn on_talk_started(&mut self, peer_id: &str, group: &str, is_admin: bool) {
+        let is_priority = self.rules.priority_groups.iter().any(|g| g == group)
+            || self.scan_priority.as_deref() == Some(group)
+            || (is_admin && self.rules.duck_for_admins);
+        if is_priority {
+            self.ducking_sources.insert((peer_id.to_string(), group.to_string()));
+        }
+    }
+    pub fn on_talk_ended(&mut self, peer_id: &str, group: &str) {
+        self.ducking_sources.remove(&(peer_id.to_string(), group.to_string()));
+    }
+    // Emergency calls duck every other source in every group
+    pub fn on_emergency_started(&mut self, peer_id: &str) {
+        self.ducking_sources.insert((peer_id.to_string(), String::new()));
+    }
+    pub fn on_emergency_ended(&mut self, peer_id: &str) {
+        self.ducking_sources.remove(&(peer_id.to_string(), String::new()));
+    }
+    pub fn is_ducking(&self) -> bool {
+        !self.ducking_sources.is_empty()
+    }
+    // Gain for a source: full volume for ducking talkers, attenuated for
+    // everyone else while any of them is talking
+    pub fn gain_for(&self, peer_id: &str) -> f32 {
+        let is_source = self.ducking_sources.iter().any(|(source, _)| source == peer_id);
+        if is_source || !self.is_ducking() {
+            1.0
+        } else {
+            db_to_gain(self.rules.attenuation_db)
+        }
+    }
+}
+// ============================================
+//        Mixer
+// ============================================
+// Convert decibels to a linear gain factor
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+// Mix decoded PCM sources, each with its own gain, into a single buffer
+pub fn mix_sources(sources: &[(&[f32], f32)]) -> Vec<f32> {
+    let len = sources.iter().map(|(samples, _)| samples.len()).max().unwrap_or(0);
+    let mut mixed = vec![0.0; len];
+    for (samples, gain) in sources {
+        for (out, sample) in mixed.iter_mut().zip(samples.iter()) {
+            *out += sample * gain;
+        }
+    }
+    for sample in mixed.iter_mut() {
+        *sample = sample.clamp(-1.0, 1.0);
+    }
+    mixed
+}
+// ============================================
+//        Playback Mixer
+// Decoded audio waiting to be played, one queue
+// per talker, mixed at the gain the ducker
+// gives each of them.
+// ============================================
+#[derive(Debug)]
+pub struct PlaybackMixer {
+    ducker: Ducker,
+    // Talker volume from 0 to 1, an admin may set it remotely
+    volume: f32,
+    // <PeerId, Samples>
+    sources: HashMap<String, VecDeque<f32>>,
+    // One per (sender, group) stream, Opus carries state from packet to
+    // packet and conceals lost ones from it. Each knows its last frame size.
+    decoders: HashMap<(String, String), (Decoder, usize)>,
+    // Local tones, played over everything and never ducked or turned down
+    tones: VecDeque<f32>,
+}
+
+pub type SharedMixer = Arc<Mutex<PlaybackMixer>>;
+
+impl Default for PlaybackMixer {
+    fn default() -> Self {
+        Self::new(DuckingRules::default())
+    }
+}
+
+impl PlaybackMixer {
+    pub fn new(rules: DuckingRules) -> Self {
+        Self {
+            ducker: Ducker::new(rules),
+            volume: 1.0,
+            sources: HashMap::new(),
+            decoders: HashMap::new(),
+            tones: VecDeque::new(),
+        }
+    }
+    pub fn ducker(&mut self) -> &mut Ducker {
+        &mut self.ducker
+    }
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+    pub fn push(&mut self, peer_id: &str, samples: &[f32]) {
+        let queue = self.sources.entry(peer_id.to_string()).or_default();
+        queue.extend(samples);
+        // Drop the oldest audio rather than fall further behind
+        let excess = queue.len().saturating_sub(MAX_QUEUED_SAMPLES);
+        queue.drain(..excess);
+    }
+    // Decodes a talker's mono packet on its stream's decoder, concealing the
+    // frames lost just before it
+    pub fn push_opus(&mut self, sender: &str, group: &str, packet: &[u8], lost: u64)
+    -> Result<(), opus::Error> {
+        let (decoder, frame_samples) = match self.decoders.entry((sender.to_string(), group.to_string())) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert((Decoder::new(SAMPLE_RATE, CHANNELS)?, 0)),
+        };
+        let mut pcm = Vec::new();
+        let mut buffer = vec![0.0; MAX_FRAME_SAMPLES];
+        if *frame_samples > 0 {
+            for _ in 0..lost.min(MAX_CONCEALED_FRAMES) {
+                let concealed = decoder.decode_float(&[], &mut buffer[..*frame_samples], false)?;
+                pcm.extend_from_slice(&buffer[..concealed]);
+            }
+        }
+        let decoded = decoder.decode_float(packet, &mut buffer, false)?;
+        *frame_samples = decoded;
+        pcm.extend_from_slice(&buffer[..decoded]);
+        self.push(sender, &pcm);
+        Ok(())
+    }
+    // The talker is done, the next transmission starts from a fresh decoder
+    pub fn end_stream(&mut self, sender: &str, group: &str) {
+        self.decoders.remove(&(sender.to_string(), group.to_string()));
+    }
+    // A talker that left without ending its streams
+    pub fn remove_talker(&mut self, sender: &str) {
+        self.decoders.retain(|(talker, _), _| talker != sender);
+        self.sources.remove(sender);
+    }
+    pub fn push_tone(&mut self, samples: &[f32]) {
+        self.tones.extend(samples);
+    }
+    // The next len mono samples, silence where nobody is talking
+    pub fn mix(&mut self, len: usize) -> Vec<f32> {
+        let mut chunks: Vec<(Vec<f32>, f32)> = self.sources.iter_mut()
+            .map(|(peer_id, queue)| {
+                let count = len.min(queue.len());
+                (queue.drain(..count).collect(), self.ducker.gain_for(peer_id) * self.volume)
+            })
+            .collect();
+        self.sources.retain(|_, queue| !queue.is_empty());
+        let count = len.min(self.tones.len());
+        chunks.push((self.tones.drain(..count).collect(), 1.0));
+
+        let sources: Vec<(&[f32], f32)> = chunks.iter()
+            .map(|(samples, gain)| (samples.as_slice(), *gain))
+            .collect();
+        let mut mixed = mix_sources(&sources);
+        mixed.resize(len, 0.0);
+        mixed
+    }
+}
+// ============================================
+//        Start Playback Stream
+// ============================================
+// Plays whatever the mixer holds, the same sample on every channel
+pub fn start_playback_stream(output_device: &cpal::Device, config: &cpal::StreamConfig,
+    mixer: SharedMixer) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    let channels = (config.channels as usize).max(1);
+    let stream = output_device.build_output_stream(
+        config,
+        move |output_data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            let mixed = match mixer.lock() {
+                Ok(mut mixer) => mixer.mix(output_data.len() / channels),
+                Err(_) => Vec::new(),
+            };
+            for (frame, sample) in output_data.chunks_mut(channels).zip(mixed.iter().chain(std::iter::repeat(&0.0))) {
+                frame.fill(*sample);
+            }
+        },
+        |err| log::log_message(&format!("An error occured on the playback stream: {}", err)),
+        None
+    );
 
+    match stream {
+        Ok(s) => {
+            if let Err(err) = s.play() {
+                log::log_message(&format!("Failed to start playback stream: {}", err));
+            }
+            Ok(s)
+        }
+        Err(e) => {
+            log::log_message(&format!("Failed to build playback stream: {}", e));
+            Err(e)
+        }
+    }
+}
+// ============================================
+//        Warning Tone
+// Beep played to a talker about to hit the
+// transmit timeout.
+// ============================================
+pub fn generate_warning_tone() -> Vec<f32> {
+    let samples = SAMPLE_RATE * WARNING_TONE_MS / 1000;
+    (0..samples)
+        .map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            0.3 * (2.0 * std::f32::consts::PI * WARNING_TONE_HZ * t).sin()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mixer(priority_groups: &[&str]) -> PlaybackMixer {
+        PlaybackMixer::new(DuckingRules {
+            priority_groups: priority_groups.iter().map(|g| g.to_string()).collect(),
+            duck_for_admins: true,
+            attenuation_db: -20.0,
+        })
+    }
+
+    // One sample from each talker, bob at 0.5 and carol at 0.25
+    fn play(mixer: &mut PlaybackMixer) -> f32 {
+        mixer.push("bob", &[0.5]);
+        mixer.push("carol", &[0.25]);
+        mixer.mix(1)[0]
+    }
+
+    #[test]
+    fn priority_talker_ducks_the_others_until_done() {
+        let mut mixer = mixer(&["dispatch"]);
+        assert!((play(&mut mixer) - 0.75).abs() < 1e-6);
+
+        mixer.ducker().on_talk_started("carol", "dispatch", false);
+        assert!((play(&mut mixer) - (0.5 * 0.1 + 0.25)).abs() < 1e-6);
+
+        mixer.ducker().on_talk_ended("carol", "dispatch");
+        assert!((play(&mut mixer) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn ordinary_talkers_duck_nobody() {
+        let mut mixer = mixer(&["dispatch"]);
+        mixer.ducker().on_talk_started("carol", "ops", false);
+        assert!((play(&mut mixer) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn emergency_ducks_every_group() {
+        let mut mixer = mixer(&[]);
+        mixer.ducker().on_emergency_started("carol");
+        assert!((play(&mut mixer) - (0.5 * 0.1 + 0.25)).abs() < 1e-6);
+        mixer.ducker().on_emergency_ended("carol");
+        assert!((play(&mut mixer) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn scan_priority_group_ducks_like_the_rules() {
+        let mut mixer = mixer(&[]);
+        mixer.ducker().set_scan_priority(Some("dispatch".to_string()));
+        mixer.ducker().on_talk_started("carol", "dispatch", false);
+        assert!((play(&mut mixer) - (0.5 * 0.1 + 0.25)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn volume_turns_down_talkers_but_not_tones() {
+        let mut mixer = mixer(&[]);
+        mixer.set_volume(0.5);
+        assert!((play(&mut mixer) - 0.375).abs() < 1e-6);
+        mixer.push_tone(&[0.3]);
+        assert!((mixer.mix(1)[0] - 0.3).abs() < 1e-6);
+        mixer.set_volume(4.0);
+        assert_eq!(mixer.volume(), 1.0);
+    }
+
+    #[test]
+    fn mixer_pads_with_silence_and_bounds_its_queues() {
+        let mut mixer = mixer(&[]);
+        mixer.push("bob", &[0.5, 0.5]);
+        assert_eq!(mixer.mix(4), vec![0.5, 0.5, 0.0, 0.0]);
+
+        mixer.push("bob", &vec![0.1; MAX_QUEUED_SAMPLES + 10]);
+        assert_eq!(mixer.mix(MAX_QUEUED_SAMPLES * 2).iter().filter(|s| **s != 0.0).count(), MAX_QUEUED_SAMPLES);
+    }
+
+    #[test]
+    fn each_stream_keeps_its_decoder_and_conceals_gaps() {
+        // A 20 ms mono CELT frame
+        let packet = [0xf8, 0xff, 0xfe, 0x00];
+        let mut mixer = mixer(&[]);
+        mixer.push_opus("bob", "ops", &packet, 0).unwrap();
+        assert_eq!(mixer.sources["bob"].len(), 960);
+        // Two frames lost, both concealed before the new one
+        mixer.push_opus("bob", "ops", &packet, 2).unwrap();
+        assert_eq!(mixer.sources["bob"].len(), 960 * 4);
+        assert_eq!(mixer.decoders.len(), 1);
+
+        mixer.push_opus("bob", "fire", &packet, 0).unwrap();
+        assert_eq!(mixer.decoders.len(), 2);
+        mixer.end_stream("bob", "ops");
+        assert_eq!(mixer.decoders.len(), 1);
+        mixer.mix(960 * 5);
+        // Long gaps are only concealed in part
+        mixer.push_opus("bob", "fire", &packet, 50).unwrap();
+        assert_eq!(mixer.sources["bob"].len(), 960 * (MAX_CONCEALED_FRAMES as usize + 1));
+    }
+}
@@ -0,0 +1,95 @@
+// ============================================
+//          Room Secret Authentication
+// The signaling server of a protected room sends
+// a random challenge to every peer registering,
+// which answers with an HMAC of it keyed by the
+// room secret. The secret never crosses the wire.
+// ============================================
+
+// ============================================
+//                  Imports
+// ============================================
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::collections::HashMap;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Room metadata flag, published over mDNS so joiners know to ask for it
+pub const PROTECTED_METADATA_KEY: &str = "protected";
+const CHALLENGE_LEN: usize = 32;
+const SESSION_TOKEN_LEN: usize = 32;
+
+// ============================================
+//            Challenge / Response
+// ============================================
+
+pub fn generate_challenge() -> String {
+    random_hex(CHALLENGE_LEN)
+}
+
+// Lets a peer that lost its socket take its place back, proof enough
+// since only that peer ever saw it
+pub fn generate_session_token() -> String {
+    random_hex(SESSION_TOKEN_LEN)
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// Bound to the peer id so an answer can't be replayed under another name
+pub fn respond(secret: &str, challenge: &str, peer_id: &str) -> String {
+    hex::encode(mac(secret, challenge, peer_id).finalize().into_bytes())
+}
+
+pub fn verify(secret: &str, challenge: &str, peer_id: &str, response: &str) -> bool {
+    match hex::decode(response) {
+        Ok(response) => mac(secret, challenge, peer_id).verify_slice(&response).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn mac(secret: &str, challenge: &str, peer_id: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(challenge.as_bytes());
+    mac.update(b":");
+    mac.update(peer_id.as_bytes());
+    mac
+}
+
+pub fn is_protected(metadata: &HashMap<String, serde_json::Value>) -> bool {
+    metadata.get(PROTECTED_METADATA_KEY)
+        .and_then(|protected| protected.as_bool())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_verify_only_with_the_same_secret_challenge_and_peer() {
+        let challenge = generate_challenge();
+        let response = respond("hunter2", &challenge, "alice");
+        assert!(verify("hunter2", &challenge, "alice", &response));
+        assert!(!verify("hunter3", &challenge, "alice", &response));
+        assert!(!verify("hunter2", &generate_challenge(), "alice", &response));
+        // Replayed under another name
+        assert!(!verify("hunter2", &challenge, "mallory", &response));
+        assert!(!verify("hunter2", &challenge, "alice", "not hex"));
+        assert!(!verify("hunter2", &challenge, "alice", &response[..32]));
+    }
+
+    #[test]
+    fn rooms_are_open_unless_flagged() {
+        let mut metadata = HashMap::new();
+        assert!(!is_protected(&metadata));
+        metadata.insert(PROTECTED_METADATA_KEY.to_string(), serde_json::json!(true));
+        assert!(is_protected(&metadata));
+    }
+}
@@ -0,0 +1,68 @@
+// ============================================
+//                  Imports
+// ============================================
+use serde::{Deserialize, Serialize};
+
+// ============================================
+//                 Structures
+// ============================================
+
+// Private call signaling carried over the control data channel
+// Example: {"call": "ring", "call_id": 3}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum CallMessage {
+    Ring { call_id: u64 },
+    Accept { call_id: u64 },
+    Decline { call_id: u64 },
+    HangUp { call_id: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallState {
+    // We called and the other side hasn't answered yet
+    Dialing,
+    // Someone is calling us
+    Ringing,
+    Active,
+    Declined,
+    Ended,
+}
+
+// The one private call a peer can be in at a time
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectCall {
+    pub call_id: u64,
+    pub peer_id: String,
+    pub state: CallState,
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl CallMessage {
+    pub fn call_id(&self) -> u64 {
+        match self {
+            CallMessage::Ring { call_id }
+            | CallMessage::Accept { call_id }
+            | CallMessage::Decline { call_id }
+            | CallMessage::HangUp { call_id } => *call_id,
+        }
+    }
+}
+
+impl DirectCall {
+    pub fn is_active(&self) -> bool {
+        self.state == CallState::Active
+    }
+    // Whether a message is about this call and comes from the other side
+    pub fn matches(&self, peer_id: &str, call_id: u64) -> bool {
+        self.peer_id == peer_id && self.call_id == call_id
+    }
+    // Group the call's audio frames and end-to-end keys go under. Group
+    // names can't hold a colon, so it never clashes with a real group.
+    pub fn key_group(&self) -> String {
+        format!("call:{}", self.call_id)
+    }
+}
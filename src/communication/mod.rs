@@ -2,18 +2,23 @@
 //                  Imports
 // ============================================
 use bytes::Bytes;
-use tokio_tungstenite::MaybeTlsStream;
-use tokio::net::TcpStream;
 use tokio_tungstenite::WebSocketStream;
-use crate::audio::FormattedAudio;
+use crate::audio::{self, DuckingRules, FormattedAudio, PlaybackMixer, SharedMixer};
+use crate::auth;
+use crate::call::{CallMessage, CallState, DirectCall};
 use crate::log;
 use futures::{SinkExt, StreamExt};
-use futures::stream::SplitSink;
+use futures::stream::{SplitSink, SplitStream};
 use futures::channel::mpsc;
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::protocol::Message;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::APIBuilder;
 use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
@@ -21,14 +26,79 @@ use webrtc::data_channel::data_channel_message::DataChannelMessage;
 use webrtc::data_channel::RTCDataChannel;
 use webrtc::Error;
 use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::peer_connection::certificate::RTCCertificate;
 use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
-use webrtc::peer_connection::policy::bundle_policy::RTCBundlePolicy;
-use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
-use webrtc::peer_connection::policy::rtcp_mux_policy::RTCRtcpMuxPolicy;
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
+use crate::control::{self, ControlAck, ControlCommand, ControlMessage, ControlRequest};
 use crate::db;
+use crate::e2e::{E2eError, GroupKeys, KeyMessage, EMERGENCY_KEY_GROUP};
+use crate::events::{self, PeerInfo, PeerStats, SessionEvent};
+use crate::floor::{self, FloorArbiter, FloorDecision, FloorMessage};
+use crate::ice::{IceConfig, ICE_METADATA_KEY};
+use crate::identity::{self, PinCheck, ShortAuthString};
+use crate::metadata;
+use crate::multicast::{self, MulticastKey, MulticastKeyMessage, MulticastSocket};
+use crate::relay;
+use crate::packet::{AudioFrame, FrameSequencer, FLAG_EMERGENCY, FLAG_END_OF_TRANSMISSION};
+use crate::scan::{ScanConfig, ScannedAudio};
+use crate::tls::{self, SignalingStream};
+use crate::topology::Topology;
+use crate::stats::{self, StreamStats};
+use crate::transmit::{TransmitCheck, TransmitGuard, TransmitLimits};
+
+// ============================================
+//               Reconnection
+// ============================================
+// Time a peer is given to come back on its own before we intervene
+const DISCONNECTED_GRACE_PERIOD: Duration = Duration::from_secs(3);
+// ICE restarts attempted before tearing the connection down
+const ICE_RESTART_ATTEMPTS: u32 = 3;
+// Base delay between ICE restarts, doubled on every attempt
+const ICE_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+// Time to wait for a restarted or re-offered connection to come up
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// First delay before reconnecting to the signaling server, doubled on every
+// failed attempt up to the max
+const SIGNALING_RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+const SIGNALING_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+const SIGNALING_RECONNECT_ATTEMPTS: u32 = 12;
+
+// ============================================
+//               Data Channels
+// ============================================
+// Negotiated ids so both ends open the same channel without on_data_channel
+const AUDIO_CHANNEL_ID: u16 = 0;
+const CONTROL_CHANNEL_ID: u16 = 1;
+const CALL_CHANNEL_ID: u16 = 2;
+// Group channels get an ID hashed from the group name so both ends agree
+// on it without negotiating
+const GROUP_CHANNEL_BASE: u16 = 16;
+const GROUP_CHANNEL_RANGE: u32 = 4096;
+// Time a peer has to acknowledge a control command
+const COMMAND_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// ============================================
+//               Floor Control
+// ============================================
+// Time the arbiter has to grant or deny a floor request
+const FLOOR_REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+// Time a queued request waits for its turn
+const FLOOR_QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+// How often the arbiter checks for expired floors
+const FLOOR_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Unanswered private calls give up after this long
+const CALL_RING_TIMEOUT: Duration = Duration::from_secs(30);
+
+// ============================================
+//               Statistics
+// ============================================
+// How often connection stats are collected
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
 
 // ============================================
 //                 Structures
@@ -36,19 +106,110 @@ use crate::db;
 
 pub struct Destination;
 
+// How we got into the room
+enum SignalingJoin {
+    // New to the room, with the list of peers to offer to
+    Registered(String),
+    // Picked up our old session, the others never saw us leave
+    Resumed,
+}
+
+// Data channels opened on every peer connection
+struct PeerChannels {
+    audio: Arc<RTCDataChannel>,
+    control: Arc<RTCDataChannel>,
+    call: Arc<RTCDataChannel>,
+}
+
+type GroupChannels = Arc<Mutex<HashMap<String, HashMap<String, Arc<RTCDataChannel>>>>>;
+type AudioReceivers = Arc<Mutex<HashMap<String, Vec<mpsc::Sender<AudioFrame>>>>>;
+type PendingCommands = Arc<Mutex<HashMap<u64, oneshot::Sender<ControlAck>>>>;
+type ScanState = Arc<Mutex<Option<(ScanConfig, mpsc::Sender<ScannedAudio>)>>>;
+type FloorWaiters = Arc<Mutex<HashMap<String, oneshot::Sender<FloorMessage>>>>;
+type SignalingSink = SplitSink<WebSocketStream<SignalingStream>, Message>;
+type SignalingReceiver = SplitStream<WebSocketStream<SignalingStream>>;
+type MulticastSockets = Arc<Mutex<HashMap<String, (Arc<MulticastSocket>, JoinHandle<()>)>>>;
+
 #[derive(Clone)]
 pub struct WebRTCModule {
     api: Arc<Mutex<webrtc::api::API>>,
     // Peer Connections: <Name, PeerConnection>
-    peer_connections: Arc<Mutex<HashMap<String, RTCPeerConnection>>>,
-    // Audio Data Channels: <Group, DataChannel>
-    audio_data_channels: HashMap<String, Vec<Arc<RTCDataChannel>>>,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<RTCPeerConnection>>>>,
+    // Audio Data Channels, one per group shared with a peer: <Group, <PeerId, DataChannel>>
+    audio_data_channels: GroupChannels,
+    // Room wide audio channel of each peer, used by emergency calls: <PeerId, DataChannel>
+    room_audio_channels: Arc<Mutex<HashMap<String, Arc<RTCDataChannel>>>>,
+    // Control Data Channels: <PeerId, DataChannel>
+    control_channels: Arc<Mutex<HashMap<String, Arc<RTCDataChannel>>>>,
+    // Private Call Data Channels: <PeerId, DataChannel>
+    call_channels: Arc<Mutex<HashMap<String, Arc<RTCDataChannel>>>>,
+    direct_call: Arc<Mutex<Option<DirectCall>>>,
+    call_audio_receivers: Arc<Mutex<Vec<mpsc::Sender<Vec<u8>>>>>,
+    // Commands waiting for an ack: <CommandId, Ack>
+    pending_commands: PendingCommands,
+    next_command_id: Arc<AtomicU64>,
+    // Room metadata, the source of truth for roles
+    room_name: Arc<Mutex<String>>,
+    room_metadata: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+    // Muted by an admin, unlike audio_sending_active the user can't lift it
+    admin_muted: Arc<Mutex<bool>>,
+    // Floor arbitration, only used while we are the arbiter
+    floor_arbiter: Arc<Mutex<FloorArbiter>>,
+    // Who holds the floor in each group: <Group, PeerId>
+    floor_holders: Arc<Mutex<HashMap<String, String>>>,
+    // Our own floor requests waiting for an answer: <Group, Answer>
+    floor_waiters: FloorWaiters,
+    // Transmit timeout per group, overrides what room metadata says
+    transmit_guard: Arc<Mutex<TransmitGuard>>,
+    transmit_limits: Arc<Mutex<HashMap<String, TransmitLimits>>>,
+    // Peer making an emergency call, it preempts every group
+    emergency_holder: Arc<Mutex<Option<String>>>,
     audio_sending_active: Arc<Mutex<bool>>,
     audio_receiving_active: Arc<Mutex<bool>>,
+    // Audio Receivers handed out by receive_audio: <Group, Receivers>
+    audio_receivers: AudioReceivers,
+    // Numbering of the frames we send, and what we heard from each
+    // talker: <(PeerId, Group), Stats>
+    frame_sequencer: Arc<Mutex<FrameSequencer>>,
+    receive_streams: Arc<Mutex<HashMap<(String, String), StreamStats>>>,
+    // Latest connection stats of each peer: <PeerId, Stats>
+    peer_stats: Arc<Mutex<HashMap<String, PeerStats>>>,
+    // Scan mode: groups monitored at once and where their audio goes
+    scan: ScanState,
+    // What the frontend plays, every talker at the gain the ducker gives it
+    playback: SharedMixer,
     // Peer Groups: <PeerId, Group Membership>
-    peer_groups: HashMap<String, Vec<String>>,
-    ws_sink: Option<Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, tokio_tungstenite::tungstenite::Message>>>>,
-    #[allow(dead_code)]
+    peer_groups: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    // Peers currently going through an ICE restart or re-offer
+    recovering_peers: Arc<Mutex<HashSet<String>>>,
+    // Peers with no direct path, their audio goes through the room host
+    relayed_peers: Arc<Mutex<HashSet<String>>>,
+    // LAN multicast transport: the interface it runs on, the keys handed
+    // out by the host and the sockets of our multicast groups: <Group, ...>
+    multicast_interface: Arc<Mutex<Option<Ipv4Addr>>>,
+    multicast_keys: Arc<Mutex<HashMap<String, MulticastKey>>>,
+    multicast_sockets: MulticastSockets,
+    // End to end keys, ours for each group and those of other members
+    group_keys: Arc<Mutex<GroupKeys>>,
+    // Device certificate from the db, the same in every DTLS handshake
+    certificate: RTCCertificate,
+    // Fingerprints peers registered with: <PeerId, Fingerprint>
+    announced_fingerprints: Arc<Mutex<HashMap<String, String>>>,
+    // Only accept admin commands from devices we paired with
+    require_trusted_admins: Arc<Mutex<bool>>,
+    // Password of a protected room, answers the server's challenge
+    room_secret: Arc<Mutex<Option<String>>>,
+    // Token the signaling server gave us to resume with if the socket drops
+    session_token: Arc<Mutex<Option<String>>>,
+    // Stable id from the db, what peers know us by
+    device_id: String,
+    // Names people go by, ours included: <PeerId, DisplayName>
+    display_names: Arc<Mutex<HashMap<String, String>>>,
+    local_peer_id: Arc<Mutex<String>>,
+    local_groups: Arc<Mutex<Vec<String>>>,
+    signaling_sender: Arc<Mutex<Option<mpsc::Sender<Message>>>>,
+    events: events::EventSender,
+    ws_sink: Option<Arc<Mutex<SignalingSink>>>,
     pool: db::SqlitePool
 }
 
@@ -60,42 +221,187 @@ impl WebRTCModule {
     pub async fn new(pool: &db::SqlitePool) -> Result<Self, webrtc::Error> {
         // Initialize WebRTC communication
         let api = create_api().await?;
+        let certificate = identity::load_or_create_certificate(pool)?;
+        let device_key = identity::device_key(&certificate)
+            .map_err(|e| webrtc::Error::new(format!("Failed to load device key: {}", e)))?;
+        let (device_id, display_name) = identity::load_or_create_device(pool)
+            .map_err(|e| webrtc::Error::new(format!("Failed to load device id: {}", e)))?;
+        let display_names = display_name.into_iter()
+            .map(|name| (device_id.clone(), name))
+            .collect();
 
         Ok(Self{
             api : Arc::new(Mutex::new(api)),
             peer_connections: Arc::new(Mutex::new(HashMap::new())),
-            audio_data_channels: HashMap::new(),
+            audio_data_channels: Arc::new(Mutex::new(HashMap::new())),
+            room_audio_channels: Arc::new(Mutex::new(HashMap::new())),
+            control_channels: Arc::new(Mutex::new(HashMap::new())),
+            call_channels: Arc::new(Mutex::new(HashMap::new())),
+            direct_call: Arc::new(Mutex::new(None)),
+            call_audio_receivers: Arc::new(Mutex::new(Vec::new())),
+            pending_commands: Arc::new(Mutex::new(HashMap::new())),
+            next_command_id: Arc::new(AtomicU64::new(1)),
+            room_name: Arc::new(Mutex::new(String::new())),
+            room_metadata: Arc::new(Mutex::new(HashMap::new())),
+            admin_muted: Arc::new(Mutex::new(false)),
+            floor_arbiter: Arc::new(Mutex::new(FloorArbiter::default())),
+            floor_holders: Arc::new(Mutex::new(HashMap::new())),
+            floor_waiters: Arc::new(Mutex::new(HashMap::new())),
+            transmit_guard: Arc::new(Mutex::new(TransmitGuard::default())),
+            transmit_limits: Arc::new(Mutex::new(HashMap::new())),
+            emergency_holder: Arc::new(Mutex::new(None)),
             audio_sending_active: Arc::new(Mutex::new(true)),
             audio_receiving_active: Arc::new(Mutex::new(true)),
-            peer_groups: HashMap::new(),
+            audio_receivers: Arc::new(Mutex::new(HashMap::new())),
+            frame_sequencer: Arc::new(Mutex::new(FrameSequencer::default())),
+            receive_streams: Arc::new(Mutex::new(HashMap::new())),
+            peer_stats: Arc::new(Mutex::new(HashMap::new())),
+            scan: Arc::new(Mutex::new(None)),
+            playback: Arc::new(std::sync::Mutex::new(PlaybackMixer::default())),
+            peer_groups: Arc::new(Mutex::new(HashMap::new())),
+            recovering_peers: Arc::new(Mutex::new(HashSet::new())),
+            relayed_peers: Arc::new(Mutex::new(HashSet::new())),
+            multicast_interface: Arc::new(Mutex::new(None)),
+            multicast_keys: Arc::new(Mutex::new(HashMap::new())),
+            multicast_sockets: Arc::new(Mutex::new(HashMap::new())),
+            group_keys: Arc::new(Mutex::new(GroupKeys::new(device_key))),
+            certificate,
+            announced_fingerprints: Arc::new(Mutex::new(HashMap::new())),
+            require_trusted_admins: Arc::new(Mutex::new(false)),
+            room_secret: Arc::new(Mutex::new(None)),
+            session_token: Arc::new(Mutex::new(None)),
+            device_id,
+            display_names: Arc::new(Mutex::new(display_names)),
+            local_peer_id: Arc::new(Mutex::new(String::new())),
+            local_groups: Arc::new(Mutex::new(Vec::new())),
+            signaling_sender: Arc::new(Mutex::new(None)),
+            events: events::create_event_channel(),
             ws_sink: None,
             pool: pool.clone()
         })
     }
-    // Group management
-    pub async fn join_group(&mut self, group: &str, data_channel: Arc<RTCDataChannel>) {
-        self.audio_data_channels.entry(group.to_string())
-            .or_insert_with(Vec::new)
-            .push(data_channel);
-        //-------------TODO-------------//
-        //      Update metadata
+    // What peers see of us, to compare out of band
+    pub fn device_fingerprint(&self) -> String {
+        identity::certificate_fingerprint(&self.certificate)
+    }
+    // The peer id to join rooms with, unlike the display name it never changes
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+    // Session events, every subscriber gets its own copy of each event
+    pub fn subscribe(&self) -> events::EventReceiver {
+        self.events.subscribe()
+    }
+    // Room metadata
+    pub async fn set_room_metadata(&self, room_name: &str,
+        metadata: HashMap<String, serde_json::Value>) {
+        *self.room_name.lock().await = room_name.to_string();
+        *self.room_metadata.lock().await = metadata;
+        self.restore_mute_state().await;
+    }
+    pub async fn room_metadata(&self) -> HashMap<String, serde_json::Value> {
+        self.room_metadata.lock().await.clone()
+    }
+    // ICE servers, TURN credentials and policy used by new connections
+    pub async fn set_ice_config(&self, config: IceConfig) {
+        let metadata = {
+            let mut room_metadata = self.room_metadata.lock().await;
+            room_metadata.insert(ICE_METADATA_KEY.to_string(), config.to_metadata());
+            room_metadata.clone()
+        };
+        self.save_room_metadata(&metadata).await;
+    }
+    // How the room is wired and who its host is
+    async fn topology(&self) -> (Topology, Option<String>) {
+        let metadata = self.room_metadata.lock().await;
+        let host = metadata::find_metadata_value(&metadata, "host")
+            .and_then(|host| host.as_str())
+            .map(|host| host.to_string());
+        (Topology::from_metadata(&metadata), host)
     }
-    pub async fn leave_group(&mut self, group: &str, data_channel: Arc<RTCDataChannel>) {
-        if let Some(data_channels) = self.audio_data_channels.get_mut(group) {
-            data_channels.retain(|dc| !Arc::ptr_eq(dc, &data_channel));
+    // The host forwarding everyone's audio in an SFU room
+    async fn forwarder(&self) -> Option<String> {
+        let (topology, host) = self.topology().await;
+        topology.forwarder(host.as_deref()).map(|host| host.to_string())
+    }
+    async fn should_connect(&self, remote_peer_id: &str) -> bool {
+        let local_peer_id = self.local_peer_id.lock().await.clone();
+        let (topology, host) = self.topology().await;
+        topology.should_connect(host.as_deref(), &local_peer_id, remote_peer_id)
+    }
+    async fn save_room_metadata(&self, metadata: &HashMap<String, serde_json::Value>) {
+        let room_name = self.room_name.lock().await.clone();
+        if let Err(e) = db::update_room_metadata(&self.pool, &room_name, metadata) {
+            log::log_message(&format!("Failed to save metadata for {}: {}", room_name, e));
+        }
+    }
+    pub async fn playback_volume(&self) -> f32 {
+        self.playback.lock().map_or(1.0, |playback| playback.volume())
+    }
+    // Group management
+    pub async fn join_group(&self, group: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // Colons delimit signaling messages and mark private call keys
+        if group.contains(':') {
+            return Err(format!("Group name {} can't contain ':'", group).into());
         }
-        //-------------TODO-------------//
-        //      Update metadata
+        let groups = {
+            let mut local_groups = self.local_groups.lock().await;
+            if local_groups.iter().any(|g| g == group) {
+                return Ok(());
+            }
+            // Its audio would share a channel with the other group's
+            if let Some(other) = local_groups.iter().find(|g| group_channel_id(g) == group_channel_id(group)) {
+                return Err(format!("Group {} collides with {} on channel {}, pick another name",
+                    group, other, group_channel_id(group)).into());
+            }
+            local_groups.push(group.to_string());
+            local_groups.clone()
+        };
+        let peer_id = self.local_peer_id.lock().await.clone();
+        self.update_user_groups(&peer_id, groups).await
     }
-    pub async fn update_user_groups(&mut self, peer_id: &str, new_groups: Vec<String>
+    pub async fn leave_group(&self, group: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let groups = {
+            let mut local_groups = self.local_groups.lock().await;
+            local_groups.retain(|g| g != group);
+            local_groups.clone()
+        };
+        self.audio_receivers.lock().await.remove(group);
+        let peer_id = self.local_peer_id.lock().await.clone();
+        self.update_user_groups(&peer_id, groups).await
+    }
+    pub async fn update_user_groups(&self, peer_id: &str, new_groups: Vec<String>
     ) -> Result<(), Box<dyn std::error::Error>> {
-        self.peer_groups.insert(peer_id.to_string(), new_groups.clone());
-
-        // Save peer connection info to database
-        let group_update_message = format!("group_update:{}:{}",
-            peer_id, new_groups.join(",")
-        );
-        self.broadcast_message(&group_update_message).await?;
+        let is_local = *self.local_peer_id.lock().await == peer_id;
+        let old_groups = if is_local {
+            std::mem::replace(&mut *self.local_groups.lock().await, new_groups.clone())
+        } else {
+            self.peer_groups.lock().await
+                .insert(peer_id.to_string(), new_groups.clone())
+                .unwrap_or_default()
+        };
+        self.rotate_group_keys(&old_groups, &new_groups).await;
+        events::emit(&self.events, SessionEvent::GroupMembershipChanged {
+            peer_id: peer_id.to_string(),
+            groups: new_groups.clone(),
+        });
+        if is_local {
+            let peers: Vec<String> = self.peer_connections.lock().await.keys().cloned().collect();
+            for peer in peers {
+                self.sync_group_channels(&peer).await;
+            }
+            self.sync_multicast().await;
+            // Peers open or close channels for the groups we now share. The
+            // server only relays updates about the sender itself.
+            let group_update_message = format!("group_update:{}:{}",
+                peer_id, new_groups.join(",")
+            );
+            self.broadcast_message(&group_update_message).await?;
+        } else {
+            self.rotate_multicast_keys(&left_groups(&old_groups, &new_groups), peer_id).await;
+            self.sync_group_channels(peer_id).await;
+            self.share_multicast_keys(peer_id).await;
+        }
         Ok(())
     }
     // Broadcasting
@@ -116,250 +422,2736 @@ impl WebRTCModule {
         peer_id: &str,
         initial_groups: Vec<String>
     ) -> Result<(), Box<dyn std::error::Error>> {
-        // The connect_async simply connects to a given url which in this case is of ws:// type
-        let (ws_stream, _) = connect_async(signaling_url).await
-            .expect("Failed to connect");
-        let (ws_sink, mut ws_stream) = ws_stream.split();
+        *self.local_peer_id.lock().await = peer_id.to_string();
+        *self.local_groups.lock().await = initial_groups.clone();
+        self.restore_mute_state().await;
 
+        // Register with the signaling server
+        let (ws_sink, mut ws_stream, joined) = self.join_signaling(signaling_url, None).await?;
         let ws_sink = Arc::new(Mutex::new(ws_sink));
         self.ws_sink = Some(ws_sink.clone());
 
-        {
-            let mut sink = ws_sink.lock().await;
-            sink.send(Message::Text(
-                format!("register:{}:{}", peer_id, initial_groups.join(",")))
-            ).await?;
+        let (signaling_sender, mut signaling_receiver) = mpsc::channel(100);
+        let ws_sink_clone = Arc::clone(&ws_sink);
+        *self.signaling_sender.lock().await = Some(signaling_sender.clone());
+
+        // handle outgoing signaling messages
+        tokio::spawn(async move {
+            while let Some(message) = signaling_receiver.next().await{
+                let mut sink = ws_sink_clone.lock().await;
+                if let Err(e) = sink.send(message).await {
+                    log::log_message(&format!("Error sending message: {}", e));
+                }
+            }
+        });
+
+        // Take back floors held for too long while we are the arbiter
+        tokio::spawn(floor_timer(self.clone()));
+        tokio::spawn(stats_timer(self.clone()));
+        self.drive_ducking();
+        self.sync_multicast().await;
+        self.rotate_group_keys(&[], &initial_groups).await;
+
+        if let SignalingJoin::Registered(peer_list) = joined {
+            self.offer_to_peers(&peer_list, &ws_sink, peer_id).await?;
+        }
+        loop {
+            self.receive_signaling(&mut ws_stream, &ws_sink, peer_id).await?;
+            // No session left means we left the room ourselves
+            if self.session_token.lock().await.is_none() {
+                return Ok(());
+            }
+
+            // Lost the server, e.g. roaming between access points. Peer
+            // connections carry on meanwhile, only signaling is gone.
+            let (new_sink, new_stream, joined) = self.reconnect_signaling(signaling_url).await?;
+            *ws_sink.lock().await = new_sink;
+            ws_stream = new_stream;
+            match joined {
+                SignalingJoin::Resumed => {
+                    // Groups may have changed while we were away
+                    let groups = self.local_groups.lock().await.join(",");
+                    self.broadcast_message(&format!("group_update:{}:{}", peer_id, groups)).await?;
+                    let display_name = self.own_display_name().await;
+                    if let Some(display_name) = display_name {
+                        self.broadcast_message(&format!("display_name:{}:{}", peer_id, display_name)).await?;
+                    }
+                }
+                SignalingJoin::Registered(peer_list) => {
+                    self.offer_to_peers(&peer_list, &ws_sink, peer_id).await?;
+                }
+            }
+        }
+    }
+
+    // Connects to the signaling server and registers, or resumes the session
+    // the token belongs to
+    async fn join_signaling(&self, signaling_url: &str, session_token: Option<String>)
+    -> Result<(SignalingSink, SignalingReceiver, SignalingJoin), Box<dyn std::error::Error>> {
+        // ws:// or wss://, the latter pinned to the fingerprint the room published
+        let tls_fingerprint = tls::published_fingerprint(&*self.room_metadata.lock().await);
+        let ws_stream = tls::connect(signaling_url, tls_fingerprint.as_deref()).await?;
+        let (mut ws_sink, mut ws_stream) = ws_stream.split();
+
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let display_name = self.own_display_name().await.unwrap_or_default();
+        let register = format!("register:{}:{}:{}:{}",
+            peer_id, self.local_groups.lock().await.join(","), self.device_fingerprint(), display_name);
+        let first_message = match session_token {
+            Some(token) => format!("resume:{}", token),
+            None => register.clone(),
+        };
+        ws_sink.send(Message::Text(first_message)).await?;
+
+        // A protected room challenges us before letting us in
+        loop {
+            match ws_stream.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    if let Some(challenge) = text.strip_prefix("challenge:") {
+                        let secret = self.room_secret.lock().await.clone()
+                            .ok_or("The room is password protected")?;
+                        let response = auth::respond(&secret, challenge, &peer_id);
+                        ws_sink.send(Message::Text(format!("auth:{}", response))).await?;
+                    } else if let Some(reason) = text.strip_prefix("rejected:") {
+                        return Err(format!("Signaling server rejected us: {}", reason).into());
+                    } else if let Some(token) = text.strip_prefix("session:") {
+                        *self.session_token.lock().await = Some(token.to_string());
+                    } else if text == "resumed" {
+                        return Ok((ws_sink, ws_stream, SignalingJoin::Resumed));
+                    } else if text == "session_expired" {
+                        // Gone too long, the others dropped us. Join as a newcomer.
+                        ws_sink.send(Message::Text(register.clone())).await?;
+                    } else {
+                        return Ok((ws_sink, ws_stream, SignalingJoin::Registered(text)));
+                    }
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err("Signaling server closed the connection".into()),
+            }
+        }
+    }
+
+    // Tries to get back into the room, waiting twice as long after every failure
+    async fn reconnect_signaling(&self, signaling_url: &str)
+    -> Result<(SignalingSink, SignalingReceiver, SignalingJoin), Box<dyn std::error::Error>> {
+        let mut delay = SIGNALING_RECONNECT_BACKOFF;
+        for attempt in 1..=SIGNALING_RECONNECT_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            let session_token = self.session_token.lock().await.clone();
+            match self.join_signaling(signaling_url, session_token).await {
+                Ok(joined) => {
+                    log::log_message("Reconnected to the signaling server");
+                    return Ok(joined);
+                }
+                Err(e) => log::log_message(&format!(
+                    "Reconnecting to the signaling server failed (attempt {}): {}", attempt, e)),
+            }
+            delay = (delay * 2).min(SIGNALING_RECONNECT_MAX_DELAY);
+        }
+        Err("Unable to reconnect to the signaling server".into())
+    }
+
+    // Sends an offer to everyone in the peer list the server gave us
+    async fn offer_to_peers(
+        &self,
+        peer_list: &str,
+        ws_sink: &Arc<Mutex<SignalingSink>>,
+        peer_id: &str
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let peers: Vec<String> = peer_list.split(',')
+            .map(|s| s.to_string())
+            .filter(|s| !s.is_empty() && s != peer_id)
+            .collect();
+        // Create an offer for each peer and send it
+        for peer in peers {
+            events::emit(&self.events, SessionEvent::PeerJoined {
+                peer_id: peer.clone(),
+                display_name: None,
+                groups: Vec::new(),
+                trusted: identity::is_trusted(&self.pool, &peer),
+            });
+            self.send_certificate(&peer).await;
+            if !self.should_connect(&peer).await {
+                continue;
+            }
+            // Their groups arrive in a group_update, channels open then
+            if let Err(e) = self.offer_to_peer(&peer, ws_sink, peer_id).await {
+                log::log_message(&format!("Failed to offer a connection to {}: {}", peer, e));
+            }
+        }
+        Ok(())
+    }
+    async fn offer_to_peer(
+        &self,
+        remote_peer_id: &str,
+        ws_sink: &Arc<Mutex<SignalingSink>>,
+        peer_id: &str
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let peer_connection = self.connect_peer(remote_peer_id).await?;
+        let offer_sdp = create_offer(&peer_connection).await?;
+
+        let mut sink = ws_sink.lock().await;
+        sink.send(Message::Text(
+            // Message Format
+            // {receiver}:{type}:{sender}:{message}
+            format!("{}:offer:{}:{}", remote_peer_id, peer_id, offer_sdp)
+        )).await?;
+        Ok(())
+    }
+
+    // Handles messages until the connection to the signaling server drops
+    async fn receive_signaling(
+        &self,
+        ws_stream: &mut SignalingReceiver,
+        ws_sink: &Arc<Mutex<SignalingSink>>,
+        peer_id: &str
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Recieve messages from the signaling server
+        while let Some(message) = ws_stream.next().await{
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    log::log_message(&format!("Lost the signaling server: {}", e));
+                    break;
+                }
+            };
+            match message {
+                Message::Text(text) => {
+                    if text.starts_with("new_peer:") {
+                        // new_peer:{peer_id}:{groups}:{fingerprint}:{display_name}
+                        let parts: Vec<&str> = text.splitn(5, ':').collect();
+                        if parts.len() < 3 {
+                            continue;
+                        }
+                        let new_peer_id = parts[1].to_string();
+                        let new_peer_groups: Vec<String> = parts[2].split(',')
+                            .map(|s| s.to_string()).collect();
+                        if let Some(fingerprint) = parts.get(3).filter(|fingerprint| !fingerprint.is_empty()) {
+                            if !self.check_identity(&new_peer_id, fingerprint).await {
+                                continue;
+                            }
+                            self.announced_fingerprints.lock().await
+                                .insert(new_peer_id.clone(), fingerprint.to_string());
+                        }
+                        let old_groups = self.peer_groups.lock().await.insert(
+                            new_peer_id.clone(),
+                            new_peer_groups.clone()
+                        ).unwrap_or_default();
+                        self.rotate_group_keys(&old_groups, &new_peer_groups).await;
+                        self.set_member_online(&new_peer_id, true).await;
+                        let display_name = parts.get(4)
+                            .and_then(|name| identity::sanitize_display_name(name));
+                        if let Some(display_name) = &display_name {
+                            self.display_names.lock().await.insert(new_peer_id.clone(), display_name.clone());
+                        }
+                        events::emit(&self.events, SessionEvent::PeerJoined {
+                            peer_id: new_peer_id.clone(),
+                            display_name,
+                            groups: new_peer_groups.clone(),
+                            trusted: identity::is_trusted(&self.pool, &new_peer_id),
+                        });
+
+                        // The newcomer only gets a list of ids, tell it our groups and name
+                        let groups = self.local_groups.lock().await.join(",");
+                        self.send_signaling_message(&new_peer_id, "group_update", &groups).await;
+                        let own_name = self.own_display_name().await;
+                        if let Some(own_name) = own_name {
+                            self.send_signaling_message(&new_peer_id, "display_name", &own_name).await;
+                        }
+                        self.send_certificate(&new_peer_id).await;
+                        self.share_multicast_keys(&new_peer_id).await;
+
+                        if !self.should_connect(&new_peer_id).await {
+                            continue;
+                        }
+                        if let Err(e) = self.offer_to_peer(&new_peer_id, ws_sink, peer_id).await {
+                            log::log_message(&format!("Failed to offer a connection to {}: {}", new_peer_id, e));
+                        }
+
+                    } else if let Some(left_peer_id) = text.strip_prefix("peer_left:") {
+                        // The server saw the peer's socket close
+                        self.drop_peer(left_peer_id).await;
+                        self.set_member_online(left_peer_id, false).await;
+
+                    } else {
+                        // {type}:{sender}:{message}
+                        let parts: Vec<&str> = text.splitn(3, ':').collect();
+                        if parts.len() < 3 {
+                            continue;
+                        }
+                        let message_type = parts[0];
+                        let remote_peer_id = parts[1];
+
+                        match message_type {
+                            "offer" | "restart" | "answer" | "candidate" => {
+                                // One peer failing to negotiate mustn't end signaling for the rest
+                                if let Err(e) = self.on_negotiation(message_type, remote_peer_id, parts[2], ws_sink, peer_id).await {
+                                    log::log_message(&format!("Negotiation with {} failed: {}", remote_peer_id, e));
+                                }
+                            }
+                            "display_name" => {
+                                self.on_display_name(remote_peer_id, parts[2]).await;
+                            }
+                            "group_update" => {
+                                let new_groups: Vec<String> = parts[2].split(',')
+                                    .map(|s| s.to_string()).collect();
+                                let old_groups = self.peer_groups.lock().await.insert(
+                                    remote_peer_id.to_string(), new_groups.clone()
+                                ).unwrap_or_default();
+                                self.rotate_group_keys(&old_groups, &new_groups).await;
+                                let left = left_groups(&old_groups, &new_groups);
+                                self.rotate_multicast_keys(&left, remote_peer_id).await;
+                                events::emit(&self.events, SessionEvent::GroupMembershipChanged {
+                                    peer_id: remote_peer_id.to_string(),
+                                    groups: new_groups,
+                                });
+                                self.sync_group_channels(remote_peer_id).await;
+                                self.share_multicast_keys(remote_peer_id).await;
+                            }
+                            "multicast_key" => {
+                                // Only relayed peers get it this way, the server vouches
+                                // for the sender but can read the key
+                                if !self.relayed_peers.lock().await.contains(remote_peer_id) {
+                                    log::log_message(&format!("Ignored multicast key from {} outside its control channel", remote_peer_id));
+                                    continue;
+                                }
+                                match serde_json::from_str::<MulticastKeyMessage>(parts[2]) {
+                                    Ok(message) => self.on_multicast_key(remote_peer_id, message).await,
+                                    Err(_) => log::log_message(&format!("Invalid multicast key from {}", remote_peer_id)),
+                                }
+                            }
+                            "certificate" => {
+                                self.on_peer_certificate(remote_peer_id, parts[2]).await;
+                            }
+                            "group_key" => {
+                                // Wrapped for its recipient, the server can't read it
+                                match serde_json::from_str::<KeyMessage>(parts[2]) {
+                                    Ok(message) => self.on_key_message(remote_peer_id, message).await,
+                                    Err(_) => log::log_message(&format!("Ignored group key from {}", remote_peer_id)),
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                },
+                // Audio relayed by the host: {sender}{frame}
+                Message::Binary(data) => {
+                    if let Some((sender, frame)) = relay::decode_envelope(&data) {
+                        self.on_relayed_audio(&sender, frame.to_vec()).await;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Offers, answers and candidates from one peer. When both sides offer at
+    // once (glare) the peer with the lower id is polite: it rolls its own
+    // offer back and answers, the other one ignores the offer it got.
+    async fn on_negotiation(
+        &self,
+        message_type: &str,
+        remote_peer_id: &str,
+        payload: &str,
+        ws_sink: &Arc<Mutex<SignalingSink>>,
+        peer_id: &str
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let existing = self.peer_connections.lock().await.get(remote_peer_id).cloned();
+        let glare = existing.as_ref()
+            .is_some_and(|pc| pc.signaling_state() == RTCSignalingState::HaveLocalOffer);
+        let polite = peer_id < remote_peer_id;
+
+        match message_type {
+            "offer" | "restart" => {
+                let sdp = payload;
+                if !self.verify_sdp_identity(remote_peer_id, sdp).await {
+                    return Ok(());
+                }
+                if glare && !polite {
+                    log::log_message(&format!("Offer from {} crossed ours, ignoring it", remote_peer_id));
+                    return Ok(());
+                }
+                let peer_connection = match (message_type, existing) {
+                    // ICE restart: renegotiate on the existing connection
+                    ("restart", Some(peer_connection)) => {
+                        if glare {
+                            rollback(&peer_connection).await?;
+                        }
+                        peer_connection
+                    }
+                    ("restart", None) => return Ok(()),
+                    // A fresh offer always replaces whatever connection
+                    // we had with that peer, our own offer included
+                    _ => self.connect_peer(remote_peer_id).await?,
+                };
+                set_remote_description(&peer_connection, RTCSessionDescription::offer(sdp.to_string())?).await?;
+
+                let answer_sdp = create_answer(&peer_connection).await?;
+                let mut sink = ws_sink.lock().await;
+                sink.send(Message::Text(
+                    format!("{}:answer:{}:{}",
+                        remote_peer_id,
+                        peer_id,
+                        answer_sdp)
+                )).await?;
+            }
+            "answer" => {
+                let sdp = payload;
+                // Our offer went to an impostor, give up on it
+                if !self.verify_sdp_identity(remote_peer_id, sdp).await {
+                    self.drop_peer(remote_peer_id).await;
+                    return Ok(());
+                }
+                // Answers to an offer we rolled back have nothing left to answer
+                if let Some(peer_connection) = existing.filter(|_| glare) {
+                    set_remote_description(&peer_connection, RTCSessionDescription::answer(sdp.to_string())?).await?;
+                }
+            }
+            "candidate" => {
+                let ice_candidate = RTCIceCandidateInit {
+                    candidate: payload.to_string(),
+                    sdp_mid: None,
+                    sdp_mline_index: None,
+                    username_fragment: None,
+                };
+                if let Some(peer_connection) = existing {
+                    add_ice_candidate(&peer_connection, ice_candidate).await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // ============================================
+    //            Peer Connection Lifecycle
+    // ============================================
+
+    // Creates a peer connection to the remote peer, replacing any previous
+    // one, and starts watching its connection state.
+    async fn connect_peer(&self, remote_peer_id: &str)
+    -> Result<Arc<RTCPeerConnection>, Error> {
+        if let Some(old_connection) = self.remove_peer_connection(remote_peer_id).await {
+            if let Err(e) = old_connection.close().await {
+                log::log_message(&format!("Error closing connection to {}: {}", remote_peer_id, e));
+            }
+        }
+
+        let signaling_sender = self.signaling_sender.lock().await.clone()
+            .ok_or_else(|| Error::new("Signaling channel not initialized".to_string()))?;
+        let peer_id = self.local_peer_id.lock().await.clone();
+
+        let mut config = IceConfig::from_metadata(&*self.room_metadata.lock().await)
+            .rtc_configuration();
+        config.certificates = vec![self.certificate.clone()];
+        let (peer_connection, channels) = create_peer_connection(
+            &self.api,
+            signaling_sender,
+            peer_id,
+            remote_peer_id.to_string(),
+            config,
+        ).await?;
+        let peer_connection = Arc::new(peer_connection);
+        let PeerChannels { audio: audio_data_channel, control: control_channel, call: call_channel } = channels;
+
+        handle_data_channel_messages(&audio_data_channel, self.clone(), remote_peer_id.to_string());
+        handle_control_messages(&control_channel, self.clone(), remote_peer_id.to_string());
+        self.control_channels.lock().await
+            .insert(remote_peer_id.to_string(), control_channel);
+        handle_call_audio(&call_channel, self.clone(), remote_peer_id.to_string());
+        self.call_channels.lock().await
+            .insert(remote_peer_id.to_string(), call_channel);
+        self.room_audio_channels.lock().await
+            .insert(remote_peer_id.to_string(), audio_data_channel);
+
+        handle_peer_connection_events(&peer_connection, self.clone(), remote_peer_id.to_string());
+
+        self.peer_connections.lock().await
+            .insert(remote_peer_id.to_string(), peer_connection.clone());
+        self.sync_group_channels(remote_peer_id).await;
+        Ok(peer_connection)
+    }
+
+    // Opens a channel for every group we share with the peer and closes the
+    // ones we no longer share, so only members get a group's audio. The
+    // control channel keeps the SCTP association up, so adding or removing
+    // channels never needs an SDP renegotiation.
+    async fn sync_group_channels(&self, remote_peer_id: &str) {
+        let peer_connection = match self.peer_connections.lock().await.get(remote_peer_id) {
+            Some(peer_connection) => peer_connection.clone(),
+            None => return,
+        };
+        let remote_groups = self.peer_groups.lock().await
+            .get(remote_peer_id)
+            .cloned()
+            .unwrap_or_default();
+        let local_groups = self.local_groups.lock().await.clone();
+        let local_peer_id = self.local_peer_id.lock().await.clone();
+        let (topology, host) = self.topology().await;
+        let shared = topology.channel_groups(host.as_deref(), &local_peer_id, &local_groups, &remote_groups);
+        let shared = without_channel_collisions(shared, remote_peer_id);
+
+        // Held throughout so two syncs never open the same channel twice
+        let mut audio_data_channels = self.audio_data_channels.lock().await;
+        for (group, channels) in audio_data_channels.iter_mut() {
+            if shared.contains(group) {
+                continue;
+            }
+            if let Some(data_channel) = channels.remove(remote_peer_id) {
+                if let Err(e) = data_channel.close().await {
+                    log::log_message(&format!("Error closing {} channel to {}: {}", group, remote_peer_id, e));
+                }
+            }
+        }
+
+        for group in shared {
+            let channels = audio_data_channels.entry(group.clone()).or_default();
+            let channel = match channels.entry(remote_peer_id.to_string()) {
+                Entry::Occupied(_) => continue,
+                Entry::Vacant(channel) => channel,
+            };
+            match create_group_channel(&peer_connection, &group).await {
+                Ok(data_channel) => {
+                    handle_data_channel_messages(&data_channel, self.clone(), remote_peer_id.to_string());
+                    channel.insert(data_channel);
+                }
+                Err(e) => {
+                    log::log_message(&format!("Failed to open {} channel to {}: {}", group, remote_peer_id, e));
+                }
+            }
+        }
+        audio_data_channels.retain(|_, channels| !channels.is_empty());
+    }
+
+    // Removes a peer connection and every data channel opened on it,
+    // leaving the connection itself to the caller.
+    async fn remove_peer_connection(&self, remote_peer_id: &str) -> Option<Arc<RTCPeerConnection>> {
+        let peer_connection = self.peer_connections.lock().await.remove(remote_peer_id);
+        self.control_channels.lock().await.remove(remote_peer_id);
+        self.call_channels.lock().await.remove(remote_peer_id);
+        self.room_audio_channels.lock().await.remove(remote_peer_id);
+
+        let mut audio_data_channels = self.audio_data_channels.lock().await;
+        for channels in audio_data_channels.values_mut() {
+            channels.remove(remote_peer_id);
+        }
+        audio_data_channels.retain(|_, channels| !channels.is_empty());
+        peer_connection
+    }
+
+    // Reacts to connection state changes reported by a peer connection
+    async fn on_connection_state_change(&self, remote_peer_id: String,
+        peer_connection: Arc<RTCPeerConnection>, state: RTCPeerConnectionState) {
+        log::log_message(&format!("Connection to {} is now {}", remote_peer_id, state));
+
+        // Ignore events from connections that have since been replaced
+        let is_current = self.peer_connections.lock().await
+            .get(&remote_peer_id)
+            .is_some_and(|current| Arc::ptr_eq(current, &peer_connection));
+        if !is_current {
+            return;
+        }
+        events::emit(&self.events, SessionEvent::ConnectionStateChanged {
+            peer_id: remote_peer_id.clone(),
+            state,
+        });
+
+        if state == RTCPeerConnectionState::Connected
+            && self.relayed_peers.lock().await.remove(&remote_peer_id) {
+            log::log_message(&format!("Direct path to {} is back, leaving the relay", remote_peer_id));
+        }
+        match state {
+            RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Failed => {
+                // Audio goes through the host until the direct path is back
+                if self.ws_sink.is_some() && self.relayed_peers.lock().await.insert(remote_peer_id.clone()) {
+                    log::log_message(&format!("Relaying audio to {} while the connection recovers", remote_peer_id));
+                }
+                if !self.recovering_peers.lock().await.insert(remote_peer_id.clone()) {
+                    return;
+                }
+                let module = self.clone();
+                tokio::spawn(async move {
+                    module.recover_peer(&remote_peer_id, state).await;
+                    module.recovering_peers.lock().await.remove(&remote_peer_id);
+                });
+            }
+            _ => {}
+        }
+    }
+
+    // Tries to bring a peer back: ICE restarts with backoff first, then a
+    // brand new connection, and finally gives up and forgets the peer.
+    async fn recover_peer(&self, remote_peer_id: &str, state: RTCPeerConnectionState) {
+        // A disconnected peer may recover on its own
+        if state == RTCPeerConnectionState::Disconnected {
+            tokio::time::sleep(DISCONNECTED_GRACE_PERIOD).await;
+        }
+
+        let mut delay = ICE_RESTART_BACKOFF;
+        for attempt in 1..=ICE_RESTART_ATTEMPTS {
+            let peer_connection = match self.peer_connections.lock().await.get(remote_peer_id) {
+                Some(pc) => pc.clone(),
+                None => return,
+            };
+            match peer_connection.connection_state() {
+                RTCPeerConnectionState::Connected => return,
+                RTCPeerConnectionState::Closed => break,
+                _ => {}
+            }
+
+            log::log_message(&format!("ICE restart {}/{} for {}",
+                attempt, ICE_RESTART_ATTEMPTS, remote_peer_id));
+            match create_restart_offer(&peer_connection).await {
+                Ok(offer_sdp) => {
+                    self.send_signaling_message(remote_peer_id, "restart", &offer_sdp).await;
+                    if wait_for_connection(&peer_connection, RECONNECT_TIMEOUT).await {
+                        log::log_message(&format!("ICE restart recovered {}", remote_peer_id));
+                        return;
+                    }
+                }
+                Err(e) => {
+                    log::log_message(&format!("ICE restart failed for {}: {}", remote_peer_id, e));
+                }
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+
+        // Tear down and start from scratch with a new offer
+        log::log_message(&format!("Re-offering connection to {}", remote_peer_id));
+        let reoffered = match self.connect_peer(remote_peer_id).await {
+            Ok(peer_connection) => match create_offer(&peer_connection).await {
+                Ok(offer_sdp) => {
+                    self.send_signaling_message(remote_peer_id, "offer", &offer_sdp).await;
+                    wait_for_connection(&peer_connection, RECONNECT_TIMEOUT).await
+                }
+                Err(e) => {
+                    log::log_message(&format!("Failed to re-offer {}: {}", remote_peer_id, e));
+                    false
+                }
+            },
+            Err(e) => {
+                log::log_message(&format!("Failed to recreate connection to {}: {}", remote_peer_id, e));
+                false
+            }
+        };
+        if !reoffered {
+            self.start_relay(remote_peer_id).await;
+        }
+    }
+
+    // No direct path left, the room host keeps relaying the audio
+    async fn start_relay(&self, remote_peer_id: &str) {
+        if self.ws_sink.is_none() {
+            self.drop_peer(remote_peer_id).await;
+            return;
+        }
+        if let Some(peer_connection) = self.remove_peer_connection(remote_peer_id).await {
+            if let Err(e) = peer_connection.close().await {
+                log::log_message(&format!("Error closing connection to {}: {}", remote_peer_id, e));
+            }
+        }
+        log::log_message(&format!("No direct path to {}, relaying audio through the host", remote_peer_id));
+        self.relayed_peers.lock().await.insert(remote_peer_id.to_string());
+    }
+
+    // Forgets everything we know about a peer that is gone for good
+    async fn drop_peer(&self, remote_peer_id: &str) {
+        log::log_message(&format!("Peer {} is gone, cleaning up", remote_peer_id));
+        if let Some(peer_connection) = self.remove_peer_connection(remote_peer_id).await {
+            if let Err(e) = peer_connection.close().await {
+                log::log_message(&format!("Error closing connection to {}: {}", remote_peer_id, e));
+            }
+        }
+        let old_groups = self.peer_groups.lock().await.remove(remote_peer_id).unwrap_or_default();
+        self.group_keys.lock().await.remove_peer(remote_peer_id);
+        self.rotate_group_keys(&old_groups, &[]).await;
+        self.rotate_multicast_keys(&old_groups, remote_peer_id).await;
+        self.relayed_peers.lock().await.remove(remote_peer_id);
+        self.recovering_peers.lock().await.remove(remote_peer_id);
+        self.announced_fingerprints.lock().await.remove(remote_peer_id);
+        self.display_names.lock().await.remove(remote_peer_id);
+        self.receive_streams.lock().await.retain(|(peer, _), _| peer != remote_peer_id);
+        if let Ok(mut playback) = self.playback.lock() {
+            playback.remove_talker(remote_peer_id);
+        }
+        self.peer_stats.lock().await.remove(remote_peer_id);
+        self.release_peer_floors(remote_peer_id).await;
+        self.finish_emergency(remote_peer_id).await;
+        let in_call = self.direct_call.lock().await.as_ref()
+            .is_some_and(|call| call.peer_id == remote_peer_id);
+        if in_call {
+            self.set_call_state(CallState::Ended).await;
+        }
+        events::emit(&self.events, SessionEvent::PeerLeft {
+            peer_id: remote_peer_id.to_string(),
+        });
+    }
+
+    // Records a member coming or going in the stored room metadata
+    async fn set_member_online(&self, remote_peer_id: &str, online: bool) {
+        let mut room_metadata = self.room_metadata.lock().await;
+        // Peers that were never members stay out of the metadata
+        if metadata::find_member_flag(&room_metadata, remote_peer_id, "online").is_none() {
+            return;
+        }
+        metadata::set_member_flag(&mut room_metadata, remote_peer_id, "online", serde_json::json!(online));
+        self.save_room_metadata(&room_metadata).await;
+    }
+
+    // Queues a {receiver}:{type}:{sender}:{message} signaling message
+    async fn send_signaling_message(&self, remote_peer_id: &str, message_type: &str, payload: &str) {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let signaling_sender = self.signaling_sender.lock().await.clone();
+        if let Some(mut signaling_sender) = signaling_sender {
+            let message = Message::Text(
+                format!("{}:{}:{}:{}", remote_peer_id, message_type, peer_id, payload)
+            );
+            if let Err(e) = signaling_sender.try_send(message) {
+                log::log_message(&format!("Failed to queue {} for {}: {}", message_type, remote_peer_id, e));
+            }
+        }
+    }
+
+    // ============================================
+    //            Audio Handling
+    // ============================================
+
+    pub async fn send_audio(
+        &self,
+        audio_data: FormattedAudio,
+        group: &str)
+    -> Result<(), Box<dyn std::error::Error>>{
+        let active = *self.audio_sending_active.lock().await;
+        if !active {
+            return Ok(());
+        }
+        if *self.admin_muted.lock().await {
+            log::log_message("Muted by an admin, audio not sent");
+            return Ok(());
+        }
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let emergency_holder = self.emergency_holder.lock().await.clone();
+        let is_emergency = emergency_holder.as_deref() == Some(peer_id.as_str());
+        let data_channels: Vec<Arc<RTCDataChannel>> = match emergency_holder {
+            // Our emergency call reaches every peer whatever the group
+            Some(holder) if holder == peer_id => self.room_audio_channels.lock().await
+                .values()
+                .cloned()
+                .collect(),
+            Some(holder) => {
+                return Err(format!("Emergency call from {} in progress", holder).into());
+            }
+            None => {
+                // Tokio's clock, so tests can move time along
+                let now = tokio::time::Instant::now().into_std();
+                if let Some(remaining) = self.transmit_guard.lock().await.locked_out(group, now) {
+                    return Err(format!("Transmit locked out in {} for {}s",
+                        group, remaining.as_secs()).into());
+                }
+                // Half duplex, only the floor holder transmits
+                if self.floor_holders.lock().await.get(group) != Some(&peer_id) {
+                    return Err(format!("Floor not granted for {}", group).into());
+                }
+                // Transmit timeout, a stuck button gets cut off
+                let limits = self.transmit_limits(group).await;
+                let check = self.transmit_guard.lock().await.on_transmit(group, &limits, now);
+                match check {
+                    TransmitCheck::Allowed => {}
+                    TransmitCheck::Warning { remaining } => {
+                        if let Ok(mut playback) = self.playback.lock() {
+                            playback.push_tone(&audio::generate_warning_tone());
+                        }
+                        events::emit(&self.events, SessionEvent::TransmitWarning {
+                            group: group.to_string(),
+                            remaining,
+                        });
+                    }
+                    TransmitCheck::CutOff { lockout } => {
+                        log::log_message(&format!("Transmit timeout in {}", group));
+                        events::emit(&self.events, SessionEvent::TransmitCutOff {
+                            group: group.to_string(),
+                            lockout,
+                        });
+                        self.release_floor(group).await?;
+                        return Err(format!("Transmit timeout in {}", group).into());
+                    }
+                    TransmitCheck::LockedOut { remaining } => {
+                        return Err(format!("Transmit locked out in {} for {}s",
+                            group, remaining.as_secs()).into());
+                    }
+                }
+                self.audio_data_channels.lock().await
+                    .get(group)
+                    .map(|channels| channels.values().cloned().collect())
+                    .unwrap_or_default()
+            }
+        };
+        // Check if the audio data is valid
+        if let Ok(data) = audio_data {
+            let samples = audio::opus_packet_samples(&data);
+            let (sequence, timestamp) = self.frame_sequencer.lock().await.next(group, samples);
+            let mut frame = AudioFrame::new(&peer_id, group, sequence, timestamp, data);
+            if is_emergency {
+                // Heard by the whole room, so sealed with our room-wide key
+                frame = frame.with_flags(FLAG_EMERGENCY);
+                self.group_keys.lock().await.seal_as(&mut frame, EMERGENCY_KEY_GROUP)?;
+            } else {
+                self.group_keys.lock().await.seal(&mut frame)?;
+            }
+            let bytes = Bytes::from(frame.encode()?);
+            // Multicast groups reach every listener on the LAN with one packet
+            let sent_multicast = !is_emergency && self.send_multicast(group, &bytes).await;
+            // Send audio to the specified destination using WebRTC
+            for data_channel in data_channels.into_iter().filter(|_| !sent_multicast) {
+                if let Ok(_) = data_channel.send(&bytes).await {
+                    log::log_message("Audio data sent successfully");
+                } else {
+                    log::log_message("Failed to send audio data");
+                }
+            }
+            let relay_group = if is_emergency { None } else { Some(group) };
+            for remote_peer_id in self.relay_targets(relay_group).await {
+                self.send_relayed(&remote_peer_id, &bytes).await;
+            }
+        } else {
+            log::log_message("Invalid audio data");
+        }
+        Ok(())
+    }
+    // Tells the group our transmission is over so receivers can flush
+    async fn send_end_of_transmission(&self, group: &str) {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let (sequence, timestamp) = self.frame_sequencer.lock().await.next(group, 0);
+        let frame = AudioFrame::new(&peer_id, group, sequence, timestamp, Vec::new())
+            .with_flags(FLAG_END_OF_TRANSMISSION);
+        let bytes = match frame.encode() {
+            Ok(bytes) => Bytes::from(bytes),
+            Err(e) => {
+                log::log_message(&format!("Failed to encode end of transmission: {}", e));
+                return;
+            }
+        };
+        let data_channels: Vec<Arc<RTCDataChannel>> = if self.send_multicast(group, &bytes).await {
+            Vec::new()
+        } else {
+            self.audio_data_channels.lock().await
+                .get(group)
+                .map(|channels| channels.values().cloned().collect())
+                .unwrap_or_default()
+        };
+        for data_channel in data_channels {
+            if data_channel.send(&bytes).await.is_err() {
+                log::log_message("Failed to send end of transmission");
+            }
+        }
+        for remote_peer_id in self.relay_targets(Some(group)).await {
+            self.send_relayed(&remote_peer_id, &bytes).await;
+        }
+    }
+    pub async fn receive_audio(&self, group: &str) -> mpsc::Receiver<AudioFrame> {
+        let (sender, receiver) = mpsc::channel(100); // Channel to send audio data
+
+        // Audio is routed here by the data channel message handler
+        self.audio_receivers.lock().await
+            .entry(group.to_string())
+            .or_default()
+            .push(sender);
+        receiver
+    }
+    // data_channel is None for audio relayed by the host
+    async fn on_audio_message(&self, remote_peer_id: &str,
+        data_channel: Option<&Arc<RTCDataChannel>>, data: Vec<u8>) {
+        let frame = match AudioFrame::decode(&data) {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::log_message(&format!("Dropped audio from {}: {}", remote_peer_id, e));
+                return;
+            }
+        };
+        // Only the forwarding host passes on audio from someone else
+        let forwarder = self.forwarder().await;
+        if frame.sender != remote_peer_id && forwarder.as_deref() != Some(remote_peer_id) {
+            log::log_message(&format!("Dropped audio from {} claiming to be {}",
+                remote_peer_id, frame.sender));
+            return;
+        }
+        let lost = match self.next_frame_gap(&frame).await {
+            Some(lost) => lost,
+            None => return,
+        };
+        let is_emergency = self.emergency_holder.lock().await.as_deref() == Some(frame.sender.as_str());
+        if forwarder.as_deref() == Some(self.local_peer_id.lock().await.as_str()) {
+            self.forward_frame(remote_peer_id, &frame, &data, is_emergency).await;
+        }
+        let active = *self.audio_receiving_active.lock().await;
+        if !active {
+            return;
+        }
+        let frame = match self.open_frame(frame, is_emergency).await {
+            Some(frame) => frame,
+            None => return,
+        };
+        let audible = self.scan_audio(&frame, is_emergency).await;
+        let groups: Vec<String> = if is_emergency {
+            // Emergency calls are heard in every group
+            self.audio_receivers.lock().await.keys().cloned().collect()
+        } else {
+            // Only if the talker's channel is in the group the frame is for
+            let in_group = match data_channel {
+                Some(data_channel) => self.audio_data_channels.lock().await
+                    .get(&frame.group)
+                    .and_then(|channels| channels.get(remote_peer_id))
+                    .is_some_and(|dc| Arc::ptr_eq(dc, data_channel)),
+                None => self.shares_group(remote_peer_id, &frame.group).await,
+            };
+            if !in_group {
+                return;
+            }
+            vec![frame.group.clone()]
+        };
+        if audible {
+            self.play_frame(&frame, lost);
+        }
+        self.deliver_audio(&frame, groups).await;
+    }
+    // Decodes a frame into the playback mixer under its talker, so the
+    // ducker can tell talkers apart
+    fn play_frame(&self, frame: &AudioFrame, lost: u64) {
+        let mut playback = match self.playback.lock() {
+            Ok(playback) => playback,
+            Err(_) => {
+                log::log_message("Playback mixer is poisoned");
+                return;
+            }
+        };
+        if !frame.payload.is_empty() {
+            if let Err(e) = playback.push_opus(&frame.sender, &frame.group, &frame.payload, lost) {
+                log::log_message(&format!("Failed to decode audio from {}: {}", frame.sender, e));
+            }
+        }
+        if frame.is_end_of_transmission() {
+            playback.end_stream(&frame.sender, &frame.group);
+        }
+    }
+    // Hands a frame to whoever called receive_audio for these groups
+    async fn deliver_audio(&self, frame: &AudioFrame, groups: Vec<String>) {
+        let mut audio_receivers = self.audio_receivers.lock().await;
+        for group in groups {
+            if let Some(receivers) = audio_receivers.get_mut(&group) {
+                receivers.retain(|receiver| !receiver.is_closed());
+                for receiver in receivers.iter_mut() {
+                    // Send the data through the channel
+                    if receiver.try_send(frame.clone()).is_err() {
+                        log::log_message("Failed to send received audio data");
+                    }
+                }
+            }
+        }
+    }
+
+
+    // ============================================
+    //            Host Relay
+    // ============================================
+
+    // Relayed peers that should hear a group, all of them when None
+    async fn relay_targets(&self, group: Option<&str>) -> Vec<String> {
+        let relayed_peers: Vec<String> = self.relayed_peers.lock().await.iter().cloned().collect();
+        let group = match group {
+            Some(group) => group,
+            None => return relayed_peers,
+        };
+        let mut targets = Vec::new();
+        for remote_peer_id in relayed_peers {
+            if self.shares_group(&remote_peer_id, group).await {
+                targets.push(remote_peer_id);
+            }
+        }
+        targets
+    }
+    async fn shares_group(&self, remote_peer_id: &str, group: &str) -> bool {
+        let local = self.local_groups.lock().await.iter().any(|g| g == group);
+        let remote = self.peer_groups.lock().await
+            .get(remote_peer_id)
+            .is_some_and(|groups| groups.iter().any(|g| g == group));
+        // The forwarding host carries groups it isn't in itself
+        let local_peer_id = self.local_peer_id.lock().await.clone();
+        match self.forwarder().await {
+            Some(host) if host == remote_peer_id => local,
+            Some(host) if host == local_peer_id => remote,
+            _ => local && remote,
+        }
+    }
+    // As the forwarding host, pass a frame on to the other members of its
+    // group, or to everyone for an emergency call
+    async fn forward_frame(&self, remote_peer_id: &str, frame: &AudioFrame, data: &[u8], is_emergency: bool) {
+        let data_channels: Vec<Arc<RTCDataChannel>> = if is_emergency {
+            self.room_audio_channels.lock().await.iter()
+                .filter(|(peer, _)| peer.as_str() != remote_peer_id)
+                .map(|(_, data_channel)| data_channel.clone())
+                .collect()
+        } else {
+            // Talkers only reach groups they are in
+            let is_member = self.peer_groups.lock().await
+                .get(remote_peer_id)
+                .is_some_and(|groups| groups.contains(&frame.group));
+            if !is_member {
+                return;
+            }
+            self.audio_data_channels.lock().await
+                .get(&frame.group)
+                .map(|channels| channels.iter()
+                    .filter(|(peer, _)| peer.as_str() != remote_peer_id)
+                    .map(|(_, data_channel)| data_channel.clone())
+                    .collect())
+                .unwrap_or_default()
+        };
+        let bytes = Bytes::copy_from_slice(data);
+        for data_channel in data_channels {
+            if let Err(e) = data_channel.send(&bytes).await {
+                log::log_message(&format!("Failed to forward audio from {}: {}", frame.sender, e));
+            }
+        }
+
+        let group = if is_emergency { None } else { Some(frame.group.as_str()) };
+        for target in self.relay_targets(group).await {
+            if target != remote_peer_id {
+                self.send_relayed(&target, data).await;
+            }
+        }
+    }
+    async fn send_relayed(&self, remote_peer_id: &str, frame: &[u8]) {
+        let ws_sink = match &self.ws_sink {
+            Some(ws_sink) => ws_sink,
+            None => return,
+        };
+        let envelope = match relay::encode_envelope(remote_peer_id, frame) {
+            Some(envelope) => envelope,
+            None => return,
+        };
+        if let Err(e) = ws_sink.lock().await.send(Message::Binary(envelope)).await {
+            log::log_message(&format!("Failed to relay audio to {}: {}", remote_peer_id, e));
+        }
+    }
+    async fn on_relayed_audio(&self, remote_peer_id: &str, data: Vec<u8>) {
+        // Only accepted while we have no direct path to the peer
+        if !self.relayed_peers.lock().await.contains(remote_peer_id) {
+            return;
+        }
+        self.on_audio_message(remote_peer_id, None, data).await;
+    }
+
+    // Frames lost just before this one, None for duplicate and late frames
+    async fn next_frame_gap(&self, frame: &AudioFrame) -> Option<u64> {
+        let key = (frame.sender.clone(), frame.group.clone());
+        let mut receive_streams = self.receive_streams.lock().await;
+        let stream = receive_streams.entry(key).or_default();
+        let lost_before = stream.frames_lost;
+        if !stream.on_frame(frame, std::time::Instant::now()) {
+            return None;
+        }
+        let lost = stream.frames_lost - lost_before;
+        if lost > 0 {
+            log::log_message(&format!("Lost {} audio frames from {} in {}",
+                lost, frame.sender, frame.group));
+        }
+        Some(lost)
+    }
+
+    // ============================================
+    //            Connection Statistics
+    // ============================================
+
+    // Latest snapshot for one peer, None until the first collection
+    pub async fn peer_stats(&self, remote_peer_id: &str) -> Option<PeerStats> {
+        self.peer_stats.lock().await.get(remote_peer_id).cloned()
+    }
+    pub async fn all_peer_stats(&self) -> HashMap<String, PeerStats> {
+        self.peer_stats.lock().await.clone()
+    }
+
+    // Refreshes the snapshot of every peer and reports each one
+    async fn collect_stats(&self) {
+        let relayed_peers = self.relayed_peers.lock().await.clone();
+        let mut peers: Vec<(String, Option<Arc<RTCPeerConnection>>)> = self.peer_connections
+            .lock().await
+            .iter()
+            .map(|(peer, peer_connection)| (peer.clone(), Some(peer_connection.clone())))
+            .collect();
+        // A peer still recovering has both a connection and the relay
+        let connected: HashSet<String> = peers.iter().map(|(peer, _)| peer.clone()).collect();
+        peers.extend(relayed_peers.difference(&connected).map(|peer| (peer.clone(), None)));
+        for (remote_peer_id, peer_connection) in peers {
+            let mut peer_stats = PeerStats::default();
+            if let Some(peer_connection) = peer_connection {
+                stats::apply_report(&mut peer_stats, &peer_connection.get_stats().await);
+            }
+            peer_stats.relayed = relayed_peers.contains(&remote_peer_id);
+
+            for ((peer, _), stream) in self.receive_streams.lock().await.iter() {
+                if *peer != remote_peer_id {
+                    continue;
+                }
+                peer_stats.packets_received += stream.frames_received;
+                peer_stats.packets_lost += stream.frames_lost;
+                let jitter = stream.jitter_ms();
+                peer_stats.jitter_ms = Some(peer_stats.jitter_ms.map_or(jitter, |j: f64| j.max(jitter)));
+            }
+            peer_stats.buffered_amount = self.buffered_amount(&remote_peer_id).await;
+
+            self.peer_stats.lock().await.insert(remote_peer_id.clone(), peer_stats.clone());
+            events::emit(&self.events, SessionEvent::StatsUpdated {
+                peer_id: remote_peer_id,
+                stats: peer_stats,
+            });
+        }
+    }
+
+    // Bytes queued on every channel to the peer, grows when the link can't keep up
+    async fn buffered_amount(&self, remote_peer_id: &str) -> usize {
+        let mut data_channels: Vec<Arc<RTCDataChannel>> = self.audio_data_channels.lock().await
+            .values()
+            .filter_map(|channels| channels.get(remote_peer_id).cloned())
+            .collect();
+        data_channels.extend(self.room_audio_channels.lock().await.get(remote_peer_id).cloned());
+        data_channels.extend(self.control_channels.lock().await.get(remote_peer_id).cloned());
+        data_channels.extend(self.call_channels.lock().await.get(remote_peer_id).cloned());
+        let mut buffered = 0;
+        for data_channel in data_channels {
+            buffered += data_channel.buffered_amount().await;
+        }
+        buffered
+    }
+
+    // ============================================
+    //            Scan Mode
+    // ============================================
+
+    // Listens to several groups at once, replacing any previous scan
+    pub async fn start_scan(&self, config: ScanConfig) -> mpsc::Receiver<ScannedAudio> {
+        let (sender, receiver) = mpsc::channel(100);
+        self.set_scan_priority(config.ducking_group());
+        *self.scan.lock().await = Some((config, sender));
+        receiver
+    }
+    pub async fn stop_scan(&self) {
+        *self.scan.lock().await = None;
+        self.set_scan_priority(None);
+    }
+    fn set_scan_priority(&self, group: Option<String>) {
+        if let Ok(mut playback) = self.playback.lock() {
+            playback.ducker().set_scan_priority(group);
+        }
+    }
+    // Hands the frame to the scan receiver, false if the scan silences it
+    async fn scan_audio(&self, frame: &AudioFrame, is_emergency: bool) -> bool {
+        let mut scan = self.scan.lock().await;
+        let (config, sender) = match scan.as_mut() {
+            Some(scan) => scan,
+            None => return true,
+        };
+        let floor_holders = self.floor_holders.lock().await.clone();
+        let group = if config.groups.contains(&frame.group) {
+            Some(frame.group.clone())
+        } else if is_emergency {
+            // Emergency calls get through even from outside the scanned groups
+            None
+        } else {
+            return false;
+        };
+        let priority_active = config.priority_group.as_ref()
+            .is_some_and(|priority| floor_holders.contains_key(priority));
+        if !is_emergency && !group.as_deref().is_some_and(|group| config.route(group, priority_active)) {
+            return false;
+        }
+        if frame.payload.is_empty() {
+            return true;
+        }
+        let audio = ScannedAudio {
+            group,
+            emergency: is_emergency,
+            peer_id: frame.sender.clone(),
+            data: frame.payload.clone(),
+        };
+        if sender.try_send(audio).is_err() {
+            log::log_message("Failed to send scanned audio data");
+        }
+        true
+    }
+
+    // ============================================
+    //            Ducking
+    // ============================================
+
+    // The mixer the frontend plays from, see audio::start_playback_stream
+    pub fn playback_mixer(&self) -> SharedMixer {
+        self.playback.clone()
+    }
+    pub fn set_ducking_rules(&self, rules: DuckingRules) {
+        if let Ok(mut playback) = self.playback.lock() {
+            playback.ducker().set_rules(rules);
+        }
+    }
+
+    // Feeds talk events into the mixer's ducker so priority talkers and
+    // admins attenuate the rest of the playback while they speak
+    fn drive_ducking(&self) {
+        let module = self.clone();
+        let playback = self.playback.clone();
+        let mut events = self.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                };
+                match event {
+                    SessionEvent::TalkStarted { peer_id, group } => {
+                        let is_admin = metadata::is_admin(&*module.room_metadata.lock().await, &peer_id);
+                        if let Ok(mut playback) = playback.lock() {
+                            playback.ducker().on_talk_started(&peer_id, &group, is_admin);
+                        }
+                    }
+                    SessionEvent::TalkEnded { peer_id, group } => {
+                        if let Ok(mut playback) = playback.lock() {
+                            playback.ducker().on_talk_ended(&peer_id, &group);
+                        }
+                    }
+                    SessionEvent::EmergencyStarted { peer_id } => {
+                        if let Ok(mut playback) = playback.lock() {
+                            playback.ducker().on_emergency_started(&peer_id);
+                        }
+                    }
+                    SessionEvent::EmergencyEnded { peer_id } => {
+                        if let Ok(mut playback) = playback.lock() {
+                            playback.ducker().on_emergency_ended(&peer_id);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    // ============================================
+    //            Floor Control
+    // ============================================
+
+    // Asks for the floor before talking, true once it's ours
+    pub async fn start_talk(&self, group: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        self.request_floor(group, false).await
+    }
+    pub async fn end_talk(&self, group: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.release_floor(group).await
+    }
+    // Who is talking in a group, None when the channel is free
+    pub async fn floor_holder(&self, group: &str) -> Option<String> {
+        self.floor_holders.lock().await.get(group).cloned()
+    }
+
+    // Requests the floor from the arbiter. With queue set we wait in line
+    // behind the current talker instead of being denied.
+    pub async fn request_floor(&self, group: &str, queue: bool)
+    -> Result<bool, Box<dyn std::error::Error>> {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        if self.floor_holders.lock().await.get(group) == Some(&peer_id) {
+            return Ok(true);
+        }
+        if let Some(remaining) = self.transmit_guard.lock().await
+            .locked_out(group, tokio::time::Instant::now().into_std()) {
+            return Err(format!("Transmit locked out in {} for {}s",
+                group, remaining.as_secs()).into());
+        }
+
+        let (answer_sender, answer_receiver) = oneshot::channel();
+        self.floor_waiters.lock().await.insert(group.to_string(), answer_sender);
+
+        let arbiter = self.floor_arbiter_id().await;
+        let request = FloorMessage::Request { group: group.to_string(), queue };
+        if arbiter == peer_id {
+            self.arbitrate_floor(&peer_id, request).await;
+        } else if let Err(e) = self.send_floor_message(&arbiter, &request).await {
+            self.floor_waiters.lock().await.remove(group);
+            return Err(e);
+        }
+
+        let timeout = if queue { FLOOR_QUEUE_TIMEOUT } else { FLOOR_REQUEST_TIMEOUT };
+        let answer = tokio::time::timeout(timeout, answer_receiver).await;
+        self.floor_waiters.lock().await.remove(group);
+        match answer {
+            Ok(Ok(FloorMessage::Granted { .. })) => Ok(true),
+            Ok(Ok(FloorMessage::Denied { holder, .. })) => {
+                events::emit(&self.events, SessionEvent::FloorDenied {
+                    group: group.to_string(),
+                    holder,
+                });
+                Ok(false)
+            }
+            _ => {
+                // No answer in time, leave the queue so we don't get the floor later
+                if queue {
+                    let _ = self.release_floor(group).await;
+                }
+                events::emit(&self.events, SessionEvent::FloorDenied {
+                    group: group.to_string(),
+                    holder: self.floor_holder(group).await,
+                });
+                Ok(false)
+            }
+        }
+    }
+
+    pub async fn release_floor(&self, group: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        self.transmit_guard.lock().await.end_transmission(group);
+        if self.floor_holders.lock().await.get(group) == Some(&peer_id) {
+            self.send_end_of_transmission(group).await;
+        }
+        let release = FloorMessage::Release { group: group.to_string() };
+        let arbiter = self.floor_arbiter_id().await;
+        if arbiter == peer_id {
+            self.arbitrate_floor(&peer_id, release).await;
+            return Ok(());
+        }
+        // Stop talking right away, the arbiter confirms with a release
+        let was_holder = {
+            let mut floor_holders = self.floor_holders.lock().await;
+            if floor_holders.get(group) == Some(&peer_id) {
+                floor_holders.remove(group);
+                true
+            } else {
+                false
+            }
+        };
+        if was_holder {
+            events::emit(&self.events, SessionEvent::TalkEnded {
+                peer_id,
+                group: group.to_string(),
+            });
+        }
+        self.send_floor_message(&arbiter, &release).await
+    }
+
+    // The room host arbitrates, or the lowest peer id if it's gone
+    async fn floor_arbiter_id(&self) -> String {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let host = metadata::find_metadata_value(&*self.room_metadata.lock().await, "host")
+            .and_then(|host| host.as_str())
+            .map(|host| host.to_string());
+        let connected_peers: Vec<String> = self.control_channels.lock().await
+            .keys().cloned().collect();
+        floor::select_arbiter(host.as_deref(), &peer_id, &connected_peers)
+    }
+
+    async fn send_floor_message(&self, remote_peer_id: &str, message: &FloorMessage)
+    -> Result<(), Box<dyn std::error::Error>> {
+        let control_channel = self.control_channels.lock().await
+            .get(remote_peer_id)
+            .cloned()
+            .ok_or_else(|| format!("No control channel to {}", remote_peer_id))?;
+        let text = control::encode_message(&ControlMessage::Floor(message.clone()));
+        control_channel.send_text(text).await?;
+        Ok(())
+    }
+
+    // Sends a floor message to one peer, handling it here if that's us
+    async fn deliver_floor_message(&self, remote_peer_id: &str, message: FloorMessage) {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        if remote_peer_id == peer_id {
+            self.apply_floor_update(message).await;
+        } else if let Err(e) = self.send_floor_message(remote_peer_id, &message).await {
+            log::log_message(&format!("Failed to send floor message to {}: {}", remote_peer_id, e));
+        }
+    }
+
+    // Sends a floor message to every peer and applies it here
+    async fn broadcast_floor_message(&self, message: FloorMessage) {
+        let peers: Vec<String> = self.control_channels.lock().await
+            .keys().cloned().collect();
+        for peer in peers {
+            if let Err(e) = self.send_floor_message(&peer, &message).await {
+                log::log_message(&format!("Failed to send floor message to {}: {}", peer, e));
+            }
+        }
+        self.apply_floor_update(message).await;
+    }
+
+    async fn on_floor_message(&self, remote_peer_id: &str, message: FloorMessage) {
+        match message {
+            FloorMessage::Request { .. } | FloorMessage::Release { .. } => {
+                // A peer that thinks we arbitrate while we don't must not get a
+                // grant, the real arbiter may be granting someone else
+                let local_peer_id = self.local_peer_id.lock().await.clone();
+                if self.floor_arbiter_id().await != local_peer_id {
+                    log::log_message(&format!("Ignored floor request from {}, we aren't the arbiter",
+                        remote_peer_id));
+                    return;
+                }
+                self.arbitrate_floor(remote_peer_id, message).await;
+            }
+            FloorMessage::Emergency { .. } | FloorMessage::EmergencyEnded { .. } => {
+                self.on_emergency_message(remote_peer_id, message).await;
+            }
+            _ => {
+                // Only the arbiter says who holds the floor, anyone else
+                // could grant it to themselves or take it from the talker
+                let arbiter = self.floor_arbiter_id().await;
+                if remote_peer_id != arbiter {
+                    log::log_message(&format!("Ignored floor update from {}, {} is the arbiter",
+                        remote_peer_id, arbiter));
+                    return;
+                }
+                self.apply_floor_update(message).await;
+            }
+        }
+    }
+
+    // Arbiter side of the protocol
+    async fn arbitrate_floor(&self, remote_peer_id: &str, message: FloorMessage) {
+        match message {
+            FloorMessage::Request { group, queue } => {
+                // Nobody talks over an emergency call
+                let emergency_holder = self.emergency_holder.lock().await.clone();
+                if emergency_holder.is_some() {
+                    self.deliver_floor_message(remote_peer_id, FloorMessage::Denied {
+                        group,
+                        holder: emergency_holder,
+                    }).await;
+                    return;
+                }
+                let decision = {
+                    let mut arbiter = self.floor_arbiter.lock().await;
+                    // Honour a grant made by a previous arbiter
+                    if let Some(holder) = self.floor_holders.lock().await.get(&group) {
+                        arbiter.adopt(&group, holder);
+                    }
+                    arbiter.request(&group, remote_peer_id, queue)
+                };
+                match decision {
+                    FloorDecision::Granted => {
+                        self.broadcast_floor_message(FloorMessage::Granted {
+                            group,
+                            holder: remote_peer_id.to_string(),
+                        }).await;
+                    }
+                    FloorDecision::Denied { holder } => {
+                        self.deliver_floor_message(remote_peer_id, FloorMessage::Denied {
+                            group,
+                            holder: Some(holder),
+                        }).await;
+                    }
+                    FloorDecision::Queued { position } => {
+                        self.deliver_floor_message(remote_peer_id, FloorMessage::Queued {
+                            group,
+                            position,
+                        }).await;
+                    }
+                }
+            }
+            FloorMessage::Release { group } => {
+                let (was_holder, next) = {
+                    let mut arbiter = self.floor_arbiter.lock().await;
+                    let was_holder = arbiter.holder(&group) == Some(remote_peer_id);
+                    (was_holder, arbiter.release(&group, remote_peer_id))
+                };
+                if was_holder {
+                    self.hand_over_floor(&group, remote_peer_id, next).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Announces a released floor and whoever was next in line
+    async fn hand_over_floor(&self, group: &str, previous: &str, next: Option<String>) {
+        self.broadcast_floor_message(FloorMessage::Released {
+            group: group.to_string(),
+            holder: previous.to_string(),
+        }).await;
+        if let Some(next) = next {
+            self.broadcast_floor_message(FloorMessage::Granted {
+                group: group.to_string(),
+                holder: next,
+            }).await;
+        }
+    }
+
+    // Everyone's view of the floor, also answers our own pending requests
+    async fn apply_floor_update(&self, message: FloorMessage) {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        match &message {
+            FloorMessage::Granted { group, holder } => {
+                self.floor_holders.lock().await.insert(group.clone(), holder.clone());
+                events::emit(&self.events, SessionEvent::TalkStarted {
+                    peer_id: holder.clone(),
+                    group: group.clone(),
+                });
+                if holder == &peer_id {
+                    if let Some(waiter) = self.floor_waiters.lock().await.remove(group) {
+                        let _ = waiter.send(message.clone());
+                    }
+                }
+            }
+            FloorMessage::Released { group, holder } => {
+                let removed = {
+                    let mut floor_holders = self.floor_holders.lock().await;
+                    if floor_holders.get(group) == Some(holder) {
+                        floor_holders.remove(group);
+                        true
+                    } else {
+                        false
+                    }
+                };
+                if removed {
+                    events::emit(&self.events, SessionEvent::TalkEnded {
+                        peer_id: holder.clone(),
+                        group: group.clone(),
+                    });
+                }
+            }
+            FloorMessage::Denied { group, .. } => {
+                if let Some(waiter) = self.floor_waiters.lock().await.remove(group) {
+                    let _ = waiter.send(message.clone());
+                }
+            }
+            FloorMessage::Queued { group, position } => {
+                log::log_message(&format!("Queued for the floor in {} at position {}", group, position));
+            }
+            _ => {}
+        }
+    }
+
+    // Frees every floor held by a peer that left
+    async fn release_peer_floors(&self, remote_peer_id: &str) {
+        let released = self.floor_arbiter.lock().await.release_peer(remote_peer_id);
+        for (group, next) in released {
+            self.hand_over_floor(&group, remote_peer_id, next).await;
+        }
+        // Peers that aren't arbitrating just forget about it
+        let groups: Vec<String> = self.floor_holders.lock().await.iter()
+            .filter(|(_, holder)| holder.as_str() == remote_peer_id)
+            .map(|(group, _)| group.clone())
+            .collect();
+        for group in groups {
+            self.apply_floor_update(FloorMessage::Released {
+                group,
+                holder: remote_peer_id.to_string(),
+            }).await;
+        }
+    }
+
+    // Transmit timeout for a group: set_transmit_limits, then the
+    // "max_transmit_secs" of the group in room metadata, then the default
+    pub async fn set_transmit_limits(&self, group: &str, limits: TransmitLimits) {
+        self.transmit_limits.lock().await.insert(group.to_string(), limits);
+    }
+    async fn transmit_limits(&self, group: &str) -> TransmitLimits {
+        if let Some(limits) = self.transmit_limits.lock().await.get(group) {
+            return *limits;
+        }
+        metadata::find_nested_metadata_value(&*self.room_metadata.lock().await, "groups", group)
+            .and_then(|group| group.get("max_transmit_secs"))
+            .and_then(|secs| secs.as_u64())
+            .map(|secs| TransmitLimits::with_max_duration(Duration::from_secs(secs)))
+            .unwrap_or_default()
+    }
+
+    // ============================================
+    //            Emergency Calls
+    // ============================================
+
+    // Preempts every talker and opens our audio to the whole room
+    pub async fn start_emergency(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        if !metadata::can_declare_emergency(&*self.room_metadata.lock().await, &peer_id) {
+            return Err("Not allowed to make emergency calls".into());
+        }
+        if let Some(holder) = self.emergency_holder.lock().await.clone() {
+            if holder != peer_id {
+                return Err(format!("Emergency call from {} in progress", holder).into());
+            }
+        }
+        self.broadcast_emergency(FloorMessage::Emergency { holder: peer_id.clone() }).await;
+        self.begin_emergency(&peer_id).await;
+        Ok(())
+    }
+    pub async fn end_emergency(&self) {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        if self.emergency_holder.lock().await.as_deref() != Some(peer_id.as_str()) {
+            return;
+        }
+        self.broadcast_emergency(FloorMessage::EmergencyEnded { holder: peer_id.clone() }).await;
+        self.finish_emergency(&peer_id).await;
+    }
+    pub async fn emergency_holder(&self) -> Option<String> {
+        self.emergency_holder.lock().await.clone()
+    }
+
+    async fn broadcast_emergency(&self, message: FloorMessage) {
+        self.broadcast_emergency_except(None, message).await;
+    }
+    async fn broadcast_emergency_except(&self, except: Option<&str>, message: FloorMessage) {
+        let peers: Vec<String> = self.control_channels.lock().await
+            .keys()
+            .filter(|peer| Some(peer.as_str()) != except)
+            .cloned()
+            .collect();
+        for peer in peers {
+            if let Err(e) = self.send_floor_message(&peer, &message).await {
+                log::log_message(&format!("Failed to send emergency to {}: {}", peer, e));
+            }
+        }
+    }
+
+    async fn on_emergency_message(&self, remote_peer_id: &str, message: FloorMessage) {
+        // Announced by the caller itself, or passed on by the forwarding host
+        let forwarder = self.forwarder().await;
+        let from_forwarder = forwarder.as_deref() == Some(remote_peer_id);
+        match &message {
+            FloorMessage::Emergency { holder } => {
+                // Only the caller can announce its own emergency, and only with the role
+                let allowed = metadata::can_declare_emergency(
+                    &*self.room_metadata.lock().await, holder);
+                if (holder != remote_peer_id && !from_forwarder) || !allowed {
+                    log::log_message(&format!("Rejected emergency call from {}", remote_peer_id));
+                    return;
+                }
+                self.begin_emergency(holder).await;
+            }
+            FloorMessage::EmergencyEnded { holder } => {
+                if holder != remote_peer_id && !from_forwarder {
+                    return;
+                }
+                self.finish_emergency(holder).await;
+            }
+            _ => return,
+        }
+        // Clients only reach each other through the host
+        if forwarder == Some(self.local_peer_id.lock().await.clone()) {
+            self.broadcast_emergency_except(Some(remote_peer_id), message).await;
+        }
+    }
+
+    async fn begin_emergency(&self, holder: &str) {
+        *self.emergency_holder.lock().await = Some(holder.to_string());
+
+        // Everyone currently talking loses the floor
+        self.floor_arbiter.lock().await.preempt_all();
+        let preempted: Vec<(String, String)> = self.floor_holders.lock().await
+            .drain()
+            .collect();
+        for (group, talker) in preempted {
+            events::emit(&self.events, SessionEvent::TalkEnded { peer_id: talker, group });
+        }
+
+        self.log_emergency(holder, "started").await;
+        events::emit(&self.events, SessionEvent::EmergencyStarted {
+            peer_id: holder.to_string(),
+        });
+    }
+
+    async fn finish_emergency(&self, holder: &str) {
+        {
+            let mut emergency_holder = self.emergency_holder.lock().await;
+            if emergency_holder.as_deref() != Some(holder) {
+                return;
+            }
+            *emergency_holder = None;
+        }
+        self.log_emergency(holder, "ended").await;
+        events::emit(&self.events, SessionEvent::EmergencyEnded {
+            peer_id: holder.to_string(),
+        });
+    }
+
+    async fn log_emergency(&self, holder: &str, event: &str) {
+        let room_name = self.room_name.lock().await.clone();
+        log::log_message(&format!("Emergency call from {} {}", holder, event));
+        if let Err(e) = db::log_emergency(&self.pool, &room_name, holder, event) {
+            log::log_message(&format!("Failed to log emergency: {}", e));
+        }
+    }
+
+    // ============================================
+    //            Private Calls
+    // ============================================
+
+    // Rings a single peer, the call stays off every group channel
+    pub async fn call_peer(&self, remote_peer_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // An SFU room only links clients to the host, there's no channel to ring on
+        if !self.should_connect(remote_peer_id).await {
+            return Err(format!("{} can't be called privately in an SFU room, only the host can", remote_peer_id).into());
+        }
+        let call = {
+            let mut direct_call = self.direct_call.lock().await;
+            if direct_call.is_some() {
+                return Err("Already in a call".into());
+            }
+            let call = DirectCall {
+                call_id: self.next_command_id.fetch_add(1, Ordering::Relaxed),
+                peer_id: remote_peer_id.to_string(),
+                state: CallState::Dialing,
+            };
+            *direct_call = Some(call.clone());
+            call
+        };
+        // Our key goes first so it's there by the time the call is answered
+        let rung = match self.share_call_key(&call).await {
+            Ok(()) => self.send_call_message(remote_peer_id, &CallMessage::Ring { call_id: call.call_id }).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = rung {
+            self.forget_call(&call).await;
+            return Err(e);
+        }
+        events::emit(&self.events, SessionEvent::CallStateChanged {
+            peer_id: remote_peer_id.to_string(),
+            state: CallState::Dialing,
+        });
+        tokio::spawn(call_ring_timeout(self.clone(), call.call_id));
+        Ok(())
+    }
+    pub async fn accept_call(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let call = self.direct_call.lock().await.clone()
+            .filter(|call| call.state == CallState::Ringing)
+            .ok_or("No incoming call")?;
+        self.share_call_key(&call).await?;
+        self.send_call_message(&call.peer_id, &CallMessage::Accept { call_id: call.call_id }).await?;
+        self.set_call_state(CallState::Active).await;
+        Ok(())
+    }
+    pub async fn decline_call(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let call = self.direct_call.lock().await.clone()
+            .filter(|call| call.state == CallState::Ringing)
+            .ok_or("No incoming call")?;
+        self.set_call_state(CallState::Declined).await;
+        self.send_call_message(&call.peer_id, &CallMessage::Decline { call_id: call.call_id }).await
+    }
+    pub async fn hang_up(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let call = self.direct_call.lock().await.clone()
+            .ok_or("Not in a call")?;
+        self.set_call_state(CallState::Ended).await;
+        self.send_call_message(&call.peer_id, &CallMessage::HangUp { call_id: call.call_id }).await
+    }
+    pub async fn current_call(&self) -> Option<DirectCall> {
+        self.direct_call.lock().await.clone()
+    }
+
+    // A fresh key of ours for this call only, wrapped for the other side
+    async fn share_call_key(&self, call: &DirectCall) -> Result<(), Box<dyn std::error::Error>> {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let message = {
+            let mut group_keys = self.group_keys.lock().await;
+            group_keys.rotate(&call.key_group());
+            group_keys.own_message(&peer_id, &call.key_group(), &call.peer_id)
+        };
+        match message {
+            Ok(message) => {
+                self.send_key_message(&call.peer_id, &message).await;
+                Ok(())
+            }
+            Err(E2eError::UnknownPeer(_)) => Err(format!("No certificate from {} yet", call.peer_id).into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    // Clears a call that never got going, unless another one took its place
+    async fn forget_call(&self, call: &DirectCall) {
+        let mut direct_call = self.direct_call.lock().await;
+        if direct_call.as_ref().is_some_and(|current| current.call_id == call.call_id) {
+            *direct_call = None;
+        }
+        self.group_keys.lock().await.forget(&call.key_group());
+    }
+
+    pub async fn send_call_audio(&self, audio_data: FormattedAudio)
+    -> Result<(), Box<dyn std::error::Error>> {
+        if *self.admin_muted.lock().await {
+            log::log_message("Muted by an admin, audio not sent");
+            return Ok(());
+        }
+        let call = self.direct_call.lock().await.clone()
+            .filter(|call| call.is_active())
+            .ok_or("Not in an active call")?;
+        let call_channel = self.call_channels.lock().await
+            .get(&call.peer_id)
+            .cloned()
+            .ok_or_else(|| format!("No call channel to {}", call.peer_id))?;
+        if let Ok(data) = audio_data {
+            let bytes = self.seal_call_audio(&call, data).await?;
+            call_channel.send(&Bytes::from(bytes)).await?;
+        } else {
+            log::log_message("Invalid audio data");
+        }
+        Ok(())
+    }
+    // Framed like group audio, sealed with our key for the call
+    async fn seal_call_audio(&self, call: &DirectCall, data: Vec<u8>)
+    -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let group = call.key_group();
+        let samples = audio::opus_packet_samples(&data);
+        let (sequence, timestamp) = self.frame_sequencer.lock().await.next(&group, samples);
+        let mut frame = AudioFrame::new(&peer_id, &group, sequence, timestamp, data);
+        self.group_keys.lock().await.seal(&mut frame)?;
+        Ok(frame.encode()?)
+    }
+    pub async fn receive_call_audio(&self) -> mpsc::Receiver<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel(100);
+        self.call_audio_receivers.lock().await.push(sender);
+        receiver
+    }
+    async fn on_call_audio(&self, remote_peer_id: &str, data: Vec<u8>) {
+        let payload = match self.open_call_audio(remote_peer_id, &data).await {
+            Some(payload) => payload,
+            None => return,
+        };
+        let mut call_audio_receivers = self.call_audio_receivers.lock().await;
+        call_audio_receivers.retain(|receiver| !receiver.is_closed());
+        for receiver in call_audio_receivers.iter_mut() {
+            if receiver.try_send(payload.clone()).is_err() {
+                log::log_message("Failed to send received call audio");
+            }
+        }
+    }
+    // The opus payload of a frame the other side sealed for our call
+    async fn open_call_audio(&self, remote_peer_id: &str, data: &[u8]) -> Option<Vec<u8>> {
+        let call = self.direct_call.lock().await.clone()
+            .filter(|call| call.is_active() && call.peer_id == remote_peer_id)?;
+        let mut frame = match AudioFrame::decode(data) {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::log_message(&format!("Dropped call audio from {}: {}", remote_peer_id, e));
+                return None;
+            }
+        };
+        if frame.sender != remote_peer_id || frame.group != call.key_group() {
+            log::log_message(&format!("Dropped call audio from {} for {} in {}",
+                remote_peer_id, frame.sender, frame.group));
+            return None;
+        }
+        match self.group_keys.lock().await.open(&mut frame) {
+            Ok(()) => Some(frame.payload),
+            Err(e) => {
+                log::log_message(&format!("Dropped call audio from {}: {}", remote_peer_id, e));
+                None
+            }
+        }
+    }
+
+    async fn send_call_message(&self, remote_peer_id: &str, message: &CallMessage)
+    -> Result<(), Box<dyn std::error::Error>> {
+        let control_channel = self.control_channels.lock().await
+            .get(remote_peer_id)
+            .cloned()
+            .ok_or_else(|| format!("No control channel to {}", remote_peer_id))?;
+        let text = control::encode_message(&ControlMessage::Call(message.clone()));
+        control_channel.send_text(text).await?;
+        Ok(())
+    }
+
+    async fn on_call_message(&self, remote_peer_id: &str, message: CallMessage) {
+        let call_id = message.call_id();
+        if let CallMessage::Ring { .. } = message {
+            let busy = {
+                let mut direct_call = self.direct_call.lock().await;
+                let busy = direct_call.is_some();
+                if !busy {
+                    *direct_call = Some(DirectCall {
+                        call_id,
+                        peer_id: remote_peer_id.to_string(),
+                        state: CallState::Ringing,
+                    });
+                }
+                busy
+            };
+            if busy {
+                // Turn the caller away
+                let decline = CallMessage::Decline { call_id };
+                if let Err(e) = self.send_call_message(remote_peer_id, &decline).await {
+                    log::log_message(&format!("Failed to decline call from {}: {}", remote_peer_id, e));
+                }
+                return;
+            }
+            events::emit(&self.events, SessionEvent::CallStateChanged {
+                peer_id: remote_peer_id.to_string(),
+                state: CallState::Ringing,
+            });
+            tokio::spawn(call_ring_timeout(self.clone(), call_id));
+            return;
+        }
+        let current = self.direct_call.lock().await.clone()
+            .filter(|call| call.matches(remote_peer_id, call_id));
+        match message {
+            CallMessage::Ring { .. } => {}
+            CallMessage::Accept { .. } => {
+                if current.is_some_and(|call| call.state == CallState::Dialing) {
+                    self.set_call_state(CallState::Active).await;
+                }
+            }
+            CallMessage::Decline { .. } => {
+                if current.is_some() {
+                    self.set_call_state(CallState::Declined).await;
+                }
+            }
+            CallMessage::HangUp { .. } => {
+                if current.is_some() {
+                    self.set_call_state(CallState::Ended).await;
+                }
+            }
+        }
+    }
+
+    // Moves the current call along, declined and ended calls are forgotten
+    async fn set_call_state(&self, state: CallState) {
+        let (peer_id, ended) = {
+            let mut direct_call = self.direct_call.lock().await;
+            let peer_id = match direct_call.as_mut() {
+                Some(call) => {
+                    call.state = state;
+                    call.peer_id.clone()
+                }
+                None => return,
+            };
+            let ended = if matches!(state, CallState::Declined | CallState::Ended) {
+                direct_call.take()
+            } else {
+                None
+            };
+            (peer_id, ended)
+        };
+        if let Some(call) = ended {
+            self.group_keys.lock().await.forget(&call.key_group());
+        }
+        events::emit(&self.events, SessionEvent::CallStateChanged { peer_id, state });
+    }
+
+    // ============================================
+    //            Text Messages
+    // ============================================
+
+    // Sends a text message to everyone in a group
+    pub async fn send_message(&self, group: &str, message: &str) {
+        self.send_text(group, &format!("msg:{}", message)).await;
+    }
+    async fn send_text(&self, group: &str, text: &str) {
+        let audio_data_channels = self.audio_data_channels.lock().await;
+        if let Some(data_channels) = audio_data_channels.get(group) {
+            for data_channel in data_channels.values() {
+                if let Err(e) = data_channel.send_text(text.to_string()).await {
+                    log::log_message(&format!("Failed to send text to {}: {}", group, e));
+                }
+            }
+        }
+    }
+    async fn on_text_message(&self, remote_peer_id: &str, text: &str) {
+        // {type}:{payload}
+        let parts: Vec<&str> = text.splitn(2, ':').collect();
+        if parts.len() < 2 {
+            return;
+        }
+        if parts[0] == "msg" {
+            events::emit(&self.events, SessionEvent::MessageReceived {
+                peer_id: remote_peer_id.to_string(),
+                message: parts[1].to_string(),
+            });
+        }
+    }
+    // ============================================
+    //            Admin Commands
+    // ============================================
+
+    // Sends a command to every peer it targets and waits for their acks
+    pub async fn send_command(&self, command: ControlCommand)
+    -> Result<Vec<(String, ControlAck)>, Box<dyn std::error::Error>> {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        if command.requires_admin()
+            && !metadata::is_admin(&*self.room_metadata.lock().await, &peer_id) {
+            return Err("Only admins can send this command".into());
+        }
+
+        let control_channels: Vec<(String, Arc<RTCDataChannel>)> = self.control_channels
+            .lock().await
+            .iter()
+            .filter(|(remote_peer_id, _)| command.is_shared_state() || command.targets(remote_peer_id))
+            .map(|(remote_peer_id, channel)| (remote_peer_id.clone(), channel.clone()))
+            .collect();
+
+        let mut pending = Vec::new();
+        for (remote_peer_id, control_channel) in control_channels {
+            let id = self.next_command_id.fetch_add(1, Ordering::Relaxed);
+            let (ack_sender, ack_receiver) = oneshot::channel();
+            self.pending_commands.lock().await.insert(id, ack_sender);
+
+            let request = ControlMessage::Request(ControlRequest { id, command: command.clone() });
+            if let Err(e) = control_channel.send_text(control::encode_message(&request)).await {
+                log::log_message(&format!("Failed to send command to {}: {}", remote_peer_id, e));
+                self.pending_commands.lock().await.remove(&id);
+                continue;
+            }
+            pending.push(async move {
+                let ack = match tokio::time::timeout(COMMAND_ACK_TIMEOUT, ack_receiver).await {
+                    Ok(Ok(ack)) => ack,
+                    _ => ControlAck::rejected(id, "no acknowledgement"),
+                };
+                (remote_peer_id, ack)
+            });
+        }
+
+        // Keep our own view of the room in line with what we asked for
+        match &command {
+            ControlCommand::Mute { target } => self.set_mute(target, true).await,
+            ControlCommand::Unmute { target } => self.set_mute(target, false).await,
+            // Whether or not it complies, it's out of its multicast groups
+            ControlCommand::Kick { target } if target != control::TARGET_ALL => {
+                let groups = self.peer_groups.lock().await.get(target).cloned().unwrap_or_default();
+                self.rotate_multicast_keys(&groups, target).await;
+            }
+            _ => {}
+        }
+
+        let acks = futures::future::join_all(pending).await;
+        let mut pending_commands = self.pending_commands.lock().await;
+        for (_, ack) in &acks {
+            pending_commands.remove(&ack.ack);
+        }
+        Ok(acks)
+    }
+
+    async fn on_control_message(&self, remote_peer_id: &str,
+        control_channel: &Arc<RTCDataChannel>, text: &str) {
+        match control::decode_message(text) {
+            Some(ControlMessage::Ack(ack)) => {
+                if let Some(ack_sender) = self.pending_commands.lock().await.remove(&ack.ack) {
+                    let _ = ack_sender.send(ack);
+                }
+            }
+            Some(ControlMessage::Floor(message)) => {
+                self.on_floor_message(remote_peer_id, message).await;
+            }
+            Some(ControlMessage::Call(message)) => {
+                self.on_call_message(remote_peer_id, message).await;
+            }
+            Some(ControlMessage::Key(message)) => {
+                self.on_key_message(remote_peer_id, message).await;
+            }
+            Some(ControlMessage::MulticastKey(message)) => {
+                self.on_multicast_key(remote_peer_id, message).await;
+            }
+            Some(ControlMessage::Request(request)) => {
+                let ack = self.authorize_command(remote_peer_id, &request).await;
+                let accepted = ack.ok;
+                let reply = control::encode_message(&ControlMessage::Ack(ack));
+                if let Err(e) = control_channel.send_text(reply).await {
+                    log::log_message(&format!("Failed to ack command from {}: {}", remote_peer_id, e));
+                }
+                if accepted {
+                    events::emit(&self.events, SessionEvent::CommandReceived {
+                        peer_id: remote_peer_id.to_string(),
+                        command: request.command.clone(),
+                    });
+                    self.apply_command(request.command).await;
+                }
+            }
+            None => {
+                log::log_message(&format!("Invalid control message from {}", remote_peer_id));
+            }
+        }
+    }
+
+    async fn authorize_command(&self, remote_peer_id: &str, request: &ControlRequest) -> ControlAck {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        if !request.command.is_shared_state() && !request.command.targets(&peer_id) {
+            return ControlAck::rejected(request.id, "not the target");
+        }
+        if request.command.requires_admin()
+            && !metadata::is_admin(&*self.room_metadata.lock().await, remote_peer_id) {
+            return ControlAck::rejected(request.id, "sender is not an admin");
+        }
+        if request.command.requires_admin()
+            && *self.require_trusted_admins.lock().await
+            && !identity::is_trusted(&self.pool, remote_peer_id) {
+            return ControlAck::rejected(request.id, "sender's device is not trusted");
+        }
+        ControlAck::accepted(request.id)
+    }
+
+    async fn apply_command(&self, command: ControlCommand) {
+        match command {
+            ControlCommand::Mute { target } => self.set_mute(&target, true).await,
+            ControlCommand::Unmute { target } => self.set_mute(&target, false).await,
+            ControlCommand::Kick { .. } => self.leave_room().await,
+            ControlCommand::ForceLeaveGroup { group, .. } => {
+                if let Err(e) = self.leave_group(&group).await {
+                    log::log_message(&format!("Failed to leave {}: {}", group, e));
+                }
+            }
+            ControlCommand::SetVolume { volume, .. } => {
+                if let Ok(mut playback) = self.playback.lock() {
+                    playback.set_volume(volume);
+                }
+            }
+            ControlCommand::Ping { .. } => {}
+        }
+    }
+
+    // Records a mute in the room metadata and enforces it if it's about us
+    async fn set_mute(&self, target: &str, muted: bool) {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let connected_peers: Vec<String> = self.peer_connections.lock().await
+            .keys().cloned().collect();
+
+        let mut room_metadata = self.room_metadata.lock().await;
+        let targets: Vec<String> = if target == control::TARGET_ALL {
+            // Muting everyone never mutes the admins
+            let mut members = metadata::list_members(&room_metadata);
+            for peer in connected_peers.into_iter().chain(std::iter::once(peer_id.clone())) {
+                if !members.contains(&peer) {
+                    members.push(peer);
+                }
+            }
+            members.into_iter()
+                .filter(|member| !member.is_empty() && !metadata::is_admin(&room_metadata, member))
+                .collect()
+        } else {
+            vec![target.to_string()]
+        };
+        for member in &targets {
+            metadata::set_member_flag(&mut room_metadata, member, "mute", serde_json::json!(muted));
+        }
+        self.save_room_metadata(&room_metadata).await;
+        drop(room_metadata);
+
+        if targets.contains(&peer_id) {
+            *self.admin_muted.lock().await = muted;
+        }
+        for member in targets {
+            events::emit(&self.events, SessionEvent::MuteChanged { peer_id: member, muted });
+        }
+    }
+    // Picks up a mute recorded before we (re)connected
+    async fn restore_mute_state(&self) {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let muted = metadata::find_member_flag(&*self.room_metadata.lock().await, &peer_id, "mute")
+            .and_then(|mute| mute.as_bool())
+            .unwrap_or(false);
+        *self.admin_muted.lock().await = muted;
+    }
+    pub async fn is_admin_muted(&self) -> bool {
+        *self.admin_muted.lock().await
+    }
+
+    // Disconnects from the signaling server and every peer
+    pub async fn leave_room(&self) {
+        // Nothing to resume, the signaling loop stops instead of reconnecting
+        *self.session_token.lock().await = None;
+        if let Some(ws_sink) = &self.ws_sink {
+            if let Err(e) = ws_sink.lock().await.close().await {
+                log::log_message(&format!("Error closing signaling connection: {}", e));
+            }
+        }
+        let mut peers: HashSet<String> = self.peer_connections.lock().await.keys().cloned().collect();
+        peers.extend(self.relayed_peers.lock().await.iter().cloned());
+        for peer in peers {
+            self.drop_peer(&peer).await;
+        }
+        for (_, (_, receiver)) in self.multicast_sockets.lock().await.drain() {
+            receiver.abort();
+        }
+        self.multicast_keys.lock().await.clear();
+    }
+
+    // ============================================
+    //            Device Identity
+    // ============================================
+
+    // The SDP fingerprint is what DTLS holds the peer to, it has to be the
+    // one the peer registered with and the one we pinned for it
+    async fn verify_sdp_identity(&self, remote_peer_id: &str, sdp: &str) -> bool {
+        let presented = match identity::sdp_fingerprint(sdp) {
+            Some(fingerprint) => fingerprint,
+            None => {
+                log::log_message(&format!("No fingerprint in SDP from {}", remote_peer_id));
+                return false;
+            }
+        };
+        let announced = self.announced_fingerprints.lock().await.get(remote_peer_id).cloned();
+        if let Some(announced) = announced {
+            if announced != presented {
+                self.reject_identity(remote_peer_id, &announced, &presented).await;
+                return false;
+            }
+        }
+        self.check_identity(remote_peer_id, &presented).await
+    }
+    async fn check_identity(&self, remote_peer_id: &str, fingerprint: &str) -> bool {
+        match identity::check_pin(&self.pool, remote_peer_id, fingerprint) {
+            PinCheck::Pinned => {
+                log::log_message(&format!("Pinned {} to {}", remote_peer_id, fingerprint));
+                true
+            }
+            PinCheck::Known => true,
+            PinCheck::Mismatch { pinned } => {
+                self.reject_identity(remote_peer_id, &pinned, fingerprint).await;
+                false
+            }
+        }
+    }
+    async fn reject_identity(&self, remote_peer_id: &str, expected: &str, presented: &str) {
+        log::log_message(&format!("{} presented {} instead of {}, refusing it",
+            remote_peer_id, presented, expected));
+        events::emit(&self.events, SessionEvent::IdentityMismatch {
+            peer_id: remote_peer_id.to_string(),
+            expected: expected.to_string(),
+            presented: presented.to_string(),
+        });
+    }
+
+    // ============================================
+    //            Pairing
+    // ============================================
+
+    // Code to compare with the one the peer's device shows for us
+    pub async fn pairing_code(&self, remote_peer_id: &str) -> Option<ShortAuthString> {
+        let remote_fingerprint = db::get_pinned_fingerprint(&self.pool, remote_peer_id).ok().flatten()?;
+        Some(identity::short_auth_string(&self.device_fingerprint(), &remote_fingerprint))
+    }
+    // Both users saw the same code, the peer's device is trusted from now on
+    pub async fn confirm_pairing(&self, remote_peer_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if db::get_pinned_fingerprint(&self.pool, remote_peer_id)?.is_none() {
+            return Err(format!("No known device for {}", remote_peer_id).into());
+        }
+        db::set_device_trusted(&self.pool, remote_peer_id, true)?;
+        events::emit(&self.events, SessionEvent::DeviceTrusted {
+            peer_id: remote_peer_id.to_string(),
+        });
+        Ok(())
+    }
+    pub fn is_trusted(&self, remote_peer_id: &str) -> bool {
+        identity::is_trusted(&self.pool, remote_peer_id)
+    }
+    pub async fn set_require_trusted_admins(&self, required: bool) {
+        *self.require_trusted_admins.lock().await = required;
+    }
+    // Room password sent as a challenge response on registration
+    pub async fn set_room_secret(&self, secret: Option<String>) {
+        *self.room_secret.lock().await = secret;
+    }
+    // Everyone in the room, with untrusted devices flagged
+    pub async fn peer_list(&self) -> Vec<PeerInfo> {
+        let peer_groups = self.peer_groups.lock().await.clone();
+        let display_names = self.display_names.lock().await.clone();
+        let mut peers: Vec<String> = self.peer_connections.lock().await.keys().cloned().collect();
+        for peer in peer_groups.keys() {
+            if !peers.contains(peer) {
+                peers.push(peer.clone());
+            }
+        }
+        peers.sort();
+        peers.into_iter()
+            .map(|peer_id| PeerInfo {
+                display_name: identity::display_label(&display_names, &peer_id),
+                groups: peer_groups.get(&peer_id).cloned().unwrap_or_default(),
+                trusted: identity::is_trusted(&self.pool, &peer_id),
+                peer_id,
+            })
+            .collect()
+    }
+
+    // ============================================
+    //            Display Names
+    // ============================================
+
+    // Renames us for everyone in the room, and next time we start
+    pub async fn set_display_name(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let display_name = identity::sanitize_display_name(name)
+            .ok_or("Display name can't be empty")?;
+        db::store_display_name(&self.pool, &display_name)?;
+        self.display_names.lock().await.insert(self.device_id.clone(), display_name.clone());
+        events::emit(&self.events, SessionEvent::DisplayNameChanged {
+            peer_id: self.device_id.clone(),
+            display_name: display_name.clone(),
+        });
+        // Sent with the registration instead if we aren't in a room yet
+        let signaling_sender = self.signaling_sender.lock().await.clone();
+        if let Some(mut signaling_sender) = signaling_sender {
+            let peer_id = self.local_peer_id.lock().await.clone();
+            signaling_sender.try_send(Message::Text(format!("display_name:{}:{}", peer_id, display_name)))?;
+        }
+        Ok(())
+    }
+    // Stored under the device id by set_display_name, whatever id we joined with
+    async fn own_display_name(&self) -> Option<String> {
+        self.display_names.lock().await.get(&self.device_id).cloned()
+    }
+    // Name to show for a peer, told apart from others going by the same name
+    pub async fn display_name(&self, peer_id: &str) -> String {
+        identity::display_label(&*self.display_names.lock().await, peer_id)
+    }
+    async fn on_display_name(&self, remote_peer_id: &str, name: &str) {
+        let display_name = match identity::sanitize_display_name(name) {
+            Some(display_name) => display_name,
+            None => return,
+        };
+        let changed = match self.display_names.lock().await.entry(remote_peer_id.to_string()) {
+            Entry::Occupied(entry) if *entry.get() == display_name => false,
+            Entry::Occupied(mut entry) => {
+                entry.insert(display_name.clone());
+                true
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(display_name.clone());
+                true
+            }
+        };
+        if changed {
+            events::emit(&self.events, SessionEvent::DisplayNameChanged {
+                peer_id: remote_peer_id.to_string(),
+                display_name,
+            });
+        }
+    }
+
+    // ============================================
+    //            Group Keys
+    // ============================================
+
+    // Replaces our key in every group of ours someone joined or left, and
+    // hands the new one to the members that are left. Our emergency key is
+    // replaced whenever someone joins or leaves the room's groups altogether.
+    async fn rotate_group_keys(&self, old_groups: &[String], new_groups: &[String]) {
+        let local_groups = self.local_groups.lock().await.clone();
+        // Neither a private call's key nor the emergency key is tied to any
+        // group of ours
+        let mut kept = local_groups.clone();
+        kept.push(EMERGENCY_KEY_GROUP.to_string());
+        kept.extend(self.direct_call.lock().await.as_ref().map(DirectCall::key_group));
+        let (rotated, emergency_rotated) = {
+            let mut group_keys = self.group_keys.lock().await;
+            group_keys.retain_groups(&kept);
+            let rotated: Vec<String> = local_groups.into_iter()
+                .filter(|group| old_groups.contains(group) != new_groups.contains(group)
+                    || !group_keys.has_own_key(group))
+                .collect();
+            for group in &rotated {
+                group_keys.rotate(group);
+            }
+            let emergency_rotated = old_groups.is_empty() != new_groups.is_empty()
+                || !group_keys.has_own_key(EMERGENCY_KEY_GROUP);
+            if emergency_rotated {
+                group_keys.rotate(EMERGENCY_KEY_GROUP);
+            }
+            (rotated, emergency_rotated)
+        };
+        for group in rotated {
+            self.share_group_key(&group).await;
+        }
+        if emergency_rotated {
+            self.share_emergency_key().await;
+        }
+    }
+
+    // Our key wrapped for each member we have a certificate for, the rest
+    // get it once their certificate arrives
+    async fn share_group_key(&self, group: &str) {
+        for member in self.group_members(group).await {
+            self.send_own_key(&member, group).await;
+        }
+    }
+    // Emergencies are heard by everyone in a group, a host that only
+    // forwards is in none and never gets the key
+    async fn share_emergency_key(&self) {
+        for member in self.room_members().await {
+            self.send_own_key(&member, EMERGENCY_KEY_GROUP).await;
+        }
+    }
+    async fn room_members(&self) -> Vec<String> {
+        self.peer_groups.lock().await.iter()
+            .filter(|(_, groups)| !groups.is_empty())
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+    async fn group_members(&self, group: &str) -> Vec<String> {
+        self.peer_groups.lock().await.iter()
+            .filter(|(_, groups)| groups.iter().any(|g| g == group))
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+    // Our keys for every group we share with the peer, and our emergency
+    // key if it is in any group
+    async fn share_own_keys(&self, remote_peer_id: &str) {
+        let remote_groups = self.peer_groups.lock().await
+            .get(remote_peer_id)
+            .cloned()
+            .unwrap_or_default();
+        if !remote_groups.is_empty() {
+            self.send_own_key(remote_peer_id, EMERGENCY_KEY_GROUP).await;
+        }
+        let shared: Vec<String> = self.local_groups.lock().await.iter()
+            .filter(|group| remote_groups.contains(group))
+            .cloned()
+            .collect();
+        for group in shared {
+            self.send_own_key(remote_peer_id, &group).await;
+        }
+    }
+    async fn send_own_key(&self, remote_peer_id: &str, group: &str) {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let message = self.group_keys.lock().await.own_message(&peer_id, group, remote_peer_id);
+        match message {
+            Ok(message) => self.send_key_message(remote_peer_id, &message).await,
+            Err(E2eError::UnknownPeer(_)) => {}
+            Err(e) => log::log_message(&format!("Failed to wrap {} key for {}: {}", group, remote_peer_id, e)),
+        }
+    }
+
+    // Over the recipient's control channel, through the forwarding host in
+    // an SFU room, or the signaling server for relayed peers. Whoever
+    // carries it only sees a blob wrapped for the recipient.
+    async fn send_key_message(&self, recipient: &str, message: &KeyMessage) {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let forwarder = self.forwarder().await
+            .filter(|host| *host != peer_id && host != recipient);
+        let control_channel = {
+            let control_channels = self.control_channels.lock().await;
+            control_channels.get(recipient)
+                .or_else(|| forwarder.as_ref().and_then(|host| control_channels.get(host)))
+                .cloned()
+        };
+        if let Some(control_channel) = control_channel {
+            let text = control::encode_message(&ControlMessage::Key(message.clone()));
+            if let Err(e) = control_channel.send_text(text).await {
+                log::log_message(&format!("Failed to send group key to {}: {}", recipient, e));
+            }
+        } else if self.relayed_peers.lock().await.contains(recipient) {
+            let payload = serde_json::to_string(message).unwrap_or_default();
+            self.send_signaling_message(recipient, "group_key", &payload).await;
+        }
+    }
+
+    async fn on_key_message(&self, remote_peer_id: &str, message: KeyMessage) {
+        let KeyMessage::Key { sender, recipient, .. } = &message;
+        let peer_id = self.local_peer_id.lock().await.clone();
+        if *recipient == peer_id {
+            // Only unwraps if the sender it names wrapped it, whoever passed it on
+            if let Err(e) = self.group_keys.lock().await.accept(&peer_id, &message) {
+                log::log_message(&format!("Rejected group key for {} from {}: {}", sender, remote_peer_id, e));
+            }
+            return;
+        }
+        // The forwarding host passes on what it can't open, nobody else does
+        if self.forwarder().await.as_deref() == Some(peer_id.as_str()) {
+            self.group_keys.lock().await.remember_forwarded(&message);
+            self.send_key_message(recipient, &message).await;
+        }
+    }
+
+    // Keys a peer needs once its control channel is up
+    async fn on_control_open(&self, remote_peer_id: &str) {
+        let peer_id = self.local_peer_id.lock().await.clone();
+        let forwarder = self.forwarder().await;
+        if forwarder.as_deref() == Some(remote_peer_id) {
+            // The host is our way to every other member
+            let local_groups = self.local_groups.lock().await.clone();
+            for group in local_groups {
+                self.share_group_key(&group).await;
+            }
+            self.share_emergency_key().await;
+        } else {
+            self.share_own_keys(remote_peer_id).await;
+        }
+        self.share_multicast_keys(remote_peer_id).await;
+        if forwarder.as_deref() == Some(peer_id.as_str()) {
+            let forwarded = self.group_keys.lock().await.forwarded_for(remote_peer_id);
+            for message in forwarded {
+                self.send_key_message(remote_peer_id, &message).await;
+            }
+        }
+    }
+
+    // Our device certificate, for the peer to wrap its group keys for us
+    async fn send_certificate(&self, remote_peer_id: &str) {
+        let certificate = hex::encode(self.group_keys.lock().await.device_certificate());
+        self.send_signaling_message(remote_peer_id, "certificate", &certificate).await;
+    }
+    // Accepts the peer's certificate if it is the one its fingerprint was
+    // pinned to, then hands it the keys of the groups we share
+    async fn on_peer_certificate(&self, remote_peer_id: &str, certificate: &str) {
+        let certificate = match hex::decode(certificate) {
+            Ok(certificate) => certificate,
+            Err(_) => {
+                log::log_message(&format!("Malformed certificate from {}", remote_peer_id));
+                return;
+            }
+        };
+        let presented = tls::certificate_fingerprint(&certificate);
+        let announced = self.announced_fingerprints.lock().await.get(remote_peer_id).cloned();
+        if let Some(announced) = announced {
+            if announced != presented {
+                self.reject_identity(remote_peer_id, &announced, &presented).await;
+                return;
+            }
+        }
+        if !self.check_identity(remote_peer_id, &presented).await {
+            return;
+        }
+        if let Err(e) = self.group_keys.lock().await.add_peer_certificate(remote_peer_id, &certificate) {
+            log::log_message(&format!("Unusable certificate from {}: {}", remote_peer_id, e));
+            return;
+        }
+        self.share_own_keys(remote_peer_id).await;
+    }
+
+    // Decrypts a group frame, None if it can't be
+    async fn open_frame(&self, mut frame: AudioFrame, is_emergency: bool) -> Option<AudioFrame> {
+        // The end of a transmission carries nothing to hide
+        if frame.payload.is_empty() {
+            return Some(frame);
+        }
+        let opened = if is_emergency {
+            self.group_keys.lock().await.open_as(&mut frame, EMERGENCY_KEY_GROUP)
+        } else {
+            self.group_keys.lock().await.open(&mut frame)
+        };
+        match opened {
+            Ok(()) => Some(frame),
+            Err(e) => {
+                log::log_message(&format!("Dropped audio from {} in {}: {}", frame.sender, frame.group, e));
+                None
+            }
+        }
+    }
+
+    // ============================================
+    //            Multicast Transport
+    // ============================================
+
+    // Interface the multicast groups run on, as picked with
+    // discovery::select_network_interface
+    pub async fn set_multicast_interface(&self, interface: IpAddr) {
+        match interface {
+            IpAddr::V4(interface) => *self.multicast_interface.lock().await = Some(interface),
+            IpAddr::V6(_) => {
+                log::log_message("Multicast audio needs an IPv4 interface");
+                return;
+            }
         }
+        self.sync_multicast().await;
+    }
 
-        // Register with the signaling server
-
-        let (signaling_sender, mut signaling_receiver) = mpsc::channel(100);
-        let ws_sink_clone = Arc::clone(&ws_sink);
+    // Joins the multicast groups we are in and leaves the others
+    async fn sync_multicast(&self) {
+        let interface = match *self.multicast_interface.lock().await {
+            Some(interface) => interface,
+            None => return,
+        };
+        let room_name = self.room_name.lock().await.clone();
+        let metadata = self.room_metadata.lock().await.clone();
+        let wanted: Vec<String> = self.local_groups.lock().await.iter()
+            .filter(|group| multicast::is_multicast_group(&metadata, group))
+            .cloned()
+            .collect();
 
-        // handle outgoing signaling messages
-        tokio::spawn(async move {
-            while let Some(message) = signaling_receiver.next().await{
-                let mut sink = ws_sink_clone.lock().await;
-                if let Err(e) = sink.send(message).await {
-                    log::log_message(&format!("Error sending message: {}", e));
-                }
+        let mut multicast_sockets = self.multicast_sockets.lock().await;
+        multicast_sockets.retain(|group, (_, receiver)| {
+            let keep = wanted.contains(group);
+            if !keep {
+                receiver.abort();
             }
+            keep
         });
-
-        // Receive initial list of peers in the channel
-        if let Some(Ok(Message::Text(peer_list))) = ws_stream.next().await {
-            let peers: Vec<String> = peer_list.split(',')
-                .map(|s| s.to_string())
-                .collect();
-            // Create an offer for each peer and send it
-            for peer in peers {
-                let peer_connection = create_peer_connection(
-                    &self.api,
-                    &mut self.audio_data_channels,
-                    signaling_sender.clone(),
-                    peer_id.to_string(),
-                    peer.clone().to_string(),
-                    initial_groups.clone()
-                ).await?;
-
-                let offer_sdp = create_offer(&peer_connection).await?;
-                let mut peer_connections = self.peer_connections.lock().await;
-                peer_connections.insert(peer.clone(), peer_connection);
-
-                let mut sink = ws_sink.lock().await;
-                sink.send(Message::Text(
-                    // Message Format
-                    // {receiver}:{type}:{sender}:{message}
-                    format!("{}:offer:{}:{}", peer, peer_id, offer_sdp)
-                )).await?;
+        for group in wanted {
+            if multicast_sockets.contains_key(&group) {
+                continue;
+            }
+            // Joined once the host sends us the key
+            let key = match self.multicast_key(&group).await {
+                Some(key) => key,
+                None => continue,
+            };
+            match MulticastSocket::join(interface, &room_name, &group, &key) {
+                Ok(socket) => {
+                    let socket = Arc::new(socket);
+                    let receiver = tokio::spawn(multicast_receiver(self.clone(), group.clone(), socket.clone()));
+                    multicast_sockets.insert(group, (socket, receiver));
+                }
+                Err(e) => {
+                    log::log_message(&format!("Failed to join multicast group {}: {}", group, e));
+                }
             }
         }
-        // Recieve messages from the signaling server
-        while let Some(message) = ws_stream.next().await{
-            match message? {
-                Message::Text(text) => {
-                    if text.starts_with("new_peer:") {
-                        let parts: Vec<&str> = text.splitn(3, ':').collect();
-                        let new_peer_id = parts[1].to_string();
-                        let new_peer_groups: Vec<String> = parts[2].split(',')
-                            .map(|s| s.to_string()).collect();
-                        self.peer_groups.insert(
-                            new_peer_id.clone(),
-                            new_peer_groups.clone()
-                        );
-
-                        let peer_connection = create_peer_connection(
-                            &self.api,
-                            &mut self.audio_data_channels,
-                            signaling_sender.clone(),
-                            peer_id.to_string(),
-                            new_peer_id.to_string(),
-                            new_peer_groups.clone()
-                        ).await?;
-
-                        let offer_sdp = create_offer(&peer_connection).await?;
-                        let mut peer_connections = self.peer_connections.lock().await;
-                        peer_connections.insert(
-                            new_peer_id.to_string(),
-                            peer_connection
-                        );
-                        let mut sink = ws_sink.lock().await;
-                        sink.send(Message::Text(
-                            format!("{}:offer:{}:{}",
-                                new_peer_id,
-                                peer_id,
-                                offer_sdp)
-                        )).await?;
+    }
 
-                    } else {
-                        let parts: Vec<&str> = text.splitn(4, ':').collect();
-                        let message_type = parts[0];
-                        let remote_peer_id = parts[1];
+    // Key of a multicast group, the host makes it up the first time it's needed
+    async fn multicast_key(&self, group: &str) -> Option<MulticastKey> {
+        let is_host = self.is_room_host().await;
+        let mut multicast_keys = self.multicast_keys.lock().await;
+        if let Some(key) = multicast_keys.get(group) {
+            return Some(*key);
+        }
+        if !is_host {
+            return None;
+        }
+        let key = multicast::generate_key();
+        multicast_keys.insert(group.to_string(), key);
+        Some(key)
+    }
+    async fn is_room_host(&self) -> bool {
+        let local_peer_id = self.local_peer_id.lock().await.clone();
+        self.is_room_host_id(&local_peer_id).await
+    }
 
-                        match message_type {
-                            "offer" => {
-                                let sdp = parts[2];
-                                let groups: Vec<String> = parts[3].split(',')
-                                .map(|s| s.to_string()).collect();
-
-                                let peer_connection = create_peer_connection(
-                                    &self.api,
-                                    &mut self.audio_data_channels,
-                                    signaling_sender.clone(),
-                                    peer_id.to_string(),
-                                    remote_peer_id.to_string(),
-                                    groups.clone()
-                                ).await?;
-
-                                set_remote_description(
-                                    &peer_connection,
-                                    RTCSessionDescription::offer(
-                                        sdp.to_string()
-                                    )?
-                                ).await?;
-
-                                let answer_sdp = create_answer(&peer_connection).await?;
-                                let mut peer_connections = self.peer_connections.lock().await;
-                                peer_connections.insert(
-                                    remote_peer_id.to_string(),
-                                    peer_connection
-                                );
-                                let mut sink = ws_sink.lock().await;
-                                sink.send(Message::Text(
-                                    format!("{}:answer:{}:{}",
-                                        remote_peer_id,
-                                        peer_id,
-                                        answer_sdp)
-                                )).await?;
-                            }
-                            "answer" => {
-                                let sdp = parts[2];
-                                let mut peer_connections = self.peer_connections.lock().await;
-                                if let Some(peer_connection) = peer_connections
-                                    .get_mut(remote_peer_id) {
-                                    set_remote_description(
-                                        &peer_connection,
-                                        RTCSessionDescription::answer(
-                                            sdp.to_string()
-                                        )?
-                                    ).await?;
-                                }
-                            }
-                            "candidate" => {
-                                let candidate = parts[2];
-                                let ice_candidate = RTCIceCandidateInit {
-                                    candidate: candidate.to_string(),
-                                    sdp_mid: None,
-                                    sdp_mline_index: None,
-                                    username_fragment: None,
-                                };
-                                // Add ICE Candidate to the peer_connection
-                                let mut peer_connections = self.peer_connections.lock().await;
-                                if let Some(peer_connection) = peer_connections
-                                    .get_mut(remote_peer_id) {
-                                    add_ice_candidate(
-                                        peer_connection,
-                                        ice_candidate
-                                    ).await?;
-                                }
-                            }
-                            "group_update:" => {
-                                let new_groups: Vec<String> = parts[2].split(',')
-                                    .map(|s| s.to_string()).collect();
-                                self.peer_groups.insert(
-                                    remote_peer_id.to_string(), new_groups.clone()
-                                );
-                            }
-                            _ => {}
-                        }
-                    }
-                },
-                _ => {}
+    // As the host, sends a peer the keys of its multicast groups
+    async fn share_multicast_keys(&self, remote_peer_id: &str) {
+        if !self.is_room_host().await {
+            return;
+        }
+        let metadata = self.room_metadata.lock().await.clone();
+        let groups: Vec<String> = self.peer_groups.lock().await
+            .get(remote_peer_id)
+            .map(|groups| groups.iter()
+                .filter(|group| multicast::is_multicast_group(&metadata, group))
+                .cloned()
+                .collect())
+            .unwrap_or_default();
+        for group in groups {
+            if let Some(key) = self.multicast_key(&group).await {
+                self.send_multicast_key(remote_peer_id, &group, &key).await;
             }
         }
-        Ok(())
+    }
+    // As the host, replaces the keys of multicast groups a member left or
+    // was kicked from so it can't listen in from here on, and hands the new
+    // ones to everyone still in them
+    async fn rotate_multicast_keys(&self, groups: &[String], leaving_peer_id: &str) {
+        if groups.is_empty() || !self.is_room_host().await {
+            return;
+        }
+        let rotated: Vec<(String, MulticastKey)> = {
+            let mut multicast_keys = self.multicast_keys.lock().await;
+            let mut rotated = Vec::new();
+            for group in groups {
+                if let Some(key) = multicast_keys.get_mut(group) {
+                    *key = multicast::generate_key();
+                    rotated.push((group.clone(), *key));
+                }
+            }
+            rotated
+        };
+        for (group, key) in &rotated {
+            if let Some((_, receiver)) = self.multicast_sockets.lock().await.remove(group) {
+                receiver.abort();
+            }
+            for member in self.group_members(group).await {
+                if member != leaving_peer_id {
+                    self.send_multicast_key(&member, group, key).await;
+                }
+            }
+        }
+        if !rotated.is_empty() {
+            self.sync_multicast().await;
+        }
+    }
+    // Over the member's control channel, whose certificate is pinned, or
+    // the signaling server if we have no direct path to it
+    async fn send_multicast_key(&self, remote_peer_id: &str, group: &str, key: &MulticastKey) {
+        let message = MulticastKeyMessage {
+            multicast_group: group.to_string(),
+            key: multicast::encode_key(key),
+        };
+        let control_channel = self.control_channels.lock().await.get(remote_peer_id).cloned();
+        if let Some(control_channel) = control_channel {
+            let text = control::encode_message(&ControlMessage::MulticastKey(message));
+            if let Err(e) = control_channel.send_text(text).await {
+                log::log_message(&format!("Failed to send multicast key to {}: {}", remote_peer_id, e));
+            }
+        } else if self.relayed_peers.lock().await.contains(remote_peer_id) {
+            let payload = serde_json::to_string(&message).unwrap_or_default();
+            self.send_signaling_message(remote_peer_id, "multicast_key", &payload).await;
+        }
     }
 
-    // ============================================
-    //            Audio Handling
-    // ============================================
-
-    pub async fn send_audio(
-        &self,
-        audio_data: FormattedAudio,
-        group: &str)
-    -> Result<(), Box<dyn std::error::Error>>{
-        let active = *self.audio_sending_active.lock().await;
-        if !active {
-            return Ok(());
+    async fn on_multicast_key(&self, remote_peer_id: &str, message: MulticastKeyMessage) {
+        if !self.is_room_host_id(remote_peer_id).await {
+            log::log_message(&format!("Ignored multicast key from {}", remote_peer_id));
+            return;
         }
-        // Check if the audio data is valid
-        if let Ok(data) = audio_data {
-            let bytes = Bytes::from(data);
-            // Send audio to the specified destination using WebRTC
-            if let Some(data_channels) = self.audio_data_channels.get(group) {
-                for data_channel in data_channels {
-                    if let Ok(_) = data_channel.send(&bytes).await {
-                        log::log_message("Audio data sent successfully");
-                    } else {
-                        log::log_message("Failed to send audio data");
-                    }
+        let group = message.multicast_group.as_str();
+        let key = match multicast::decode_key(&message.key) {
+            Some(key) => key,
+            None => {
+                log::log_message(&format!("Invalid multicast key from {}", remote_peer_id));
+                return;
+            }
+        };
+        let previous = self.multicast_keys.lock().await.insert(group.to_string(), key);
+        // A new key means rejoining with it
+        if previous.is_some_and(|previous| previous != key) {
+            if let Some((_, receiver)) = self.multicast_sockets.lock().await.remove(group) {
+                receiver.abort();
+            }
+        }
+        self.sync_multicast().await;
+    }
+    async fn is_room_host_id(&self, peer_id: &str) -> bool {
+        let (_, host) = self.topology().await;
+        host.as_deref() == Some(peer_id)
+    }
+
+    // True if the group runs over multicast, whether or not the send worked
+    async fn send_multicast(&self, group: &str, frame: &[u8]) -> bool {
+        let socket = self.multicast_sockets.lock().await
+            .get(group)
+            .map(|(socket, _)| socket.clone());
+        match socket {
+            Some(socket) => {
+                if let Err(e) = socket.send(frame).await {
+                    log::log_message(&format!("Failed to send multicast audio to {}: {}", group, e));
                 }
+                true
             }
-        } else {
-            log::log_message("Invalid audio data");
+            None => false,
         }
-        Ok(())
     }
-    pub async fn receive_audio(&self, group: &str) -> mpsc::Receiver<Vec<u8>> {
-        let (sender, receiver) = mpsc::channel(100); // Channel to send audio data
 
-        if let Some(data_channels) = self.audio_data_channels.get(group) {
-            for data_channel in data_channels {
-                let sender_clone = sender.clone();
-                let data_channel_clone = Arc::clone(data_channel);
-                let active = self.audio_receiving_active.clone();
-
-                data_channel_clone.on_message(Box::new(move |msg: DataChannelMessage| {
-                    let mut sender_clone = sender_clone.clone();
-                    let active = active.clone();
-                    tokio::spawn(async move {
-                        let active = *active.lock().await;
-                        if !active {
-                            return;
-                        }
-                        let data = msg.data.to_vec();
-                        // Send the data through the channel
-                        if let Err(_) = sender_clone.try_send(data) {
-                            log::log_message("Failed to send received audio data");
-                        }
-                    });
-                    Box::pin(async {})
-                }));
+    async fn on_multicast_audio(&self, group: &str, data: Vec<u8>) {
+        let frame = match AudioFrame::decode(&data) {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::log_message(&format!("Dropped multicast audio in {}: {}", group, e));
+                return;
             }
+        };
+        // Our own frames loop back, and a group's key only speaks for that group
+        if frame.group != group || *self.local_peer_id.lock().await == frame.sender {
+            return;
         }
-        receiver
+        let is_member = self.peer_groups.lock().await
+            .get(&frame.sender)
+            .is_some_and(|groups| groups.iter().any(|g| g == group));
+        if !is_member {
+            return;
+        }
+        let lost = match self.next_frame_gap(&frame).await {
+            Some(lost) => lost,
+            None => return,
+        };
+        if !*self.audio_receiving_active.lock().await {
+            return;
+        }
+        let frame = match self.open_frame(frame, false).await {
+            Some(frame) => frame,
+            None => return,
+        };
+        if self.scan_audio(&frame, false).await {
+            self.play_frame(&frame, lost);
+        }
+        self.deliver_audio(&frame, vec![frame.group.clone()]).await;
     }
+
     // ============================================
     //            Control Audio Sending/Receiving
     // ============================================
@@ -385,13 +3177,11 @@ impl WebRTCModule {
 // ============================================
 async fn create_peer_connection(
     api: &Arc<Mutex<webrtc::api::API>>,
-    audio_data_channels: &mut HashMap<String, Vec<Arc<RTCDataChannel>>>,
     signaling_sender: mpsc::Sender<Message>,
     peer_id: String,
     remote_peer_id: String,
-    groups: Vec<String>,
-) -> Result<RTCPeerConnection, Error> {
-    let config = create_rtc_configuration();
+    config: RTCConfiguration,
+) -> Result<(RTCPeerConnection, PeerChannels), Error> {
     let api = api.lock().await;
     let peer_connection = api.new_peer_connection(config).await?;
 
@@ -417,16 +3207,32 @@ async fn create_peer_connection(
     // Create a data channel for audio
     let data_channel_init = RTCDataChannelInit {
         ordered: Some(true),
+        negotiated: Some(AUDIO_CHANNEL_ID),
         ..Default::default()
     };
     let audio_data_channel = peer_connection.create_data_channel("audio", Some(data_channel_init)).await?;
-    for group in &groups {
-        audio_data_channels.entry(group.to_string())
-            .or_insert(Vec::new())
-            .push(audio_data_channel.clone());
-    }
 
-    Ok(peer_connection)
+    // Create a reliable data channel for admin commands
+    let control_channel_init = RTCDataChannelInit {
+        ordered: Some(true),
+        negotiated: Some(CONTROL_CHANNEL_ID),
+        ..Default::default()
+    };
+    let control_channel = peer_connection.create_data_channel("control", Some(control_channel_init)).await?;
+
+    // Create a data channel for private calls, kept apart from group audio
+    let call_channel_init = RTCDataChannelInit {
+        ordered: Some(true),
+        negotiated: Some(CALL_CHANNEL_ID),
+        ..Default::default()
+    };
+    let call_channel = peer_connection.create_data_channel("call", Some(call_channel_init)).await?;
+
+    Ok((peer_connection, PeerChannels {
+        audio: audio_data_channel,
+        control: control_channel,
+        call: call_channel,
+    }))
 }
 // Media Engine
 async fn create_media_engine() -> Result<MediaEngine, webrtc::Error>  {
@@ -459,19 +3265,6 @@ async fn create_api() -> Result<webrtc::api::API, Error> {
     let api = APIBuilder::new().with_media_engine(media_engine).build();
     Ok(api)
 }
-// RTC Configuration
-fn create_rtc_configuration() -> RTCConfiguration {
-    RTCConfiguration {
-        // Maybe add STUN server in future
-        ice_servers: vec![],
-        ice_transport_policy: RTCIceTransportPolicy::All,
-        bundle_policy: RTCBundlePolicy::MaxBundle,
-        rtcp_mux_policy: RTCRtcpMuxPolicy::Require,
-        ice_candidate_pool_size: 0,
-        // peer_identity & certificates ommited
-        ..Default::default()
-    }
-}
 // Create Offer
 async fn create_offer(peer_connection: &RTCPeerConnection) -> Result<String, Error> {
     // First step when communicating with peer
@@ -490,6 +3283,25 @@ async fn create_answer(peer_connection: &RTCPeerConnection) -> Result<String, Er
 
     Ok(answer.sdp)
 }
+// Create ICE Restart Offer
+async fn create_restart_offer(peer_connection: &RTCPeerConnection) -> Result<String, Error> {
+    // Same as an offer but with fresh ICE credentials so candidates are gathered again
+    let options = RTCOfferOptions {
+        ice_restart: true,
+        ..Default::default()
+    };
+    let offer = peer_connection.create_offer(Some(options)).await?;
+
+    peer_connection.set_local_description(offer.clone()).await?;
+
+    Ok(offer.sdp)
+}
+// Drops the offer we made, to take the remote one instead
+async fn rollback(peer_connection: &RTCPeerConnection) -> Result<(), Error> {
+    let mut rollback = peer_connection.pending_local_description().await.unwrap_or_default();
+    rollback.sdp_type = RTCSdpType::Rollback;
+    peer_connection.set_local_description(rollback).await
+}
 // Set Remote Description
 async fn set_remote_description(peer_connection: &RTCPeerConnection, received_offer: RTCSessionDescription) -> Result<(), Error> {
     // Receives offer and sets it as the remote description
@@ -503,14 +3315,641 @@ async fn add_ice_candidate(peer_connection: &RTCPeerConnection, candidate: RTCIc
     Ok(())
 }
 // Handle Peer Connection Events
-#[allow(dead_code)]
-async fn handle_peer_connection_events(peer_connection: &RTCPeerConnection) {
-    #[allow(unused_variables)]
-    let on_connection_state_change = peer_connection.on_peer_connection_state_change(Box::new(
-        | state: RTCPeerConnectionState| {
-            println!("Peer Connection State had changed: {}", state);
-            Box::pin(async {})
+fn handle_peer_connection_events(peer_connection: &Arc<RTCPeerConnection>,
+    module: WebRTCModule, remote_peer_id: String) {
+    // Weak reference so the handler doesn't keep the connection alive
+    let weak_connection = Arc::downgrade(peer_connection);
+    peer_connection.on_peer_connection_state_change(Box::new(
+        move |state: RTCPeerConnectionState| {
+            let module = module.clone();
+            let remote_peer_id = remote_peer_id.clone();
+            let weak_connection = weak_connection.clone();
+            Box::pin(async move {
+                if let Some(peer_connection) = weak_connection.upgrade() {
+                    module.on_connection_state_change(remote_peer_id, peer_connection, state).await;
+                }
+            })
         },
     ));
-    // Additional event handlers can be added here
+}
+// Negotiated channel carrying one group's audio to one peer
+async fn create_group_channel(peer_connection: &RTCPeerConnection, group: &str)
+-> Result<Arc<RTCDataChannel>, Error> {
+    let data_channel_init = RTCDataChannelInit {
+        ordered: Some(true),
+        negotiated: Some(group_channel_id(group)),
+        ..Default::default()
+    };
+    peer_connection.create_data_channel(&format!("group:{}", group), Some(data_channel_init)).await
+}
+// FNV-1a, std's hasher isn't guaranteed to agree across builds
+fn group_channel_id(group: &str) -> u16 {
+    let hash = group.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+    GROUP_CHANNEL_BASE + (hash % GROUP_CHANNEL_RANGE) as u16
+}
+// Leaves out groups that hash to the same channel id, their audio would
+// mix. Both ends see the same groups so they leave out the same ones.
+// Groups a peer was in before an update and isn't anymore
+fn left_groups(old_groups: &[String], new_groups: &[String]) -> Vec<String> {
+    old_groups.iter()
+        .filter(|group| !new_groups.contains(group))
+        .cloned()
+        .collect()
+}
+fn without_channel_collisions(groups: Vec<String>, remote_peer_id: &str) -> Vec<String> {
+    let mut counts: HashMap<u16, usize> = HashMap::new();
+    for group in &groups {
+        *counts.entry(group_channel_id(group)).or_default() += 1;
+    }
+    groups.into_iter()
+        .filter(|group| {
+            let id = group_channel_id(group);
+            let collides = counts[&id] > 1;
+            if collides {
+                log::log_message(&format!(
+                    "Not opening {} to {}, another group shares channel {}", group, remote_peer_id, id));
+            }
+            !collides
+        })
+        .collect()
+}
+// Handle Data Channel Messages
+fn handle_data_channel_messages(data_channel: &Arc<RTCDataChannel>,
+    module: WebRTCModule, remote_peer_id: String) {
+    // Single dispatcher per channel, text is signaling and binary is audio
+    let weak_channel = Arc::downgrade(data_channel);
+    data_channel.on_message(Box::new(move |msg: DataChannelMessage| {
+        let module = module.clone();
+        let remote_peer_id = remote_peer_id.clone();
+        let weak_channel = weak_channel.clone();
+        Box::pin(async move {
+            if msg.is_string {
+                let text = String::from_utf8_lossy(&msg.data).to_string();
+                module.on_text_message(&remote_peer_id, &text).await;
+            } else if let Some(data_channel) = weak_channel.upgrade() {
+                module.on_audio_message(&remote_peer_id, Some(&data_channel), msg.data.to_vec()).await;
+            }
+        })
+    }));
+}
+// Floor Timer
+async fn floor_timer(module: WebRTCModule) {
+    loop {
+        tokio::time::sleep(FLOOR_CHECK_INTERVAL).await;
+        let expired = module.floor_arbiter.lock().await.expire(tokio::time::Instant::now());
+        for (group, holder, next) in expired {
+            log::log_message(&format!("Floor in {} taken back from {}", group, holder));
+            module.hand_over_floor(&group, &holder, next).await;
+        }
+    }
+}
+// Multicast Receiver
+async fn multicast_receiver(module: WebRTCModule, group: String, socket: Arc<MulticastSocket>) {
+    loop {
+        match socket.recv().await {
+            Ok(frame) => module.on_multicast_audio(&group, frame).await,
+            Err(e) => {
+                log::log_message(&format!("Multicast receive failed in {}: {}", group, e));
+                break;
+            }
+        }
+    }
+}
+async fn stats_timer(module: WebRTCModule) {
+    loop {
+        tokio::time::sleep(STATS_INTERVAL).await;
+        module.collect_stats().await;
+    }
+}
+// Call Ring Timeout
+async fn call_ring_timeout(module: WebRTCModule, call_id: u64) {
+    tokio::time::sleep(CALL_RING_TIMEOUT).await;
+    let unanswered = module.direct_call.lock().await.as_ref()
+        .is_some_and(|call| call.call_id == call_id
+            && matches!(call.state, CallState::Dialing | CallState::Ringing));
+    if unanswered {
+        log::log_message("Private call went unanswered");
+        let _ = module.hang_up().await;
+    }
+}
+// Handle Call Audio
+fn handle_call_audio(call_channel: &Arc<RTCDataChannel>,
+    module: WebRTCModule, remote_peer_id: String) {
+    call_channel.on_message(Box::new(move |msg: DataChannelMessage| {
+        let module = module.clone();
+        let remote_peer_id = remote_peer_id.clone();
+        Box::pin(async move {
+            module.on_call_audio(&remote_peer_id, msg.data.to_vec()).await;
+        })
+    }));
+}
+// Handle Control Messages
+fn handle_control_messages(control_channel: &Arc<RTCDataChannel>,
+    module: WebRTCModule, remote_peer_id: String) {
+    // Hand over our group keys as soon as the channel can carry them
+    let open_module = module.clone();
+    let open_peer_id = remote_peer_id.clone();
+    control_channel.on_open(Box::new(move || {
+        Box::pin(async move {
+            open_module.on_control_open(&open_peer_id).await;
+        })
+    }));
+    let weak_channel = Arc::downgrade(control_channel);
+    control_channel.on_message(Box::new(move |msg: DataChannelMessage| {
+        let module = module.clone();
+        let remote_peer_id = remote_peer_id.clone();
+        let weak_channel = weak_channel.clone();
+        Box::pin(async move {
+            if let Some(control_channel) = weak_channel.upgrade() {
+                let text = String::from_utf8_lossy(&msg.data).to_string();
+                module.on_control_message(&remote_peer_id, &control_channel, &text).await;
+            }
+        })
+    }));
+}
+// Wait For Connection
+async fn wait_for_connection(peer_connection: &RTCPeerConnection, timeout: Duration) -> bool {
+    // Polls the connection state until it connects, closes or times out
+    let deadline = tokio::time::Instant::now() + timeout;
+    while tokio::time::Instant::now() < deadline {
+        match peer_connection.connection_state() {
+            RTCPeerConnectionState::Connected => return true,
+            RTCPeerConnectionState::Closed => return false,
+            _ => tokio::time::sleep(Duration::from_millis(250)).await,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 20 ms mono CELT frame
+    const OPUS_FRAME: [u8; 4] = [0xf8, 0xff, 0xfe, 0x00];
+
+    // A module in its own db, alone in the room and in these groups. The db
+    // goes away with the returned guard.
+    async fn test_module(peer_id: &str, groups: &[&str]) -> (WebRTCModule, db::TestDb) {
+        let db = db::TestDb::new();
+        let module = WebRTCModule::new(&db).await.unwrap();
+        let groups: Vec<String> = groups.iter().map(|g| g.to_string()).collect();
+        *module.local_peer_id.lock().await = peer_id.to_string();
+        *module.local_groups.lock().await = groups.clone();
+        module.rotate_group_keys(&[], &groups).await;
+        (module, db)
+    }
+
+    // A control channel to the peer, enough for the module to count it as
+    // connected. Nothing is ever sent on it.
+    async fn add_control_channel(module: &WebRTCModule, peer_id: &str) -> RTCPeerConnection {
+        let peer_connection = module.api.lock().await
+            .new_peer_connection(RTCConfiguration::default()).await.unwrap();
+        let control_channel = peer_connection.create_data_channel("control", None).await.unwrap();
+        module.control_channels.lock().await.insert(peer_id.to_string(), control_channel);
+        peer_connection
+    }
+
+    fn drain(events: &mut events::EventReceiver) -> Vec<SessionEvent> {
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        received
+    }
+
+    // Talks for the given time, a packet every 500 ms, until send_audio fails
+    async fn talk(module: &WebRTCModule, group: &str, duration: Duration) -> Result<(), String> {
+        let step = Duration::from_millis(500);
+        let mut elapsed = Duration::ZERO;
+        while elapsed < duration {
+            module.send_audio(Ok(OPUS_FRAME.to_vec()), group).await.map_err(|e| e.to_string())?;
+            tokio::time::advance(step).await;
+            elapsed += step;
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn signaling_reconnects_back_off_up_to_the_cap() {
+        let (module, _db) = test_module("alice", &["ops"]).await;
+        // Nothing listens there
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let started = tokio::time::Instant::now();
+
+        let result = module.reconnect_signaling(&format!("ws://127.0.0.1:{}", port)).await;
+        assert!(result.is_err());
+        // 0.5 s doubling to the 30 s cap, twelve attempts
+        assert_eq!(started.elapsed(), Duration::from_millis(500 + 1_000 + 2_000 + 4_000 + 8_000 + 16_000 + 6 * 30_000));
+    }
+
+    #[tokio::test]
+    async fn colliding_group_channels_are_refused() {
+        // Both hash to channel 2578
+        assert_eq!(group_channel_id("team11"), group_channel_id("team280"));
+        let (module, _db) = test_module("alice", &["team11"]).await;
+        assert!(module.join_group("team280").await.is_err());
+        assert_eq!(*module.local_groups.lock().await, vec!["team11"]);
+
+        let groups = vec!["team11".to_string(), "ops".to_string(), "team280".to_string()];
+        assert_eq!(without_channel_collisions(groups, "bob"), vec!["ops"]);
+    }
+
+    #[tokio::test]
+    async fn private_calls_in_sfu_rooms_only_reach_the_host() {
+        let (module, _db) = test_module("bob", &["ops"]).await;
+        let mut metadata = HashMap::new();
+        metadata.insert("host".to_string(), serde_json::json!("alice"));
+        metadata.insert(crate::topology::MODE_METADATA_KEY.to_string(), serde_json::json!("sfu"));
+        module.set_room_metadata("room", metadata).await;
+
+        let error = module.call_peer("carol").await.unwrap_err();
+        assert!(error.to_string().contains("SFU"));
+        assert!(module.current_call().await.is_none());
+        // The host is a direct link, that call only fails for want of a channel
+        let error = module.call_peer("alice").await.unwrap_err();
+        assert!(!error.to_string().contains("SFU"));
+    }
+
+    // Each module takes the other's certificate, as if both were pinned
+    async fn introduce(a: (&WebRTCModule, &str), b: (&WebRTCModule, &str)) {
+        let a_certificate = a.0.group_keys.lock().await.device_certificate().to_vec();
+        let b_certificate = b.0.group_keys.lock().await.device_certificate().to_vec();
+        a.0.group_keys.lock().await.add_peer_certificate(b.1, &b_certificate).unwrap();
+        b.0.group_keys.lock().await.add_peer_certificate(a.1, &a_certificate).unwrap();
+    }
+
+    #[tokio::test]
+    async fn call_audio_only_opens_for_the_other_side() {
+        let (alice, _alice_db) = test_module("alice", &["ops"]).await;
+        let (bob, _bob_db) = test_module("bob", &["ops"]).await;
+        let (carol, _carol_db) = test_module("carol", &["ops"]).await;
+        introduce((&alice, "alice"), (&bob, "bob")).await;
+        introduce((&alice, "alice"), (&carol, "carol")).await;
+        let call = |peer_id: &str| DirectCall { call_id: 1, peer_id: peer_id.to_string(), state: CallState::Active };
+        *alice.direct_call.lock().await = Some(call("bob"));
+        *bob.direct_call.lock().await = Some(call("alice"));
+        // Carol forwards or overhears, and claims the same call
+        *carol.direct_call.lock().await = Some(call("alice"));
+
+        alice.share_call_key(&call("bob")).await.unwrap();
+        let message = alice.group_keys.lock().await.own_message("alice", "call:1", "bob").unwrap();
+        bob.on_key_message("alice", message.clone()).await;
+        carol.on_key_message("alice", message).await;
+
+        let sealed = alice.seal_call_audio(&call("bob"), OPUS_FRAME.to_vec()).await.unwrap();
+        assert!(!sealed.windows(OPUS_FRAME.len()).any(|window| window == OPUS_FRAME));
+        assert_eq!(bob.open_call_audio("alice", &sealed).await, Some(OPUS_FRAME.to_vec()));
+        assert_eq!(carol.open_call_audio("alice", &sealed).await, None);
+        // Raw opus from before framing is refused
+        assert_eq!(bob.open_call_audio("alice", &OPUS_FRAME).await, None);
+
+        // The key goes with the call
+        bob.set_call_state(CallState::Ended).await;
+        *bob.direct_call.lock().await = Some(call("alice"));
+        assert_eq!(bob.open_call_audio("alice", &sealed).await, None);
+    }
+
+    #[tokio::test]
+    async fn simultaneous_rings_leave_one_call() {
+        let (module, _db) = test_module("alice", &["ops"]).await;
+        let mut events = module.subscribe();
+        tokio::join!(
+            module.on_call_message("bob", CallMessage::Ring { call_id: 1 }),
+            module.on_call_message("carol", CallMessage::Ring { call_id: 1 }),
+        );
+        let ringing: Vec<String> = drain(&mut events).into_iter()
+            .filter_map(|event| match event {
+                SessionEvent::CallStateChanged { peer_id, state: CallState::Ringing } => Some(peer_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ringing.len(), 1);
+        assert_eq!(module.current_call().await.unwrap().peer_id, ringing[0]);
+    }
+
+    #[tokio::test]
+    async fn scanned_emergencies_from_other_groups_have_no_group() {
+        let (module, _db) = test_module("bob", &["ops", "fire"]).await;
+        let mut received = module.start_scan(ScanConfig::new(vec!["ops".to_string()])).await;
+        let frame = |group: &str| AudioFrame {
+            version: 1,
+            flags: 0,
+            sequence: 0,
+            timestamp: 0,
+            sender: "alice".to_string(),
+            group: group.to_string(),
+            payload: OPUS_FRAME.to_vec(),
+        };
+
+        assert!(!module.scan_audio(&frame("fire"), false).await);
+        assert!(module.scan_audio(&frame("fire"), true).await);
+        let audio = received.try_next().unwrap().unwrap();
+        assert_eq!(audio.group, None);
+        assert!(audio.emergency);
+
+        assert!(module.scan_audio(&frame("ops"), false).await);
+        let audio = received.try_next().unwrap().unwrap();
+        assert_eq!(audio.group.as_deref(), Some("ops"));
+        assert!(!audio.emergency);
+        assert!(received.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn audio_is_relayed_while_a_connection_recovers() {
+        let (mut module, _db) = test_module("bob", &["ops"]).await;
+        // A signaling connection for the relay to go through
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while server.next().await.is_some() {}
+        });
+        let (ws_sink, _ws_stream) = tls::connect(&url, None).await.unwrap().split();
+        module.ws_sink = Some(Arc::new(Mutex::new(ws_sink)));
+
+        let peer_connection = Arc::new(add_control_channel(&module, "alice").await);
+        module.peer_connections.lock().await.insert("alice".to_string(), peer_connection.clone());
+        module.peer_groups.lock().await.insert("alice".to_string(), vec!["ops".to_string()]);
+        module.on_connection_state_change("alice".to_string(), peer_connection.clone(),
+            RTCPeerConnectionState::Disconnected).await;
+        assert!(module.relayed_peers.lock().await.contains("alice"));
+        assert_eq!(module.relay_targets(Some("ops")).await, vec!["alice"]);
+
+        module.on_connection_state_change("alice".to_string(), peer_connection,
+            RTCPeerConnectionState::Connected).await;
+        assert!(!module.relayed_peers.lock().await.contains("alice"));
+    }
+
+    #[tokio::test]
+    async fn repeated_display_names_are_announced_once() {
+        let (module, _db) = test_module("bob", &["ops"]).await;
+        let mut events = module.subscribe();
+        module.on_display_name("alice", "Alice").await;
+        module.on_display_name("alice", "Alice").await;
+        module.on_display_name("alice", "Ali").await;
+
+        let renames: Vec<String> = drain(&mut events).into_iter()
+            .filter_map(|event| match event {
+                SessionEvent::DisplayNameChanged { display_name, .. } => Some(display_name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(renames, vec!["Alice", "Ali"]);
+    }
+
+    #[tokio::test]
+    async fn commands_need_an_admin_sender_and_the_right_target() {
+        let (module, _db) = test_module("bob", &["ops"]).await;
+        let mut metadata = HashMap::new();
+        metadata.insert("groups".to_string(), serde_json::json!({
+            "ops": { "members": { "alice": { "admin": true }, "mallory": {} } }
+        }));
+        module.set_room_metadata("room", metadata).await;
+        let request = |command| ControlRequest { id: 1, command };
+        let mute = |target: &str| ControlCommand::Mute { target: target.to_string() };
+        let kick = |target: &str| ControlCommand::Kick { target: target.to_string() };
+
+        assert!(module.authorize_command("alice", &request(kick("bob"))).await.ok);
+        assert!(module.authorize_command("alice", &request(kick(control::TARGET_ALL))).await.ok);
+        let ack = module.authorize_command("mallory", &request(kick("bob"))).await;
+        assert_eq!(ack.reason.as_deref(), Some("sender is not an admin"));
+        let ack = module.authorize_command("alice", &request(kick("carol"))).await;
+        assert_eq!(ack.reason.as_deref(), Some("not the target"));
+        // Mutes are recorded by everyone, whoever they're for
+        assert!(module.authorize_command("alice", &request(mute("carol"))).await.ok);
+        let ping = ControlCommand::Ping { target: "bob".to_string() };
+        assert!(module.authorize_command("mallory", &request(ping)).await.ok);
+
+        *module.require_trusted_admins.lock().await = true;
+        let ack = module.authorize_command("alice", &request(kick("bob"))).await;
+        assert_eq!(ack.reason.as_deref(), Some("sender's device is not trusted"));
+    }
+
+    #[tokio::test]
+    async fn emergencies_preempt_talkers_and_need_the_role() {
+        let (module, _db) = test_module("bob", &["ops"]).await;
+        let mut metadata = HashMap::new();
+        metadata.insert("groups".to_string(), serde_json::json!({
+            "ops": { "members": { "alice": { "emergency": true }, "bob": { "emergency": true }, "mallory": {} } }
+        }));
+        module.set_room_metadata("room", metadata).await;
+        module.floor_holders.lock().await.insert("ops".to_string(), "carol".to_string());
+        let mut events = module.subscribe();
+        let emergency = |holder: &str| FloorMessage::Emergency { holder: holder.to_string() };
+
+        // Without the role, or on someone else's behalf
+        module.on_emergency_message("mallory", emergency("mallory")).await;
+        module.on_emergency_message("mallory", emergency("alice")).await;
+        assert_eq!(module.emergency_holder().await, None);
+        assert!(drain(&mut events).is_empty());
+
+        module.on_emergency_message("alice", emergency("alice")).await;
+        assert_eq!(module.emergency_holder().await.as_deref(), Some("alice"));
+        assert!(module.floor_holders.lock().await.is_empty());
+        let received = drain(&mut events);
+        assert!(received.iter().any(|event| matches!(event,
+            SessionEvent::TalkEnded { peer_id, group } if peer_id == "carol" && group == "ops")));
+        assert!(received.iter().any(|event| matches!(event,
+            SessionEvent::EmergencyStarted { peer_id } if peer_id == "alice")));
+        // Nobody else can call one until it's over
+        let error = module.start_emergency().await.unwrap_err();
+        assert!(error.to_string().contains("in progress"));
+
+        module.on_emergency_message("mallory", FloorMessage::EmergencyEnded { holder: "alice".to_string() }).await;
+        assert_eq!(module.emergency_holder().await.as_deref(), Some("alice"));
+        module.on_emergency_message("alice", FloorMessage::EmergencyEnded { holder: "alice".to_string() }).await;
+        assert_eq!(module.emergency_holder().await, None);
+    }
+
+    #[tokio::test]
+    async fn forwarders_cannot_open_emergency_calls() {
+        let (alice, _alice_db) = test_module("alice", &["ops"]).await;
+        let (bob, _bob_db) = test_module("bob", &["fire"]).await;
+        let (hank, _hank_db) = test_module("hank", &[]).await;
+        let mut metadata = HashMap::new();
+        metadata.insert("host".to_string(), serde_json::json!("hank"));
+        metadata.insert(crate::topology::MODE_METADATA_KEY.to_string(), serde_json::json!("sfu"));
+        for module in [&alice, &bob, &hank] {
+            module.set_room_metadata("room", metadata.clone()).await;
+            *module.emergency_holder.lock().await = Some("alice".to_string());
+        }
+        introduce((&alice, "alice"), (&bob, "bob")).await;
+        introduce((&alice, "alice"), (&hank, "hank")).await;
+        alice.peer_groups.lock().await.extend([
+            ("bob".to_string(), vec!["fire".to_string()]),
+            ("hank".to_string(), Vec::new()),
+        ]);
+
+        // Only members of some group get the key, through the host
+        assert_eq!(alice.room_members().await, vec!["bob"]);
+        let message = alice.group_keys.lock().await.own_message("alice", EMERGENCY_KEY_GROUP, "bob").unwrap();
+        hank.on_key_message("alice", message.clone()).await;
+        bob.on_key_message("hank", message).await;
+        assert!(alice.group_keys.lock().await.own_message("alice", EMERGENCY_KEY_GROUP, "hank").is_ok());
+
+        let mut frame = AudioFrame::new("alice", "ops", 1, 960, OPUS_FRAME.to_vec()).with_flags(FLAG_EMERGENCY);
+        alice.group_keys.lock().await.seal_as(&mut frame, EMERGENCY_KEY_GROUP).unwrap();
+        let sealed = frame.encode().unwrap();
+        assert!(!sealed.windows(OPUS_FRAME.len()).any(|window| window == OPUS_FRAME));
+
+        let mut hank_audio = hank.receive_audio("ops").await;
+        hank.on_audio_message("alice", None, sealed.clone()).await;
+        assert!(hank_audio.try_next().is_err());
+
+        let mut bob_audio = bob.receive_audio("fire").await;
+        bob.on_audio_message("hank", None, sealed).await;
+        assert_eq!(bob_audio.try_next().unwrap().unwrap().payload, OPUS_FRAME.to_vec());
+        // Cleartext emergencies from before are dropped
+        let cleartext = AudioFrame::new("alice", "ops", 2, 1920, OPUS_FRAME.to_vec()).with_flags(FLAG_EMERGENCY);
+        bob.on_audio_message("hank", None, cleartext.encode().unwrap()).await;
+        assert!(bob_audio.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn multicast_keys_only_come_from_the_host() {
+        let (module, _db) = test_module("bob", &["ann"]).await;
+        let mut metadata = HashMap::new();
+        metadata.insert("host".to_string(), serde_json::json!("alice"));
+        module.set_room_metadata("room", metadata).await;
+        let message = |key: &MulticastKey| MulticastKeyMessage {
+            multicast_group: "ann".to_string(),
+            key: multicast::encode_key(key),
+        };
+
+        module.on_multicast_key("mallory", message(&multicast::generate_key())).await;
+        assert!(module.multicast_keys.lock().await.is_empty());
+        let key = multicast::generate_key();
+        module.on_multicast_key("alice", message(&key)).await;
+        assert_eq!(module.multicast_keys.lock().await.get("ann"), Some(&key));
+    }
+
+    #[tokio::test]
+    async fn host_replaces_multicast_keys_when_members_leave_or_are_kicked() {
+        let (module, _db) = test_module("alice", &["ann"]).await;
+        let mut metadata = HashMap::new();
+        metadata.insert("host".to_string(), serde_json::json!("alice"));
+        metadata.insert("groups".to_string(), serde_json::json!({
+            "ann": { "transport": "multicast", "members": { "alice": { "admin": true } } },
+            "ops": {}
+        }));
+        module.set_room_metadata("room", metadata).await;
+        module.peer_groups.lock().await.extend(["bob", "carol", "dave"].map(|peer| {
+            (peer.to_string(), vec!["ann".to_string(), "ops".to_string()])
+        }));
+        let first = module.multicast_key("ann").await.unwrap();
+        let current = || async { *module.multicast_keys.lock().await.get("ann").unwrap() };
+
+        // Changing other groups leaves the key alone
+        module.update_user_groups("bob", vec!["ann".to_string()]).await.unwrap();
+        assert_eq!(current().await, first);
+        module.update_user_groups("bob", Vec::new()).await.unwrap();
+        let second = current().await;
+        assert_ne!(second, first);
+
+        module.drop_peer("carol").await;
+        let third = current().await;
+        assert_ne!(third, second);
+
+        module.send_command(ControlCommand::Kick { target: "dave".to_string() }).await.unwrap();
+        assert_ne!(current().await, third);
+    }
+
+    #[tokio::test]
+    async fn only_the_arbiter_answers_floor_requests() {
+        let (module, _db) = test_module("bob", &["ops"]).await;
+        let mut metadata = HashMap::new();
+        metadata.insert("host".to_string(), serde_json::json!("alice"));
+        module.set_room_metadata("room", metadata).await;
+        let _alice = add_control_channel(&module, "alice").await;
+        let _carol = add_control_channel(&module, "carol").await;
+        let mut events = module.subscribe();
+
+        let request = FloorMessage::Request { group: "ops".to_string(), queue: false };
+        module.on_floor_message("carol", request).await;
+        assert_eq!(module.floor_arbiter.lock().await.holder("ops"), None);
+        assert!(module.floor_holders.lock().await.is_empty());
+        assert!(drain(&mut events).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_floors_time_out_on_the_arbiter() {
+        let (module, _db) = test_module("alice", &["ops"]).await;
+        tokio::spawn(floor_timer(module.clone()));
+        assert!(module.request_floor("ops", false).await.unwrap());
+        assert_eq!(module.floor_holders.lock().await.get("ops").map(String::as_str), Some("alice"));
+
+        tokio::time::sleep(floor::FLOOR_HOLD_TIMEOUT - Duration::from_secs(1)).await;
+        assert!(module.floor_holders.lock().await.contains_key("ops"));
+        tokio::time::sleep(Duration::from_secs(1) + FLOOR_CHECK_INTERVAL).await;
+        assert!(module.floor_holders.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn floor_updates_only_come_from_the_arbiter() {
+        let (module, _db) = test_module("bob", &["ops"]).await;
+        let mut metadata = HashMap::new();
+        metadata.insert("host".to_string(), serde_json::json!("alice"));
+        module.set_room_metadata("room", metadata).await;
+        let _alice = add_control_channel(&module, "alice").await;
+        let _mallory = add_control_channel(&module, "mallory").await;
+        let granted = |holder: &str| FloorMessage::Granted { group: "ops".to_string(), holder: holder.to_string() };
+        let released = |holder: &str| FloorMessage::Released { group: "ops".to_string(), holder: holder.to_string() };
+
+        module.on_floor_message("mallory", granted("mallory")).await;
+        assert_eq!(module.floor_holder("ops").await, None);
+
+        module.on_floor_message("alice", granted("carol")).await;
+        module.on_floor_message("mallory", released("carol")).await;
+        assert_eq!(module.floor_holder("ops").await.as_deref(), Some("carol"));
+
+        module.on_floor_message("alice", released("carol")).await;
+        assert_eq!(module.floor_holder("ops").await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transmit_timeout_warns_cuts_off_and_locks_out() {
+        let (module, _db) = test_module("alice", &["ops"]).await;
+        module.set_transmit_limits("ops", TransmitLimits {
+            max_duration: Duration::from_secs(10),
+            warning_before: Duration::from_secs(3),
+            lockout: Duration::from_secs(5),
+        }).await;
+        let mut events = module.subscribe();
+        assert!(module.start_talk("ops").await.unwrap());
+
+        // Quiet until 3 s before the limit
+        talk(&module, "ops", Duration::from_millis(6500)).await.unwrap();
+        assert!(!drain(&mut events).iter().any(|e| matches!(e, SessionEvent::TransmitWarning { .. })));
+        assert_eq!(module.playback_mixer().lock().unwrap().mix(10), vec![0.0; 10]);
+
+        // The warning fires once, with the tone queued for playback
+        talk(&module, "ops", Duration::from_secs(1)).await.unwrap();
+        let warnings: Vec<Duration> = drain(&mut events).into_iter()
+            .filter_map(|e| match e {
+                SessionEvent::TransmitWarning { remaining, .. } => Some(remaining),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(warnings, vec![Duration::from_secs(3)]);
+        let tone = module.playback_mixer().lock().unwrap().mix(audio::generate_warning_tone().len());
+        assert!(tone.iter().any(|sample| *sample != 0.0));
+
+        // Cut off at the limit, and the floor goes with it
+        let error = talk(&module, "ops", Duration::from_secs(5)).await.unwrap_err();
+        assert!(error.contains("Transmit timeout"), "{}", error);
+        assert!(drain(&mut events).iter().any(|e| matches!(e,
+            SessionEvent::TransmitCutOff { lockout, .. } if *lockout == Duration::from_secs(5))));
+        assert_eq!(module.floor_holder("ops").await, None);
+
+        // Locked out, even from asking for the floor, until the lockout ends
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert!(module.start_talk("ops").await.unwrap_err().to_string().contains("locked out"));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(module.start_talk("ops").await.unwrap());
+        talk(&module, "ops", Duration::from_secs(1)).await.unwrap();
+    }
 }
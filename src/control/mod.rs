@@ -0,0 +1,144 @@
+// ============================================
+//                  Imports
+// ============================================
+use serde::{Deserialize, Serialize};
+use crate::call::CallMessage;
+use crate::e2e::KeyMessage;
+use crate::floor::FloorMessage;
+use crate::multicast::MulticastKeyMessage;
+
+// Target that addresses every peer in the room
+pub const TARGET_ALL: &str = "all";
+
+// ============================================
+//                 Structures
+// ============================================
+
+// Admin commands sent over the control data channel
+// Example: {"id": 7, "action": "mute", "target": "all"}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ControlCommand {
+    Mute { target: String },
+    Unmute { target: String },
+    Kick { target: String },
+    ForceLeaveGroup { target: String, group: String },
+    SetVolume { target: String, volume: f32 },
+    Ping { target: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlRequest {
+    pub id: u64,
+    #[serde(flatten)]
+    pub command: ControlCommand,
+}
+
+// Every request is answered with an ack carrying the request id
+// Example: {"ack": 7, "ok": false, "reason": "sender is not an admin"}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlAck {
+    pub ack: u64,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ControlMessage {
+    Ack(ControlAck),
+    Floor(FloorMessage),
+    Call(CallMessage),
+    Key(KeyMessage),
+    MulticastKey(MulticastKeyMessage),
+    Request(ControlRequest),
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl ControlCommand {
+    pub fn target(&self) -> &str {
+        match self {
+            ControlCommand::Mute { target }
+            | ControlCommand::Unmute { target }
+            | ControlCommand::Kick { target }
+            | ControlCommand::ForceLeaveGroup { target, .. }
+            | ControlCommand::SetVolume { target, .. }
+            | ControlCommand::Ping { target } => target,
+        }
+    }
+    // Whether the command addresses the given peer
+    pub fn targets(&self, peer_id: &str) -> bool {
+        let target = self.target();
+        target == TARGET_ALL || target == peer_id
+    }
+    // Mute state is room wide, every peer records it even when not targeted
+    pub fn is_shared_state(&self) -> bool {
+        matches!(self, ControlCommand::Mute { .. } | ControlCommand::Unmute { .. })
+    }
+    // Anyone may ping, everything else is reserved to admins
+    pub fn requires_admin(&self) -> bool {
+        !matches!(self, ControlCommand::Ping { .. })
+    }
+}
+
+impl ControlAck {
+    pub fn accepted(id: u64) -> Self {
+        Self { ack: id, ok: true, reason: None }
+    }
+    pub fn rejected(id: u64, reason: &str) -> Self {
+        Self { ack: id, ok: false, reason: Some(reason.to_string()) }
+    }
+}
+
+// ============================================
+//            Encoding / Decoding
+// ============================================
+pub fn encode_message(message: &ControlMessage) -> String {
+    serde_json::to_string(message).unwrap_or_default()
+}
+
+pub fn decode_message(text: &str) -> Option<ControlMessage> {
+    serde_json::from_str(text).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_address_their_target_or_everyone() {
+        let mute = ControlCommand::Mute { target: "bob".to_string() };
+        assert!(mute.targets("bob"));
+        assert!(!mute.targets("carol"));
+        let kick = ControlCommand::Kick { target: TARGET_ALL.to_string() };
+        assert!(kick.targets("bob") && kick.targets("carol"));
+    }
+
+    #[test]
+    fn only_pings_are_open_to_everyone() {
+        assert!(!ControlCommand::Ping { target: "bob".to_string() }.requires_admin());
+        assert!(ControlCommand::Mute { target: "bob".to_string() }.requires_admin());
+        assert!(ControlCommand::SetVolume { target: "bob".to_string(), volume: 0.5 }.requires_admin());
+    }
+
+    #[test]
+    fn requests_and_acks_decode_to_their_own_variants() {
+        let request = ControlMessage::Request(ControlRequest {
+            id: 7,
+            command: ControlCommand::ForceLeaveGroup { target: "bob".to_string(), group: "ops".to_string() },
+        });
+        assert_eq!(decode_message(&encode_message(&request)), Some(request));
+        let ack = ControlMessage::Ack(ControlAck::rejected(7, "sender is not an admin"));
+        assert_eq!(decode_message(&encode_message(&ack)), Some(ack));
+        let key = ControlMessage::MulticastKey(crate::multicast::MulticastKeyMessage {
+            multicast_group: "ann".to_string(),
+            key: "00".repeat(32),
+        });
+        assert_eq!(decode_message(&encode_message(&key)), Some(key));
+        assert_eq!(decode_message(r#"{"id": 7, "action": "self_destruct", "target": "all"}"#), None);
+    }
+}
@@ -39,6 +39,72 @@ pub fn initialize_database(pool: &SqlitePool) {
         )",
         [],
     ).expect("Failed to create rooms table.");
+
+    // Every emergency call, kept for safety audits
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS emergency_log (
+            id INTEGER PRIMARY KEY,
+            room TEXT NOT NULL,
+            peer_id TEXT NOT NULL,
+            event TEXT NOT NULL,
+            timestamp TEXT NOT NULL
+        )",
+        [],
+    ).expect("Failed to create emergency_log table.");
+
+    // Certificate (and key) this device presents in every DTLS handshake
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS device_identity (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            certificate TEXT NOT NULL
+        )",
+        [],
+    ).expect("Failed to create device_identity table.");
+
+    // Self-signed certificate the signaling server presents over wss://
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS signaling_identity (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            certificate BLOB NOT NULL,
+            private_key BLOB NOT NULL
+        )",
+        [],
+    ).expect("Failed to create signaling_identity table.");
+
+    // Id peers know this device by, and the name shown to them
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS local_device (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            device_id TEXT NOT NULL,
+            display_name TEXT
+        )",
+        [],
+    ).expect("Failed to create local_device table.");
+
+    // Fingerprint first seen for each peer, later ones must match it.
+    // Trusted once the users compared their pairing codes.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS known_devices (
+            peer_id TEXT PRIMARY KEY,
+            fingerprint TEXT NOT NULL,
+            first_seen TEXT NOT NULL,
+            trusted INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    ).expect("Failed to create known_devices table.");
+
+    // Peers registered with the signaling server this device runs, a row
+    // lives as long as the peer's session
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS connected_peers (
+            peer_id TEXT PRIMARY KEY,
+            groups TEXT NOT NULL,
+            fingerprint TEXT NOT NULL,
+            display_name TEXT NOT NULL,
+            connected_at TEXT NOT NULL
+        )",
+        [],
+    ).expect("Failed to create connected_peers table.");
 }
 // ============================================
 //          Store Room Information
@@ -108,8 +174,7 @@ pub fn get_room_metadata(
         let conn = pool.get().expect("Failed to get connection from pool");
 
         // Extract the room name from the ws_url
-        let url_parts: Vec<&str> = ws_url.split("/").collect();
-        let room_name = url_parts.last().unwrap_or(&"");
+        let room_name = room_name_from_url(ws_url);
 
         let mut stmt = conn.prepare("SELECT metadata FROM rooms WHERE name = ?1")?;
         let metadata: Result<String, rusqlite::Error> = stmt.query_row(
@@ -125,3 +190,215 @@ pub fn get_room_metadata(
             Err(e) => Err(e),
         }
 }
+// ============================================
+//          Update Room Metadata
+// ============================================
+pub fn update_room_metadata(
+    pool: &SqlitePool,
+    room_name: &str,
+    metadata: &HashMap<String, serde_json::Value>
+) -> Result<()> {
+    let room_metadata = serde_json::to_string(metadata).unwrap();
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute(
+        "UPDATE rooms SET metadata = ?1 WHERE name = ?2",
+        params![room_metadata, room_name],
+    )?;
+    Ok(())
+}
+// Rooms are looked up by the last segment of their WebSocket URL
+pub fn room_name_from_url(ws_url: &str) -> String {
+    ws_url.split('/').next_back().unwrap_or("").to_string()
+}
+// ============================================
+//            Log Emergency
+// ============================================
+pub fn log_emergency(pool: &SqlitePool, room_name: &str, peer_id: &str, event: &str) -> Result<()> {
+    let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute(
+        "INSERT INTO emergency_log (room, peer_id, event, timestamp)
+        VALUES (?1, ?2, ?3, ?4)",
+        params![room_name, peer_id, event, timestamp],
+    )?;
+    Ok(())
+}
+// ============================================
+//            Device Identity
+// ============================================
+pub fn load_device_certificate(pool: &SqlitePool) -> Result<Option<String>> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    let certificate = conn.query_row(
+        "SELECT certificate FROM device_identity WHERE id = 1", [], |row| row.get(0));
+    match certificate {
+        Ok(certificate) => Ok(Some(certificate)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+pub fn store_device_certificate(pool: &SqlitePool, certificate: &str) -> Result<()> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute(
+        "INSERT OR REPLACE INTO device_identity (id, certificate) VALUES (1, ?1)",
+        params![certificate],
+    )?;
+    Ok(())
+}
+// ============================================
+//            Local Device
+// ============================================
+// (device id, display name)
+pub fn load_local_device(pool: &SqlitePool) -> Result<Option<(String, Option<String>)>> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    let device = conn.query_row(
+        "SELECT device_id, display_name FROM local_device WHERE id = 1", [],
+        |row| Ok((row.get(0)?, row.get(1)?)));
+    match device {
+        Ok(device) => Ok(Some(device)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+pub fn store_device_id(pool: &SqlitePool, device_id: &str) -> Result<()> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute(
+        "INSERT OR IGNORE INTO local_device (id, device_id) VALUES (1, ?1)",
+        params![device_id],
+    )?;
+    Ok(())
+}
+pub fn store_display_name(pool: &SqlitePool, display_name: &str) -> Result<()> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute(
+        "UPDATE local_device SET display_name = ?1 WHERE id = 1",
+        params![display_name],
+    )?;
+    Ok(())
+}
+// ============================================
+//            Signaling Identity
+// ============================================
+// (certificate DER, PKCS#8 private key DER)
+pub fn load_signaling_certificate(pool: &SqlitePool) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    let identity = conn.query_row(
+        "SELECT certificate, private_key FROM signaling_identity WHERE id = 1", [],
+        |row| Ok((row.get(0)?, row.get(1)?)));
+    match identity {
+        Ok(identity) => Ok(Some(identity)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+pub fn store_signaling_certificate(pool: &SqlitePool, certificate: &[u8], private_key: &[u8]) -> Result<()> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute(
+        "INSERT OR REPLACE INTO signaling_identity (id, certificate, private_key) VALUES (1, ?1, ?2)",
+        params![certificate, private_key],
+    )?;
+    Ok(())
+}
+// ============================================
+//            Known Devices
+// ============================================
+pub fn get_pinned_fingerprint(pool: &SqlitePool, peer_id: &str) -> Result<Option<String>> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    let fingerprint = conn.query_row(
+        "SELECT fingerprint FROM known_devices WHERE peer_id = ?1",
+        params![peer_id], |row| row.get(0));
+    match fingerprint {
+        Ok(fingerprint) => Ok(Some(fingerprint)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+pub fn pin_device(pool: &SqlitePool, peer_id: &str, fingerprint: &str) -> Result<()> {
+    let first_seen = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute(
+        "INSERT OR IGNORE INTO known_devices (peer_id, fingerprint, first_seen)
+        VALUES (?1, ?2, ?3)",
+        params![peer_id, fingerprint, first_seen],
+    )?;
+    Ok(())
+}
+pub fn is_device_trusted(pool: &SqlitePool, peer_id: &str) -> Result<bool> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    let trusted = conn.query_row(
+        "SELECT trusted FROM known_devices WHERE peer_id = ?1",
+        params![peer_id], |row| row.get(0));
+    match trusted {
+        Ok(trusted) => Ok(trusted),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+pub fn set_device_trusted(pool: &SqlitePool, peer_id: &str, trusted: bool) -> Result<()> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute(
+        "UPDATE known_devices SET trusted = ?1 WHERE peer_id = ?2",
+        params![trusted, peer_id],
+    )?;
+    Ok(())
+}
+// ============================================
+//            Connected Peers
+// ============================================
+pub fn store_connected_peer(pool: &SqlitePool, peer_id: &str, groups: &str,
+    fingerprint: &str, display_name: &str) -> Result<()> {
+    let connected_at = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute(
+        "INSERT OR REPLACE INTO connected_peers (peer_id, groups, fingerprint, display_name, connected_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![peer_id, groups, fingerprint, display_name, connected_at],
+    )?;
+    Ok(())
+}
+pub fn remove_connected_peer(pool: &SqlitePool, peer_id: &str) -> Result<()> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute("DELETE FROM connected_peers WHERE peer_id = ?1", params![peer_id])?;
+    Ok(())
+}
+// Left over from a previous run of the server
+pub fn clear_connected_peers(pool: &SqlitePool) -> Result<()> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    conn.execute("DELETE FROM connected_peers", [])?;
+    Ok(())
+}
+pub fn load_connected_peers(pool: &SqlitePool) -> Result<Vec<String>> {
+    let conn = pool.get().expect("Failed to get connection from pool");
+    let mut statement = conn.prepare("SELECT peer_id FROM connected_peers ORDER BY peer_id")?;
+    let peers = statement.query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    Ok(peers)
+}
+
+// ============================================
+//              Test Fixtures
+// ============================================
+
+// A fresh initialized db, deleted with its directory when dropped
+#[cfg(test)]
+pub struct TestDb {
+    pool: SqlitePool,
+    _dir: tempfile::TempDir,
+}
+
+#[cfg(test)]
+impl TestDb {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().expect("Failed to create a test db directory");
+        let pool = initialize_pool(dir.path().join("test.db").to_str().unwrap());
+        initialize_database(&pool);
+        TestDb { pool, _dir: dir }
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDb {
+    type Target = SqlitePool;
+    fn deref(&self) -> &SqlitePool {
+        &self.pool
+    }
+}
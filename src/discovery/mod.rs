@@ -168,6 +168,36 @@ pub fn select_network_interface() -> Option<IpAddr> {
 }
 
 
+// ============================================
+//            Update Service Metadata
+// Republishes a room's metadata, e.g. once the
+// host has started a TURN relay.
+// ============================================
+pub fn update_service_metadata(
+    service_daemon: mdns_sd::ServiceDaemon,
+    service_info: &ServiceInfo,
+    metadata: &HashMap<String, serde_json::Value>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut txt_properties = HashMap::new();
+    txt_properties.insert("metadata".to_string(), serde_json::to_string(metadata)?);
+
+    let service_name = service_info.get_fullname()
+        .trim_end_matches(service_info.get_type())
+        .trim_end_matches('.');
+    let ip_addresses: Vec<IpAddr> = service_info.get_addresses().iter().cloned().collect();
+    for service_type in ["_webrtc._udp.local.", "_ws._tcp.local."] {
+        let info = ServiceInfo::new(
+            service_type,
+            service_name,
+            service_info.get_hostname(),
+            ip_addresses.as_slice(),
+            service_info.get_port(),
+            txt_properties.clone(),
+        )?;
+        service_daemon.register(info)?;
+    }
+    Ok(())
+}
 // ============================================
 //            Load and Broadcast Services
 // ============================================
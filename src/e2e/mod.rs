@@ -0,0 +1,464 @@
+// ============================================
+//        Group End-to-End Encryption
+// Every member seals its audio with its own key
+// for the group. That key is wrapped separately
+// for each member with a secret agreed between
+// the two device certificates, so a forwarding
+// host or the signaling server only ever passes
+// on blobs it can't open. Keys are replaced
+// whenever the group's membership changes.
+// ============================================
+
+// ============================================
+//                  Imports
+// ============================================
+use crate::multicast::{self, MulticastKey};
+use crate::packet::{AudioFrame, FLAG_ENCRYPTED};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use p256::pkcs8::DecodePrivateKey;
+use p256::{ecdh, PublicKey, SecretKey};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+const EPOCH_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+// Keys kept per sender so frames in flight during a rotation still open
+const KEPT_EPOCHS: usize = 2;
+const WRAP_INFO: &[u8] = b"walkie-talkie-e2e-wrap";
+// Key group of the room-wide emergency keys. Group names can't hold a
+// colon, so it never clashes with a real group.
+pub const EMERGENCY_KEY_GROUP: &str = "room:emergency";
+
+// ============================================
+//                 Structures
+// ============================================
+
+// A sender's group key wrapped for one recipient, carried over the control
+// data channel or the signaling server. Only the recipient can unwrap it.
+// Example: {"e2e": "key", "group": "all", "sender": "bob", "recipient": "alice",
+//           "epoch": 3, "wrapped": "9f86d0..."}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "e2e", rename_all = "snake_case")]
+pub enum KeyMessage {
+    Key { group: String, sender: String, recipient: String, epoch: u32, wrapped: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum E2eError {
+    NoKey,
+    UnknownEpoch(u32),
+    NotEncrypted,
+    Malformed,
+    Crypto,
+    // We have no certificate for the peer to agree a wrapping secret with
+    UnknownPeer(String),
+    // The key was wrapped for someone else
+    NotForUs,
+    BadCertificate,
+}
+
+// This device's certificate and the private key behind it
+#[derive(Clone)]
+pub struct DeviceKey {
+    certificate: Vec<u8>,
+    secret: SecretKey,
+}
+
+#[derive(Clone)]
+struct SenderKey {
+    epoch: u32,
+    key: MulticastKey,
+    cipher: Aes256Gcm,
+}
+
+// Our own key in each group and the ones other members sent us
+//
+// Sealed payload:
+// +----------+-----------+------------------------------+
+// | epoch 4B | nonce 12B | AES-256-GCM(opus payload)    |
+// +----------+-----------+------------------------------+
+//
+// The frame header is authenticated along with the payload.
+//
+// Wrapped key:
+// +-----------+------------------------------------------+
+// | nonce 12B | AES-256-GCM(group key), AAD = addressing |
+// +-----------+------------------------------------------+
+//
+// The wrapping key is HKDF-SHA256 over the ECDH secret of the sender's and
+// the recipient's certificate keys, so a key that opens also proves who
+// sent it.
+pub struct GroupKeys {
+    device: DeviceKey,
+    own: HashMap<String, SenderKey>,
+    // <(Sender, Group), Keys oldest first>
+    peers: HashMap<(String, String), Vec<SenderKey>>,
+    // Certificate keys of the peers we checked: <PeerId, Key>
+    public_keys: HashMap<String, PublicKey>,
+    // Wrapped keys the forwarding host passes on, latest per sender and
+    // group: <Recipient, <(Sender, Group), Message>>
+    forwarded: HashMap<String, HashMap<(String, String), KeyMessage>>,
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl SenderKey {
+    fn new(epoch: u32, key: MulticastKey) -> Self {
+        Self { epoch, key, cipher: Aes256Gcm::new(&key.into()) }
+    }
+}
+
+impl DeviceKey {
+    // DER certificate and PKCS#8 private key, as the DTLS certificate keeps them
+    pub fn from_der(certificate: Vec<u8>, private_key: &[u8]) -> Result<Self, E2eError> {
+        let secret = SecretKey::from_pkcs8_der(private_key).map_err(|_| E2eError::BadCertificate)?;
+        if certificate_public_key(&certificate)? != secret.public_key() {
+            return Err(E2eError::BadCertificate);
+        }
+        Ok(Self { certificate, secret })
+    }
+    pub fn certificate(&self) -> &[u8] {
+        &self.certificate
+    }
+}
+
+// The P-256 key a device certificate is issued for
+pub fn certificate_public_key(certificate: &[u8]) -> Result<PublicKey, E2eError> {
+    let (_, certificate) = X509Certificate::from_der(certificate).map_err(|_| E2eError::BadCertificate)?;
+    PublicKey::from_sec1_bytes(&certificate.public_key().subject_public_key.data)
+        .map_err(|_| E2eError::BadCertificate)
+}
+
+impl GroupKeys {
+    pub fn new(device: DeviceKey) -> Self {
+        Self {
+            device,
+            own: HashMap::new(),
+            peers: HashMap::new(),
+            public_keys: HashMap::new(),
+            forwarded: HashMap::new(),
+        }
+    }
+    pub fn device_certificate(&self) -> &[u8] {
+        self.device.certificate()
+    }
+    // A peer's certificate, checked against its pin by the caller
+    pub fn add_peer_certificate(&mut self, peer: &str, certificate: &[u8]) -> Result<(), E2eError> {
+        let public_key = certificate_public_key(certificate)?;
+        self.public_keys.insert(peer.to_string(), public_key);
+        Ok(())
+    }
+    pub fn knows_peer(&self, peer: &str) -> bool {
+        self.public_keys.contains_key(peer)
+    }
+
+    // Replaces our key for a group, anyone who isn't sent the new one
+    // can't follow from here on
+    pub fn rotate(&mut self, group: &str) {
+        let epoch = self.own.get(group).map_or(0, |key| key.epoch.wrapping_add(1));
+        self.own.insert(group.to_string(), SenderKey::new(epoch, multicast::generate_key()));
+    }
+    pub fn has_own_key(&self, group: &str) -> bool {
+        self.own.contains_key(group)
+    }
+    // Drops our keys for groups we left
+    pub fn retain_groups(&mut self, groups: &[String]) {
+        self.own.retain(|group, _| groups.contains(group));
+    }
+    // Drops every key, ours and the peers', for a group that is over
+    pub fn forget(&mut self, group: &str) {
+        self.own.remove(group);
+        self.peers.retain(|(_, g), _| g != group);
+    }
+    pub fn remove_peer(&mut self, peer: &str) {
+        self.peers.retain(|(sender, _), _| sender != peer);
+        self.public_keys.remove(peer);
+        self.forwarded.remove(peer);
+        for messages in self.forwarded.values_mut() {
+            messages.retain(|(sender, _), _| sender != peer);
+        }
+    }
+
+    // Our current key for the group, wrapped for one recipient
+    pub fn own_message(&self, sender: &str, group: &str, recipient: &str) -> Result<KeyMessage, E2eError> {
+        let key = self.own.get(group).ok_or(E2eError::NoKey)?;
+        let public_key = self.public_keys.get(recipient)
+            .ok_or_else(|| E2eError::UnknownPeer(recipient.to_string()))?;
+        let cipher = self.wrapping_cipher(public_key);
+        let aad = wrapping_data(group, sender, recipient, key.epoch);
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: &key.key, aad: &aad })
+            .map_err(|_| E2eError::Crypto)?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&ciphertext);
+        Ok(KeyMessage::Key {
+            group: group.to_string(),
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+            epoch: key.epoch,
+            wrapped: hex::encode(wrapped),
+        })
+    }
+
+    // Unwraps a member's key sent to us and stores it. Fails unless it was
+    // wrapped for us by the sender it names.
+    pub fn accept(&mut self, local_peer_id: &str, message: &KeyMessage) -> Result<(), E2eError> {
+        let KeyMessage::Key { group, sender, recipient, epoch, wrapped } = message;
+        if recipient != local_peer_id {
+            return Err(E2eError::NotForUs);
+        }
+        let public_key = self.public_keys.get(sender)
+            .ok_or_else(|| E2eError::UnknownPeer(sender.clone()))?;
+        let wrapped = hex::decode(wrapped).map_err(|_| E2eError::Malformed)?;
+        if wrapped.len() < NONCE_LEN {
+            return Err(E2eError::Malformed);
+        }
+        let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+        let aad = wrapping_data(group, sender, recipient, *epoch);
+        let key = self.wrapping_cipher(public_key)
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| E2eError::Crypto)?;
+        let key: MulticastKey = key.try_into().map_err(|_| E2eError::Malformed)?;
+
+        let keys = self.peers.entry((sender.clone(), group.clone())).or_default();
+        keys.retain(|known| known.epoch != *epoch);
+        keys.push(SenderKey::new(*epoch, key));
+        if keys.len() > KEPT_EPOCHS {
+            keys.remove(0);
+        }
+        Ok(())
+    }
+
+    // The host keeps what it passed on, for recipients whose channel
+    // isn't up yet. It never gets to read any of it.
+    pub fn remember_forwarded(&mut self, message: &KeyMessage) {
+        let KeyMessage::Key { group, sender, recipient, .. } = message;
+        self.forwarded.entry(recipient.clone())
+            .or_default()
+            .insert((sender.clone(), group.clone()), message.clone());
+    }
+    pub fn forwarded_for(&self, recipient: &str) -> Vec<KeyMessage> {
+        self.forwarded.get(recipient)
+            .map(|messages| messages.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn wrapping_cipher(&self, public_key: &PublicKey) -> Aes256Gcm {
+        let shared = ecdh::diffie_hellman(self.device.secret.to_nonzero_scalar(), public_key.as_affine());
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared.raw_secret_bytes())
+            .expand(WRAP_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Aes256Gcm::new(&key.into())
+    }
+
+    // Encrypts the payload with our key for the frame's group
+    pub fn seal(&self, frame: &mut AudioFrame) -> Result<(), E2eError> {
+        let group = frame.group.clone();
+        self.seal_as(frame, &group)
+    }
+    // Encrypts the payload with our key for another key group, for frames
+    // heard beyond their own group
+    pub fn seal_as(&self, frame: &mut AudioFrame, key_group: &str) -> Result<(), E2eError> {
+        let key = self.own.get(key_group).ok_or(E2eError::NoKey)?;
+        frame.flags |= FLAG_ENCRYPTED;
+        let aad = associated_data(frame)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = key.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: &frame.payload, aad: &aad })
+            .map_err(|_| E2eError::Crypto)?;
+
+        let mut payload = Vec::with_capacity(EPOCH_LEN + NONCE_LEN + ciphertext.len());
+        payload.extend_from_slice(&key.epoch.to_be_bytes());
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&ciphertext);
+        frame.payload = payload;
+        Ok(())
+    }
+
+    // Decrypts the payload with the sender's key, in place
+    pub fn open(&self, frame: &mut AudioFrame) -> Result<(), E2eError> {
+        let group = frame.group.clone();
+        self.open_as(frame, &group)
+    }
+    pub fn open_as(&self, frame: &mut AudioFrame, key_group: &str) -> Result<(), E2eError> {
+        if !frame.is_encrypted() {
+            return Err(E2eError::NotEncrypted);
+        }
+        if frame.payload.len() < EPOCH_LEN + NONCE_LEN {
+            return Err(E2eError::Malformed);
+        }
+        let (epoch, rest) = frame.payload.split_at(EPOCH_LEN);
+        let epoch = u32::from_be_bytes([epoch[0], epoch[1], epoch[2], epoch[3]]);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let key = self.peers.get(&(frame.sender.clone(), key_group.to_string()))
+            .ok_or(E2eError::NoKey)?
+            .iter()
+            .find(|key| key.epoch == epoch)
+            .ok_or(E2eError::UnknownEpoch(epoch))?;
+        let aad = associated_data(frame)?;
+        let plaintext = key.cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| E2eError::Crypto)?;
+        frame.payload = plaintext;
+        Ok(())
+    }
+}
+
+// Binds a wrapped key to its group, sender, recipient and epoch
+fn wrapping_data(group: &str, sender: &str, recipient: &str, epoch: u32) -> Vec<u8> {
+    format!("{}\0{}\0{}\0{}", group, sender, recipient, epoch).into_bytes()
+}
+
+// The encoded header, so a sealed payload can't be moved to another frame
+fn associated_data(frame: &AudioFrame) -> Result<Vec<u8>, E2eError> {
+    AudioFrame { payload: Vec::new(), ..frame.clone() }
+        .encode()
+        .map_err(|_| E2eError::Malformed)
+}
+
+impl fmt::Display for E2eError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            E2eError::NoKey => write!(f, "no group key"),
+            E2eError::UnknownEpoch(epoch) => write!(f, "unknown group key epoch {}", epoch),
+            E2eError::NotEncrypted => write!(f, "audio frame is not encrypted"),
+            E2eError::Malformed => write!(f, "encrypted payload is malformed"),
+            E2eError::Crypto => write!(f, "failed to decrypt"),
+            E2eError::UnknownPeer(peer) => write!(f, "no certificate for {}", peer),
+            E2eError::NotForUs => write!(f, "group key is addressed to another peer"),
+            E2eError::BadCertificate => write!(f, "certificate has no usable P-256 key"),
+        }
+    }
+}
+
+impl std::error::Error for E2eError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> DeviceKey {
+        let generated = rcgen::generate_simple_self_signed(vec!["walkie-talkie.local".to_string()]).unwrap();
+        DeviceKey::from_der(generated.cert.der().to_vec(), &generated.key_pair.serialize_der()).unwrap()
+    }
+
+    // Group keys for each peer, every one knowing every other's certificate
+    fn members(peers: &[&str]) -> Vec<GroupKeys> {
+        let devices: Vec<DeviceKey> = peers.iter().map(|_| device()).collect();
+        devices.iter().map(|own| {
+            let mut keys = GroupKeys::new(own.clone());
+            for (peer, device) in peers.iter().zip(&devices) {
+                keys.add_peer_certificate(peer, device.certificate()).unwrap();
+            }
+            keys
+        }).collect()
+    }
+
+    fn frame(sender: &str) -> AudioFrame {
+        AudioFrame::new(sender, "ops", 1, 160, vec![1, 2, 3, 4])
+    }
+
+    #[test]
+    fn member_opens_what_the_sender_sealed() {
+        let mut keys = members(&["alice", "bob"]);
+        keys[0].rotate("ops");
+        let message = keys[0].own_message("alice", "ops", "bob").unwrap();
+        keys[1].accept("bob", &message).unwrap();
+
+        let mut sealed = frame("alice");
+        keys[0].seal(&mut sealed).unwrap();
+        assert_ne!(sealed.payload, vec![1, 2, 3, 4]);
+        keys[1].open(&mut sealed).unwrap();
+        assert_eq!(sealed.payload, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rotation_keeps_the_previous_epoch_only() {
+        let mut keys = members(&["alice", "bob"]);
+        let mut sealed = Vec::new();
+        for _ in 0..3 {
+            keys[0].rotate("ops");
+            let message = keys[0].own_message("alice", "ops", "bob").unwrap();
+            keys[1].accept("bob", &message).unwrap();
+            let mut frame = frame("alice");
+            keys[0].seal(&mut frame).unwrap();
+            sealed.push(frame);
+        }
+        assert_eq!(keys[1].open(&mut sealed[0].clone()), Err(E2eError::UnknownEpoch(0)));
+        assert!(keys[1].open(&mut sealed[1].clone()).is_ok());
+        assert!(keys[1].open(&mut sealed[2].clone()).is_ok());
+    }
+
+    #[test]
+    fn forged_sender_is_rejected() {
+        let mut keys = members(&["alice", "bob", "mallory"]);
+        keys[2].rotate("ops");
+        // Mallory wraps her own key for bob but claims alice sent it
+        let KeyMessage::Key { group, recipient, epoch, wrapped, .. } =
+            keys[2].own_message("mallory", "ops", "bob").unwrap();
+        let forged = KeyMessage::Key { group, sender: "alice".to_string(), recipient, epoch, wrapped };
+        assert_eq!(keys[1].accept("bob", &forged), Err(E2eError::Crypto));
+
+        let mut frame = frame("alice");
+        keys[2].seal(&mut frame).unwrap();
+        assert_eq!(keys[1].open(&mut frame), Err(E2eError::NoKey));
+    }
+
+    #[test]
+    fn host_and_non_members_cannot_open() {
+        let mut keys = members(&["alice", "bob", "host"]);
+        keys[0].rotate("ops");
+        let message = keys[0].own_message("alice", "ops", "bob").unwrap();
+
+        // The host passes the key on but it isn't addressed to it
+        keys[2].remember_forwarded(&message);
+        assert_eq!(keys[2].forwarded_for("bob"), vec![message.clone()]);
+        assert_eq!(keys[2].accept("host", &message), Err(E2eError::NotForUs));
+        // Readdressing it doesn't help either
+        let KeyMessage::Key { group, sender, epoch, wrapped, .. } = message;
+        let readdressed = KeyMessage::Key { group, sender, recipient: "host".to_string(), epoch, wrapped };
+        assert_eq!(keys[2].accept("host", &readdressed), Err(E2eError::Crypto));
+
+        let mut sealed = frame("alice");
+        keys[0].seal(&mut sealed).unwrap();
+        assert_eq!(keys[2].open(&mut sealed), Err(E2eError::NoKey));
+    }
+
+    #[test]
+    fn frames_sealed_as_another_key_group_only_open_as_it() {
+        let mut keys = members(&["alice", "bob"]);
+        keys[0].rotate("ops");
+        keys[0].rotate(EMERGENCY_KEY_GROUP);
+        for group in ["ops", EMERGENCY_KEY_GROUP] {
+            let message = keys[0].own_message("alice", group, "bob").unwrap();
+            keys[1].accept("bob", &message).unwrap();
+        }
+
+        let mut sealed = frame("alice");
+        keys[0].seal_as(&mut sealed, EMERGENCY_KEY_GROUP).unwrap();
+        assert_eq!(sealed.group, "ops");
+        assert!(keys[1].open(&mut sealed.clone()).is_err());
+        keys[1].open_as(&mut sealed, EMERGENCY_KEY_GROUP).unwrap();
+        assert_eq!(sealed.payload, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn keys_need_the_recipients_certificate() {
+        let mut alice = GroupKeys::new(device());
+        alice.rotate("ops");
+        assert_eq!(alice.own_message("alice", "ops", "bob"), Err(E2eError::UnknownPeer("bob".to_string())));
+    }
+}
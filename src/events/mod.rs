@@ -0,0 +1,149 @@
+// ============================================
+//                  Imports
+// ============================================
+use std::time::Duration;
+use tokio::sync::broadcast;
+use crate::call::CallState;
+use crate::control::ControlCommand;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+
+// Events kept per subscriber before the slowest one starts lagging
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+// ============================================
+//                 Structures
+// ============================================
+
+// Everything a frontend needs to render the live state of a session
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    PeerJoined {
+        peer_id: String,
+        // None until the peer tells us its name
+        display_name: Option<String>,
+        groups: Vec<String>,
+        // Paired with us, see WebRTCModule::confirm_pairing
+        trusted: bool,
+    },
+    PeerLeft {
+        peer_id: String,
+    },
+    ConnectionStateChanged {
+        peer_id: String,
+        state: RTCPeerConnectionState,
+    },
+    GroupMembershipChanged {
+        peer_id: String,
+        groups: Vec<String>,
+    },
+    TalkStarted {
+        peer_id: String,
+        group: String,
+    },
+    TalkEnded {
+        peer_id: String,
+        group: String,
+    },
+    FloorDenied {
+        group: String,
+        // Peer currently holding the floor, if known
+        holder: Option<String>,
+    },
+    MessageReceived {
+        peer_id: String,
+        message: String,
+    },
+    // An admin command we accepted and applied
+    CommandReceived {
+        peer_id: String,
+        command: ControlCommand,
+    },
+    MuteChanged {
+        peer_id: String,
+        muted: bool,
+    },
+    // Raise an alert on every device
+    EmergencyStarted {
+        peer_id: String,
+    },
+    EmergencyEnded {
+        peer_id: String,
+    },
+    // Private call with a single peer
+    CallStateChanged {
+        peer_id: String,
+        state: CallState,
+    },
+    // We are about to be cut off, the warning tone is already playing
+    TransmitWarning {
+        group: String,
+        remaining: Duration,
+    },
+    TransmitCutOff {
+        group: String,
+        lockout: Duration,
+    },
+    StatsUpdated {
+        peer_id: String,
+        stats: PeerStats,
+    },
+    // A peer's certificate isn't the one it registered or was pinned with,
+    // someone may be impersonating it
+    IdentityMismatch {
+        peer_id: String,
+        expected: String,
+        presented: String,
+    },
+    DeviceTrusted {
+        peer_id: String,
+    },
+    DisplayNameChanged {
+        peer_id: String,
+        display_name: String,
+    },
+}
+
+// A peer as shown in the peer list
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    // Name to show, see identity::display_label
+    pub display_name: String,
+    pub groups: Vec<String>,
+    // Untrusted until the users compared pairing codes
+    pub trusted: bool,
+}
+
+// Connection quality snapshot for a single peer
+#[derive(Debug, Clone, Default)]
+pub struct PeerStats {
+    pub round_trip_time_ms: Option<f64>,
+    // Audio frames, counted from their sequence numbers
+    pub packets_received: u64,
+    pub packets_lost: u64,
+    pub jitter_ms: Option<f64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // Candidate types of the selected pair, "host/srflx", "relay/host"...
+    pub candidate_pair: Option<String>,
+    // Bytes waiting to be sent on the data channels
+    pub buffered_amount: usize,
+    // No direct path, audio goes through the room host
+    pub relayed: bool,
+}
+
+// ============================================
+//              Event Channel
+// ============================================
+pub type EventSender = broadcast::Sender<SessionEvent>;
+pub type EventReceiver = broadcast::Receiver<SessionEvent>;
+
+pub fn create_event_channel() -> EventSender {
+    let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+    sender
+}
+
+// Sends an event to every subscriber, it's fine if nobody is listening
+pub fn emit(sender: &EventSender, event: SessionEvent) {
+    let _ = sender.send(event);
+}
@@ -0,0 +1,286 @@
+// ============================================
+//                  Imports
+// ============================================
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+// The tokio clock, so hold timeouts follow a paused test clock
+use tokio::time::Instant;
+
+// Longest a peer may hold the floor before the arbiter takes it back
+pub const FLOOR_HOLD_TIMEOUT: Duration = Duration::from_secs(60);
+
+// ============================================
+//                 Structures
+// ============================================
+
+// Floor control messages carried over the control data channel
+// Example: {"floor": "request", "group": "all", "queue": true}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "floor", rename_all = "snake_case")]
+pub enum FloorMessage {
+    // Peer -> arbiter
+    Request { group: String, queue: bool },
+    Release { group: String },
+    // Arbiter -> requester
+    Denied { group: String, holder: Option<String> },
+    Queued { group: String, position: usize },
+    // Arbiter -> everyone
+    Granted { group: String, holder: String },
+    Released { group: String, holder: String },
+    // Caller -> everyone, preempts every floor in the room
+    Emergency { holder: String },
+    EmergencyEnded { holder: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FloorDecision {
+    Granted,
+    Denied { holder: String },
+    Queued { position: usize },
+}
+
+// Floor state of a single group
+#[derive(Debug, Default)]
+struct GroupFloor {
+    holder: Option<(String, Instant)>,
+    queue: VecDeque<String>,
+}
+
+// Decides who may talk in each group, only the arbiter peer runs one
+#[derive(Debug)]
+pub struct FloorArbiter {
+    groups: HashMap<String, GroupFloor>,
+    hold_timeout: Duration,
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl Default for FloorArbiter {
+    fn default() -> Self {
+        Self::new(FLOOR_HOLD_TIMEOUT)
+    }
+}
+
+impl FloorArbiter {
+    pub fn new(hold_timeout: Duration) -> Self {
+        Self {
+            groups: HashMap::new(),
+            hold_timeout,
+        }
+    }
+
+    pub fn holder(&self, group: &str) -> Option<&str> {
+        self.groups.get(group)?
+            .holder.as_ref()
+            .map(|(holder, _)| holder.as_str())
+    }
+
+    // Takes over a holder granted by a previous arbiter
+    pub fn adopt(&mut self, group: &str, holder: &str) {
+        let floor = self.groups.entry(group.to_string()).or_default();
+        if floor.holder.is_none() {
+            floor.holder = Some((holder.to_string(), Instant::now()));
+        }
+    }
+
+    pub fn request(&mut self, group: &str, peer_id: &str, queue: bool) -> FloorDecision {
+        let floor = self.groups.entry(group.to_string()).or_default();
+        match &floor.holder {
+            None => {
+                floor.queue.retain(|queued| queued != peer_id);
+                floor.holder = Some((peer_id.to_string(), Instant::now()));
+                FloorDecision::Granted
+            }
+            Some((holder, _)) if holder == peer_id => {
+                // Asking again renews the lease
+                floor.holder = Some((peer_id.to_string(), Instant::now()));
+                FloorDecision::Granted
+            }
+            Some((holder, _)) => {
+                if !queue {
+                    return FloorDecision::Denied { holder: holder.clone() };
+                }
+                if !floor.queue.iter().any(|queued| queued == peer_id) {
+                    floor.queue.push_back(peer_id.to_string());
+                }
+                let position = floor.queue.iter()
+                    .position(|queued| queued == peer_id)
+                    .unwrap_or(0);
+                FloorDecision::Queued { position: position + 1 }
+            }
+        }
+    }
+
+    // Releases the floor or leaves the queue. Returns the peer that was
+    // granted the floor next, if any.
+    pub fn release(&mut self, group: &str, peer_id: &str) -> Option<String> {
+        let floor = self.groups.get_mut(group)?;
+        floor.queue.retain(|queued| queued != peer_id);
+        match &floor.holder {
+            Some((holder, _)) if holder == peer_id => {
+                floor.holder = floor.queue.pop_front()
+                    .map(|next| (next, Instant::now()));
+                floor.holder.as_ref().map(|(next, _)| next.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // Removes a peer everywhere. Returns (group, next holder) for every
+    // floor the peer was holding.
+    pub fn release_peer(&mut self, peer_id: &str) -> Vec<(String, Option<String>)> {
+        let held: Vec<String> = self.groups.iter()
+            .filter(|(_, floor)| floor.holder.as_ref().is_some_and(|(holder, _)| holder == peer_id))
+            .map(|(group, _)| group.clone())
+            .collect();
+        for floor in self.groups.values_mut() {
+            floor.queue.retain(|queued| queued != peer_id);
+        }
+        held.into_iter()
+            .map(|group| {
+                let next = self.release(&group, peer_id);
+                (group, next)
+            })
+            .collect()
+    }
+
+    // Takes every floor back for an emergency. Returns (group, holder) for
+    // every floor that was held.
+    pub fn preempt_all(&mut self) -> Vec<(String, String)> {
+        let mut preempted = Vec::new();
+        for (group, floor) in self.groups.iter_mut() {
+            floor.queue.clear();
+            if let Some((holder, _)) = floor.holder.take() {
+                preempted.push((group.clone(), holder));
+            }
+        }
+        preempted
+    }
+
+    // Takes back floors held for too long. Returns (group, expired holder,
+    // next holder) for every floor that changed hands.
+    pub fn expire(&mut self, now: Instant) -> Vec<(String, String, Option<String>)> {
+        let hold_timeout = self.hold_timeout;
+        let expired: Vec<(String, String)> = self.groups.iter()
+            .filter_map(|(group, floor)| match &floor.holder {
+                Some((holder, granted_at)) if now.duration_since(*granted_at) >= hold_timeout =>
+                    Some((group.clone(), holder.clone())),
+                _ => None,
+            })
+            .collect();
+        expired.into_iter()
+            .map(|(group, holder)| {
+                let next = self.release(&group, &holder);
+                (group, holder, next)
+            })
+            .collect()
+    }
+}
+
+// ============================================
+//            Arbiter Selection
+// ============================================
+
+// The room host arbitrates while it's around, otherwise the lowest peer id
+// among the peers we can see takes over so everyone picks the same one.
+pub fn select_arbiter(host: Option<&str>, local_peer_id: &str, connected_peers: &[String]) -> String {
+    if let Some(host) = host {
+        if host == local_peer_id || connected_peers.iter().any(|peer| peer == host) {
+            return host.to_string();
+        }
+    }
+    connected_peers.iter()
+        .map(|peer| peer.as_str())
+        .chain(std::iter::once(local_peer_id))
+        .min()
+        .unwrap_or(local_peer_id)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_floor_goes_to_one_talker_at_a_time() {
+        let mut arbiter = FloorArbiter::default();
+        assert_eq!(arbiter.request("ops", "alice", false), FloorDecision::Granted);
+        assert_eq!(arbiter.request("ops", "bob", false), FloorDecision::Denied { holder: "alice".to_string() });
+        // Other groups have floors of their own
+        assert_eq!(arbiter.request("fire", "bob", false), FloorDecision::Granted);
+        assert_eq!(arbiter.request("ops", "alice", false), FloorDecision::Granted);
+        assert_eq!(arbiter.holder("ops"), Some("alice"));
+    }
+
+    #[test]
+    fn queued_requests_take_over_in_order() {
+        let mut arbiter = FloorArbiter::default();
+        arbiter.request("ops", "alice", false);
+        assert_eq!(arbiter.request("ops", "bob", true), FloorDecision::Queued { position: 1 });
+        assert_eq!(arbiter.request("ops", "carol", true), FloorDecision::Queued { position: 2 });
+        assert_eq!(arbiter.request("ops", "bob", true), FloorDecision::Queued { position: 1 });
+
+        // Releasing a floor you don't hold only leaves the queue
+        assert_eq!(arbiter.release("ops", "bob"), None);
+        assert_eq!(arbiter.release("ops", "alice"), Some("carol".to_string()));
+        assert_eq!(arbiter.release("ops", "carol"), None);
+        assert_eq!(arbiter.holder("ops"), None);
+    }
+
+    #[test]
+    fn departed_peers_lose_their_floors_and_places() {
+        let mut arbiter = FloorArbiter::default();
+        arbiter.request("ops", "alice", false);
+        arbiter.request("fire", "bob", false);
+        arbiter.request("fire", "alice", true);
+        arbiter.request("ops", "carol", true);
+
+        let released = arbiter.release_peer("alice");
+        assert_eq!(released, vec![("ops".to_string(), Some("carol".to_string()))]);
+        assert_eq!(arbiter.release("fire", "bob"), None);
+    }
+
+    #[test]
+    fn floors_held_too_long_are_taken_back() {
+        let mut arbiter = FloorArbiter::new(Duration::from_secs(10));
+        arbiter.request("ops", "alice", false);
+        arbiter.request("ops", "bob", true);
+        let now = Instant::now();
+        assert!(arbiter.expire(now).is_empty());
+
+        let expired = arbiter.expire(now + Duration::from_secs(10));
+        assert_eq!(expired, vec![("ops".to_string(), "alice".to_string(), Some("bob".to_string()))]);
+        assert_eq!(arbiter.holder("ops"), Some("bob"));
+    }
+
+    #[test]
+    fn emergencies_take_every_floor_and_clear_the_queues() {
+        let mut arbiter = FloorArbiter::default();
+        arbiter.request("ops", "alice", false);
+        arbiter.request("ops", "bob", true);
+        arbiter.request("fire", "carol", false);
+
+        let mut preempted = arbiter.preempt_all();
+        preempted.sort();
+        assert_eq!(preempted, vec![
+            ("fire".to_string(), "carol".to_string()),
+            ("ops".to_string(), "alice".to_string()),
+        ]);
+        assert_eq!(arbiter.holder("ops"), None);
+        // Bob's place in the queue went with it
+        assert_eq!(arbiter.request("ops", "dave", false), FloorDecision::Granted);
+    }
+
+    #[test]
+    fn the_host_arbitrates_while_connected() {
+        let peers = vec!["carol".to_string(), "bob".to_string()];
+        assert_eq!(select_arbiter(Some("carol"), "dave", &peers), "carol");
+        assert_eq!(select_arbiter(Some("dave"), "dave", &peers), "dave");
+        // Everyone falls back on the same lowest id
+        assert_eq!(select_arbiter(Some("alice"), "dave", &peers), "bob");
+        assert_eq!(select_arbiter(None, "abe", &peers), "abe");
+    }
+}
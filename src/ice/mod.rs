@@ -0,0 +1,297 @@
+// ============================================
+//                  Imports
+// ============================================
+use crate::log;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::policy::bundle_policy::RTCBundlePolicy;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
+use webrtc::peer_connection::policy::rtcp_mux_policy::RTCRtcpMuxPolicy;
+use webrtc::turn::auth::{generate_auth_key, AuthHandler};
+use webrtc::turn::relay::relay_static::RelayAddressGeneratorStatic;
+use webrtc::turn::server::config::{ConnConfig, ServerConfig};
+use webrtc::turn::server::Server;
+use webrtc::util::vnet::net::Net;
+
+// Room metadata key holding the ICE settings
+pub const ICE_METADATA_KEY: &str = "ice";
+pub const TURN_DEFAULT_PORT: u16 = 3478;
+const TURN_REALM: &str = "walkie-talkie";
+const TURN_CREDENTIAL_LEN: usize = 16;
+
+// ============================================
+//                 Structures
+// ============================================
+
+// ICE settings of a room, carried in its metadata
+// Example: {"servers": [{"urls": ["turn:192.168.1.2:3478"], "username": "u",
+//           "credential": "p"}], "transport_policy": "all", "candidate_pool_size": 0}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IceConfig {
+    pub servers: Vec<IceServer>,
+    // "all", or "relay" to only ever go through a TURN server
+    pub transport_policy: String,
+    pub candidate_pool_size: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    // TURN credentials, empty for STUN
+    pub username: String,
+    pub credential: String,
+}
+
+// TURN relay the room host can run so peers on isolated VLANs still meet
+pub struct TurnRelay {
+    server: Server,
+    ice_server: IceServer,
+}
+
+struct RelayAuth {
+    username: String,
+    key: Vec<u8>,
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl Default for IceConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            transport_policy: "all".to_string(),
+            candidate_pool_size: 0,
+        }
+    }
+}
+
+impl IceConfig {
+    // Room settings, or host candidates only when the room has none
+    pub fn from_metadata(metadata: &HashMap<String, serde_json::Value>) -> Self {
+        metadata.get(ICE_METADATA_KEY)
+            .and_then(|ice| match serde_json::from_value(ice.clone()) {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    log::log_message(&format!("Ignoring invalid ICE settings: {}", e));
+                    None
+                }
+            })
+            .unwrap_or_default()
+    }
+    pub fn to_metadata(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    pub fn rtc_configuration(&self) -> RTCConfiguration {
+        let ice_transport_policy = match self.transport_policy.as_str() {
+            "relay" => RTCIceTransportPolicy::Relay,
+            _ => RTCIceTransportPolicy::All,
+        };
+        RTCConfiguration {
+            ice_servers: self.servers.iter().map(IceServer::rtc_ice_server).collect(),
+            ice_transport_policy,
+            bundle_policy: RTCBundlePolicy::MaxBundle,
+            rtcp_mux_policy: RTCRtcpMuxPolicy::Require,
+            ice_candidate_pool_size: self.candidate_pool_size,
+            // peer_identity & certificates ommited
+            ..Default::default()
+        }
+    }
+}
+
+impl IceServer {
+    fn rtc_ice_server(&self) -> RTCIceServer {
+        let credential_type = if self.username.is_empty() {
+            RTCIceCredentialType::Unspecified
+        } else {
+            RTCIceCredentialType::Password
+        };
+        RTCIceServer {
+            urls: self.urls.clone(),
+            username: self.username.clone(),
+            credential: self.credential.clone(),
+            credential_type,
+        }
+    }
+}
+
+impl AuthHandler for RelayAuth {
+    fn auth_handle(&self, username: &str, _realm: &str, _src_addr: SocketAddr)
+    -> Result<Vec<u8>, webrtc::turn::Error> {
+        if username == self.username {
+            Ok(self.key.clone())
+        } else {
+            Err(webrtc::turn::Error::ErrNoSuchUser)
+        }
+    }
+}
+
+impl TurnRelay {
+    // Listens on the given address with a fresh set of credentials
+    pub async fn start(ip_address: IpAddr, port: u16) -> Result<Self, Box<dyn std::error::Error>> {
+        let username = random_credential();
+        let credential = random_credential();
+        let conn = Arc::new(UdpSocket::bind(SocketAddr::new(ip_address, port)).await?);
+        // Port 0 lets the OS pick, publish the one we actually got
+        let port = conn.local_addr()?.port();
+
+        let server = Server::new(ServerConfig {
+            conn_configs: vec![ConnConfig {
+                conn,
+                relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+                    relay_address: ip_address,
+                    address: ip_address.to_string(),
+                    net: Arc::new(Net::new(None)),
+                }),
+            }],
+            realm: TURN_REALM.to_string(),
+            auth_handler: Arc::new(RelayAuth {
+                key: generate_auth_key(&username, TURN_REALM, &credential),
+                username: username.clone(),
+            }),
+            channel_bind_timeout: std::time::Duration::from_secs(0),
+            alloc_close_notify: None,
+        }).await?;
+        log::log_message(&format!("TURN relay listening on {}:{}", ip_address, port));
+
+        Ok(Self {
+            server,
+            ice_server: IceServer {
+                urls: vec![format!("turn:{}:{}?transport=udp", ip_address, port)],
+                username,
+                credential,
+            },
+        })
+    }
+    // Entry to publish in the room's ICE settings
+    pub fn ice_server(&self) -> IceServer {
+        self.ice_server.clone()
+    }
+    pub async fn close(&self) {
+        if let Err(e) = self.server.close().await {
+            log::log_message(&format!("Error closing TURN relay: {}", e));
+        }
+    }
+}
+
+fn random_credential() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TURN_CREDENTIAL_LEN)
+        .map(char::from)
+        .collect()
+}
+
+// ============================================
+//                   Tests
+// ============================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn metadata(ice: serde_json::Value) -> HashMap<String, serde_json::Value> {
+        HashMap::from([(ICE_METADATA_KEY.to_string(), ice)])
+    }
+
+    #[test]
+    fn rooms_without_settings_use_host_candidates() {
+        let config = IceConfig::from_metadata(&HashMap::new());
+        assert_eq!(config, IceConfig::default());
+
+        let rtc = config.rtc_configuration();
+        assert!(rtc.ice_servers.is_empty());
+        assert_eq!(rtc.ice_transport_policy, RTCIceTransportPolicy::All);
+    }
+
+    #[test]
+    fn invalid_settings_fall_back_to_the_default() {
+        let config = IceConfig::from_metadata(&metadata(serde_json::json!({"servers": "nope"})));
+        assert_eq!(config, IceConfig::default());
+        let config = IceConfig::from_metadata(&metadata(serde_json::json!(42)));
+        assert_eq!(config, IceConfig::default());
+    }
+
+    #[test]
+    fn settings_round_trip_through_metadata() {
+        let config = IceConfig {
+            servers: vec![IceServer {
+                urls: vec!["turn:192.168.1.2:3478".to_string()],
+                username: "u".to_string(),
+                credential: "p".to_string(),
+            }],
+            transport_policy: "relay".to_string(),
+            candidate_pool_size: 2,
+        };
+        assert_eq!(IceConfig::from_metadata(&metadata(config.to_metadata())), config);
+
+        // Missing fields take their defaults
+        let partial = IceConfig::from_metadata(&metadata(serde_json::json!({"candidate_pool_size": 1})));
+        assert_eq!(partial.transport_policy, "all");
+        assert!(partial.servers.is_empty());
+        assert_eq!(partial.candidate_pool_size, 1);
+    }
+
+    #[test]
+    fn transport_policy_maps_to_rtc() {
+        let cases = [
+            ("relay", RTCIceTransportPolicy::Relay),
+            ("all", RTCIceTransportPolicy::All),
+            ("anything else", RTCIceTransportPolicy::All),
+        ];
+        for (policy, expected) in cases {
+            let config = IceConfig { transport_policy: policy.to_string(), ..Default::default() };
+            assert_eq!(config.rtc_configuration().ice_transport_policy, expected, "{}", policy);
+        }
+    }
+
+    #[test]
+    fn only_turn_servers_carry_a_password() {
+        let config = IceConfig {
+            servers: vec![
+                IceServer { urls: vec!["stun:192.168.1.2:3478".to_string()], ..Default::default() },
+                IceServer {
+                    urls: vec!["turn:192.168.1.2:3478".to_string()],
+                    username: "u".to_string(),
+                    credential: "p".to_string(),
+                },
+            ],
+            candidate_pool_size: 3,
+            ..Default::default()
+        };
+        let rtc = config.rtc_configuration();
+        assert_eq!(rtc.ice_candidate_pool_size, 3);
+        assert_eq!(rtc.ice_servers[0].credential_type, RTCIceCredentialType::Unspecified);
+        assert_eq!(rtc.ice_servers[1].credential_type, RTCIceCredentialType::Password);
+        assert_eq!(rtc.ice_servers[1].username, "u");
+        assert_eq!(rtc.ice_servers[1].credential, "p");
+    }
+
+    #[tokio::test]
+    async fn turn_relay_publishes_its_credentials() {
+        let relay = TurnRelay::start(IpAddr::V4(Ipv4Addr::LOCALHOST), 0).await.unwrap();
+        let server = relay.ice_server();
+        assert_eq!(server.urls.len(), 1);
+        let port: u16 = server.urls[0]
+            .strip_prefix("turn:127.0.0.1:").unwrap()
+            .strip_suffix("?transport=udp").unwrap()
+            .parse().unwrap();
+        assert_ne!(port, 0);
+        assert_eq!(server.username.len(), TURN_CREDENTIAL_LEN);
+        assert_eq!(server.credential.len(), TURN_CREDENTIAL_LEN);
+        assert_ne!(server.username, server.credential);
+        relay.close().await;
+    }
+}
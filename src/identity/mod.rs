@@ -0,0 +1,274 @@
+// ============================================
+//                  Imports
+// ============================================
+use crate::db;
+use crate::e2e::DeviceKey;
+use crate::log;
+use rand::RngCore;
+use rcgen::{KeyPair, PKCS_ECDSA_P256_SHA256};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use webrtc::peer_connection::certificate::RTCCertificate;
+
+// Pairing code emoji, 6 bits worth each
+const SAS_EMOJI: [&str; 64] = [
+    "🐶", "🐱", "🦁", "🐎", "🦄", "🐷", "🐘", "🐰",
+    "🐼", "🐓", "🐧", "🐢", "🐟", "🐙", "🦋", "🌷",
+    "🌳", "🌵", "🍄", "🌏", "🌙", "☁️", "🔥", "🍌",
+    "🍎", "🍓", "🌽", "🍕", "🎂", "❤️", "😀", "🤖",
+    "🎩", "👓", "🔧", "🎅", "👍", "☂️", "⌛", "⏰",
+    "🎁", "💡", "📕", "✏️", "📎", "✂️", "🔒", "🔑",
+    "🔨", "☎️", "🏁", "🚂", "🚲", "✈️", "🚀", "🏆",
+    "⚽", "🎸", "🎺", "🔔", "⚓", "🎧", "📁", "📌",
+];
+const SAS_EMOJI_COUNT: usize = 5;
+const DEVICE_ID_LEN: usize = 8;
+// Device id characters shown to tell apart peers with the same name
+const SHORT_ID_LEN: usize = 4;
+const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+// ============================================
+//                 Structures
+// ============================================
+
+// Code both users read out to each other when pairing, either form will do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShortAuthString {
+    // "123 456"
+    pub digits: String,
+    pub emoji: Vec<&'static str>,
+}
+
+// What a peer's fingerprint says about who it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinCheck {
+    // First time we see this peer, its fingerprint is now pinned
+    Pinned,
+    Known,
+    // Someone else is using this peer's name
+    Mismatch { pinned: String },
+}
+
+// ============================================
+//              Device Id
+// ============================================
+
+// Random id the device keeps for good, whatever name its user goes by.
+// Returns it with the display name stored last time, if any.
+pub fn load_or_create_device(pool: &db::SqlitePool)
+-> Result<(String, Option<String>), Box<dyn std::error::Error>> {
+    if let Some(device) = db::load_local_device(pool)? {
+        return Ok(device);
+    }
+    let mut bytes = [0u8; DEVICE_ID_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let device_id = hex::encode(bytes);
+    db::store_device_id(pool, &device_id)?;
+    log::log_message(&format!("Created device id {}", device_id));
+    Ok((device_id, None))
+}
+
+// Single line and bounded, None if nothing is left
+pub fn sanitize_display_name(name: &str) -> Option<String> {
+    let name: String = name.chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_DISPLAY_NAME_LEN)
+        .collect();
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+// What to call a peer: its name, with part of its device id when someone
+// else goes by the same name, or just the id if it never told us its name
+pub fn display_label(names: &HashMap<String, String>, peer_id: &str) -> String {
+    let name = match names.get(peer_id) {
+        Some(name) => name,
+        None => return peer_id.to_string(),
+    };
+    let taken = names.iter().any(|(other, other_name)| other != peer_id && other_name == name);
+    if taken {
+        let short_id: String = peer_id.chars().take(SHORT_ID_LEN).collect();
+        format!("{}#{}", name, short_id)
+    } else {
+        name.clone()
+    }
+}
+
+// ============================================
+//            Device Certificate
+// ============================================
+
+// The certificate stored in the db, or a new one the first time around.
+// Its key pair is the device's long lived identity.
+pub fn load_or_create_certificate(pool: &db::SqlitePool) -> Result<RTCCertificate, webrtc::Error> {
+    match db::load_device_certificate(pool) {
+        Ok(Some(pem)) => match RTCCertificate::from_pem(&pem) {
+            Ok(certificate) => return Ok(certificate),
+            Err(e) => log::log_message(&format!("Stored device certificate is unusable: {}", e)),
+        },
+        Ok(None) => {}
+        Err(e) => return Err(webrtc::Error::new(format!("Failed to load device certificate: {}", e))),
+    }
+
+    let key_pair = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)
+        .map_err(|e| webrtc::Error::new(e.to_string()))?;
+    let certificate = RTCCertificate::from_key_pair(key_pair)?;
+    db::store_device_certificate(pool, &certificate.serialize_pem())
+        .map_err(|e| webrtc::Error::new(format!("Failed to store device certificate: {}", e)))?;
+    log::log_message("Created a new device certificate");
+    Ok(certificate)
+}
+
+// The certificate's key pair, to wrap group keys with. The DTLS certificate
+// doesn't hand out its private key, its PEM form does.
+pub fn device_key(certificate: &RTCCertificate) -> Result<DeviceKey, Box<dyn std::error::Error>> {
+    let blocks = pem::parse_many(certificate.serialize_pem())?;
+    let find = |tag: &str| blocks.iter()
+        .find(|block| block.tag() == tag)
+        .map(|block| block.contents().to_vec())
+        .ok_or_else(|| format!("Device certificate has no {} block", tag));
+    let private_key = find("PRIVATE_KEY")?;
+    Ok(DeviceKey::from_der(find("CERTIFICATE")?, &private_key)?)
+}
+
+// SHA-256 fingerprint as bare lowercase hex, so it fits in colon delimited
+// signaling messages
+pub fn certificate_fingerprint(certificate: &RTCCertificate) -> String {
+    certificate.get_fingerprints()
+        .first()
+        .map(|fingerprint| normalize_fingerprint(&fingerprint.value))
+        .unwrap_or_default()
+}
+
+// The a=fingerprint the remote DTLS certificate will be checked against
+pub fn sdp_fingerprint(sdp: &str) -> Option<String> {
+    sdp.lines()
+        .filter_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+        .find_map(|value| {
+            let (algorithm, fingerprint) = value.split_once(' ')?;
+            algorithm.eq_ignore_ascii_case("sha-256")
+                .then(|| normalize_fingerprint(fingerprint))
+        })
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.trim().replace(':', "").to_lowercase()
+}
+
+// ============================================
+//              Pinning
+// ============================================
+
+// Trust on first use, a peer keeps the fingerprint we first saw it with
+pub fn check_pin(pool: &db::SqlitePool, peer_id: &str, fingerprint: &str) -> PinCheck {
+    match db::get_pinned_fingerprint(pool, peer_id) {
+        Ok(Some(pinned)) if pinned == fingerprint => PinCheck::Known,
+        Ok(Some(pinned)) => PinCheck::Mismatch { pinned },
+        Ok(None) => {
+            if let Err(e) = db::pin_device(pool, peer_id, fingerprint) {
+                log::log_message(&format!("Failed to pin {}: {}", peer_id, e));
+            }
+            PinCheck::Pinned
+        }
+        Err(e) => {
+            // Better to refuse than to silently skip the check
+            log::log_message(&format!("Failed to look up {}: {}", peer_id, e));
+            PinCheck::Mismatch { pinned: String::new() }
+        }
+    }
+}
+
+// ============================================
+//              Pairing
+// ============================================
+
+// Derived from both fingerprints, so both devices show the same code
+// only if nobody sits between them
+pub fn short_auth_string(local_fingerprint: &str, remote_fingerprint: &str) -> ShortAuthString {
+    let mut fingerprints = [local_fingerprint, remote_fingerprint];
+    fingerprints.sort();
+    let mut hasher = Sha256::new();
+    hasher.update(b"walkie-talkie-sas");
+    for fingerprint in fingerprints {
+        hasher.update(fingerprint.as_bytes());
+    }
+    let hash = hasher.finalize();
+
+    let number = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 1_000_000;
+    let emoji = hash[4..4 + SAS_EMOJI_COUNT].iter()
+        .map(|byte| SAS_EMOJI[(byte & 0x3f) as usize])
+        .collect();
+    ShortAuthString {
+        digits: format!("{:03} {:03}", number / 1000, number % 1000),
+        emoji,
+    }
+}
+
+pub fn is_trusted(pool: &db::SqlitePool, peer_id: &str) -> bool {
+    db::is_device_trusted(pool, peer_id).unwrap_or_else(|e| {
+        log::log_message(&format!("Failed to look up {}: {}", peer_id, e));
+        false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_key_comes_from_the_dtls_certificate() {
+        let key_pair = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let certificate = RTCCertificate::from_key_pair(key_pair).unwrap();
+        let device_key = device_key(&certificate).unwrap();
+        let fingerprint = hex::encode(Sha256::digest(device_key.certificate()));
+        assert_eq!(fingerprint, certificate_fingerprint(&certificate));
+    }
+
+    #[test]
+    fn the_first_fingerprint_seen_is_kept() {
+        let pool = db::TestDb::new();
+        assert_eq!(check_pin(&pool, "alice", "aa11"), PinCheck::Pinned);
+        assert_eq!(check_pin(&pool, "alice", "aa11"), PinCheck::Known);
+        assert_eq!(check_pin(&pool, "alice", "bb22"), PinCheck::Mismatch { pinned: "aa11".to_string() });
+        // A mismatch doesn't replace the pin
+        assert_eq!(check_pin(&pool, "alice", "aa11"), PinCheck::Known);
+        assert_eq!(check_pin(&pool, "bob", "bb22"), PinCheck::Pinned);
+    }
+
+    #[test]
+    fn sdp_fingerprints_are_normalized() {
+        let sdp = "v=0\r\na=fingerprint:sha-1 00:11\r\na=fingerprint:SHA-256 AB:CD:EF\r\n";
+        assert_eq!(sdp_fingerprint(sdp), Some("abcdef".to_string()));
+        assert_eq!(sdp_fingerprint("v=0\r\n"), None);
+    }
+
+    #[test]
+    fn both_devices_derive_the_same_pairing_code() {
+        let code = short_auth_string("aa11", "bb22");
+        assert_eq!(code, short_auth_string("bb22", "aa11"));
+        assert_eq!(code.digits.len(), 7);
+        assert!(code.digits.chars().enumerate().all(|(i, c)| if i == 3 { c == ' ' } else { c.is_ascii_digit() }));
+        assert_eq!(code.emoji.len(), SAS_EMOJI_COUNT);
+        // Someone in the middle shows each side a different certificate
+        assert_ne!(code, short_auth_string("aa11", "cc33"));
+    }
+
+    #[test]
+    fn shared_names_are_told_apart_by_device_id() {
+        let mut names = HashMap::new();
+        names.insert("a1b2c3d4".to_string(), "Alex".to_string());
+        assert_eq!(display_label(&names, "a1b2c3d4"), "Alex");
+        assert_eq!(display_label(&names, "f0e1d2c3"), "f0e1d2c3");
+
+        names.insert("f0e1d2c3".to_string(), "Alex".to_string());
+        assert_eq!(display_label(&names, "a1b2c3d4"), "Alex#a1b2");
+        assert_eq!(display_label(&names, "f0e1d2c3"), "Alex#f0e1");
+    }
+
+    #[test]
+    fn display_names_are_single_line_and_bounded() {
+        assert_eq!(sanitize_display_name("  Alex\n"), Some("Alex".to_string()));
+        assert_eq!(sanitize_display_name(" \t "), None);
+        assert_eq!(sanitize_display_name(&"x".repeat(100)).map(|name| name.len()), Some(MAX_DISPLAY_NAME_LEN));
+    }
+}
@@ -1,7 +1,23 @@
 pub mod audio;
+pub mod auth;
+pub mod call;
 pub mod communication;
+pub mod control;
 pub mod discovery;
+pub mod e2e;
+pub mod events;
+pub mod floor;
+pub mod ice;
+pub mod identity;
 pub mod db;
 pub mod log;
+pub mod packet;
+pub mod relay;
+pub mod scan;
+pub mod stats;
+pub mod topology;
+pub mod tls;
+pub mod transmit;
 pub mod websocket;
 pub mod metadata;
+pub mod multicast;
@@ -10,7 +10,16 @@ use std::io::Write;
 //            Global Logger Instance
 // ============================================
 lazy_static! {
-    static ref LOGGER: Mutex<Logger> = Mutex::new(Logger::new("log.txt").unwrap());
+    static ref LOGGER: Mutex<Logger> = Mutex::new(Logger::new(&log_path()).unwrap());
+}
+#[cfg(not(test))]
+fn log_path() -> String {
+    "log.txt".to_string()
+}
+// Test runs keep out of the working tree
+#[cfg(test)]
+fn log_path() -> String {
+    std::env::temp_dir().join("wt-test-log.txt").to_string_lossy().into_owned()
 }
 // ============================================
 //                 Logger Struct
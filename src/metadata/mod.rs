@@ -86,3 +86,111 @@ pub fn find_nested_metadata_value<'a>(
     }
     None
 }
+
+// Members of a group: metadata["groups"][group]["members"]
+pub fn find_group_members<'a>(
+    metadata: &'a HashMap<String, serde_json::Value>,
+    group: &str,
+) -> Option<&'a serde_json::Map<String, serde_json::Value>> {
+    find_nested_metadata_value(metadata, "groups", group)?
+        .get("members")?
+        .as_object()
+}
+
+// A member is an admin if any group lists them with "admin": true
+pub fn is_admin(
+    metadata: &HashMap<String, serde_json::Value>,
+    peer_id: &str,
+) -> bool {
+    let groups = match find_metadata_value(metadata, "groups").and_then(|g| g.as_object()) {
+        Some(groups) => groups,
+        None => return false,
+    };
+    groups.keys().any(|group| {
+        find_group_members(metadata, group)
+            .and_then(|members| members.get(peer_id))
+            .and_then(|member| member.get("admin"))
+            .and_then(|admin| admin.as_bool())
+            .unwrap_or(false)
+    })
+}
+
+// Every member listed in any group
+pub fn list_members(metadata: &HashMap<String, serde_json::Value>) -> Vec<String> {
+    let mut members: Vec<String> = Vec::new();
+    if let Some(groups) = find_metadata_value(metadata, "groups").and_then(|g| g.as_object()) {
+        for group in groups.keys() {
+            if let Some(group_members) = find_group_members(metadata, group) {
+                for member in group_members.keys() {
+                    if !members.contains(member) {
+                        members.push(member.clone());
+                    }
+                }
+            }
+        }
+    }
+    members
+}
+
+// First value of a member flag ("admin", "mute"...) found in any group
+pub fn find_member_flag<'a>(
+    metadata: &'a HashMap<String, serde_json::Value>,
+    peer_id: &str,
+    flag: &str,
+) -> Option<&'a serde_json::Value> {
+    let groups = find_metadata_value(metadata, "groups")?.as_object()?;
+    groups.keys()
+        .filter_map(|group| find_group_members(metadata, group)?.get(peer_id)?.get(flag))
+        .next()
+}
+
+// Sets a member flag in every group the member is listed in, members
+// that aren't listed anywhere yet are added to the "all" group
+pub fn set_member_flag(
+    metadata: &mut HashMap<String, serde_json::Value>,
+    peer_id: &str,
+    flag: &str,
+    new_value: serde_json::Value,
+) {
+    let groups = metadata.entry("groups".to_string())
+        .or_insert_with(|| serde_json::json!({}));
+    let groups = match groups.as_object_mut() {
+        Some(groups) => groups,
+        None => return,
+    };
+
+    let mut found = false;
+    for group in groups.values_mut() {
+        if let Some(member) = group.get_mut("members")
+            .and_then(|members| members.get_mut(peer_id))
+            .and_then(|member| member.as_object_mut()) {
+            member.insert(flag.to_string(), new_value.clone());
+            found = true;
+        }
+    }
+    if !found {
+        let all = groups.entry("all".to_string())
+            .or_insert_with(|| serde_json::json!({ "members": {} }));
+        if let Some(members) = all.get_mut("members").and_then(|m| m.as_object_mut()) {
+            members.insert(peer_id.to_string(), serde_json::json!({
+                "admin": false,
+                "online": true,
+                "mute": false
+            }));
+            if let Some(member) = members.get_mut(peer_id).and_then(|m| m.as_object_mut()) {
+                member.insert(flag.to_string(), new_value);
+            }
+        }
+    }
+}
+
+// Admins and members flagged with "emergency": true may call emergencies
+pub fn can_declare_emergency(
+    metadata: &HashMap<String, serde_json::Value>,
+    peer_id: &str,
+) -> bool {
+    is_admin(metadata, peer_id)
+        || find_member_flag(metadata, peer_id, "emergency")
+            .and_then(|emergency| emergency.as_bool())
+            .unwrap_or(false)
+}
@@ -0,0 +1,159 @@
+// ============================================
+//              LAN Multicast Audio
+// Announcement style groups with many listeners
+// send their frames once over UDP multicast
+// instead of once per peer. Keys are handed out
+// by the room host over its control channel to
+// each member, and replaced when one leaves.
+// ============================================
+
+// ============================================
+//                  Imports
+// ============================================
+use crate::metadata;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+
+// Organization local scope, 239.255.0.0/16
+const ADDRESS_PREFIX: [u8; 2] = [239, 255];
+// Each group gets its own port so sockets don't see other groups' traffic
+const PORT_BASE: u16 = 40000;
+const PORT_RANGE: u32 = 1000;
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+// Large enough for any Opus frame we send
+const MAX_PACKET_LEN: usize = 4096;
+
+pub type MulticastKey = [u8; KEY_LEN];
+
+// ============================================
+//                 Structures
+// ============================================
+
+// A group's key from the room host, over the control data channel or the
+// signaling server for relayed peers
+// Example: {"multicast_group": "announcements", "key": "9f86d0..."}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MulticastKeyMessage {
+    pub multicast_group: String,
+    pub key: String,
+}
+
+// Socket joined to one group's multicast address
+//
+// +-----------+------------------------------+
+// | nonce 12B | AES-256-GCM(audio frame)     |
+// +-----------+------------------------------+
+pub struct MulticastSocket {
+    socket: UdpSocket,
+    destination: SocketAddrV4,
+    cipher: Aes256Gcm,
+    group: String,
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl MulticastSocket {
+    // Joins the group's address on the given interface
+    pub fn join(interface: Ipv4Addr, room: &str, group: &str, key: &MulticastKey)
+    -> Result<Self, Box<dyn std::error::Error>> {
+        let destination = group_address(room, group);
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), destination.port()).into())?;
+        socket.join_multicast_v4(destination.ip(), &interface)?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket: UdpSocket::from_std(socket.into())?,
+            destination,
+            cipher: Aes256Gcm::new(key.into()),
+            group: group.to_string(),
+        })
+    }
+
+    pub async fn send(&self, frame: &[u8]) -> std::io::Result<()> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        // The group is bound in so a packet can't be replayed into another one
+        let ciphertext = self.cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: frame, aad: self.group.as_bytes() })
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Failed to encrypt multicast frame"))?;
+
+        let mut packet = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        packet.extend_from_slice(&nonce);
+        packet.extend_from_slice(&ciphertext);
+        self.socket.send_to(&packet, self.destination).await?;
+        Ok(())
+    }
+
+    // Next frame from the group, packets that don't decrypt are skipped
+    pub async fn recv(&self) -> std::io::Result<Vec<u8>> {
+        let mut buffer = [0u8; MAX_PACKET_LEN];
+        loop {
+            let (len, _) = self.socket.recv_from(&mut buffer).await?;
+            if len < NONCE_LEN {
+                continue;
+            }
+            let (nonce, ciphertext) = buffer[..len].split_at(NONCE_LEN);
+            let payload = Payload { msg: ciphertext, aad: self.group.as_bytes() };
+            if let Ok(frame) = self.cipher.decrypt(Nonce::from_slice(nonce), payload) {
+                return Ok(frame);
+            }
+        }
+    }
+}
+
+// Groups marked with "transport": "multicast" in the room metadata
+pub fn is_multicast_group(metadata: &HashMap<String, serde_json::Value>, group: &str) -> bool {
+    metadata::find_nested_metadata_value(metadata, "groups", group)
+        .and_then(|group| group.get("transport"))
+        .and_then(|transport| transport.as_str())
+        == Some("multicast")
+}
+
+// Address and port hashed from the room and group so every member agrees
+// on them without negotiating
+pub fn group_address(room: &str, group: &str) -> SocketAddrV4 {
+    // FNV-1a, std's hasher isn't guaranteed to agree across builds
+    let hash = room.bytes().chain([b'/']).chain(group.bytes())
+        .fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+    let ip = Ipv4Addr::new(ADDRESS_PREFIX[0], ADDRESS_PREFIX[1], (hash >> 8) as u8, hash as u8);
+    let port = PORT_BASE + ((hash >> 16) % PORT_RANGE) as u16;
+    SocketAddrV4::new(ip, port)
+}
+
+// ============================================
+//                 Group Keys
+// ============================================
+
+pub fn generate_key() -> MulticastKey {
+    let mut key = [0u8; KEY_LEN];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+// Hex, keys travel inside colon delimited signaling messages
+pub fn encode_key(key: &MulticastKey) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn decode_key(encoded: &str) -> Option<MulticastKey> {
+    if encoded.len() != KEY_LEN * 2 || !encoded.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&encoded[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
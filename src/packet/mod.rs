@@ -0,0 +1,186 @@
+// ============================================
+//                  Imports
+// ============================================
+use std::collections::HashMap;
+use std::fmt;
+
+// Bumped whenever the header layout changes
+pub const FRAME_VERSION: u8 = 1;
+// Last frame of a transmission, the payload may be empty
+pub const FLAG_END_OF_TRANSMISSION: u8 = 0b0000_0001;
+// Audio of an emergency call, heard in every group
+pub const FLAG_EMERGENCY: u8 = 0b0000_0010;
+// Payload sealed with the sender's group key, see the e2e module
+pub const FLAG_ENCRYPTED: u8 = 0b0000_0100;
+// version, flags, sequence, timestamp, sender length, group length
+const FIXED_HEADER_LEN: usize = 1 + 1 + 2 + 4 + 1 + 1;
+
+// ============================================
+//                 Structures
+// ============================================
+
+// Audio frame sent over the audio data channels
+//
+//  0       1       2               4                               8
+// +-------+-------+---------------+-------------------------------+
+// |version| flags |   sequence    |           timestamp           |
+// +-------+-------+---------------+-------------------------------+
+// |sender len| sender ... |group len| group ... |  opus payload ...
+// +----------+------------+---------+-----------+------------------
+//
+// Integers are big endian. The timestamp counts samples at 48 kHz like
+// RTP does, sender and group are UTF-8 of up to 255 bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioFrame {
+    pub version: u8,
+    pub flags: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub sender: String,
+    pub group: String,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    Truncated,
+    UnsupportedVersion(u8),
+    InvalidUtf8,
+    FieldTooLong(&'static str),
+}
+
+// Sequence and timestamp of the next frame sent to each group
+#[derive(Debug, Default)]
+pub struct FrameSequencer {
+    groups: HashMap<String, (u16, u32)>,
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl AudioFrame {
+    pub fn new(sender: &str, group: &str, sequence: u16, timestamp: u32, payload: Vec<u8>) -> Self {
+        Self {
+            version: FRAME_VERSION,
+            flags: 0,
+            sequence,
+            timestamp,
+            sender: sender.to_string(),
+            group: group.to_string(),
+            payload,
+        }
+    }
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.flags |= flags;
+        self
+    }
+    pub fn is_end_of_transmission(&self) -> bool {
+        self.flags & FLAG_END_OF_TRANSMISSION != 0
+    }
+    pub fn is_emergency(&self) -> bool {
+        self.flags & FLAG_EMERGENCY != 0
+    }
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, FrameError> {
+        let sender = self.sender.as_bytes();
+        let group = self.group.as_bytes();
+        if sender.len() > u8::MAX as usize {
+            return Err(FrameError::FieldTooLong("sender"));
+        }
+        if group.len() > u8::MAX as usize {
+            return Err(FrameError::FieldTooLong("group"));
+        }
+        let mut bytes = Vec::with_capacity(
+            FIXED_HEADER_LEN + sender.len() + group.len() + self.payload.len());
+        bytes.push(self.version);
+        bytes.push(self.flags);
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.push(sender.len() as u8);
+        bytes.extend_from_slice(sender);
+        bytes.push(group.len() as u8);
+        bytes.extend_from_slice(group);
+        bytes.extend_from_slice(&self.payload);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, FrameError> {
+        let mut reader = Reader { bytes };
+        let version = reader.take(1)?[0];
+        if version != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let flags = reader.take(1)?[0];
+        let mut sequence = [0; 2];
+        sequence.copy_from_slice(reader.take(2)?);
+        let mut timestamp = [0; 4];
+        timestamp.copy_from_slice(reader.take(4)?);
+        let sender = reader.take_string()?;
+        let group = reader.take_string()?;
+        Ok(Self {
+            version,
+            flags,
+            sequence: u16::from_be_bytes(sequence),
+            timestamp: u32::from_be_bytes(timestamp),
+            sender,
+            group,
+            payload: reader.bytes.to_vec(),
+        })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FrameError> {
+        if self.bytes.len() < len {
+            return Err(FrameError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+    // Length prefixed UTF-8
+    fn take_string(&mut self) -> Result<String, FrameError> {
+        let len = self.take(1)?[0] as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| FrameError::InvalidUtf8)
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated => write!(f, "audio frame is truncated"),
+            FrameError::UnsupportedVersion(version) =>
+                write!(f, "unsupported audio frame version {}", version),
+            FrameError::InvalidUtf8 => write!(f, "audio frame header is not valid UTF-8"),
+            FrameError::FieldTooLong(field) => write!(f, "audio frame {} is too long", field),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl FrameSequencer {
+    // Numbers the next frame of a group and advances its clock by the
+    // samples it carries
+    pub fn next(&mut self, group: &str, samples: u32) -> (u16, u32) {
+        let (sequence, timestamp) = self.groups.entry(group.to_string()).or_default();
+        let current = (*sequence, *timestamp);
+        *sequence = sequence.wrapping_add(1);
+        *timestamp = timestamp.wrapping_add(samples);
+        current
+    }
+}
+
+// Whether a sequence number comes after another, allowing for wrap around
+pub fn is_newer_sequence(sequence: u16, previous: u16) -> bool {
+    sequence != previous && sequence.wrapping_sub(previous) < 0x8000
+}
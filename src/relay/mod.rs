@@ -0,0 +1,38 @@
+// ============================================
+//              Host Audio Relay
+// Audio frames forwarded by the room host over
+// the signaling WebSocket, for peers that can't
+// reach each other directly (client isolation,
+// VLANs...).
+// ============================================
+
+// Binary WebSocket message wrapping an audio frame
+//
+// +---------+-----------+-------------------+
+// | id len  |  peer id  | audio frame ...   |
+// +---------+-----------+-------------------+
+//
+// Sent to the host the id is the receiver, forwarded by the host it's the
+// sender, so a peer can't pass for another one.
+pub fn encode_envelope(peer_id: &str, frame: &[u8]) -> Option<Vec<u8>> {
+    let peer_id = peer_id.as_bytes();
+    if peer_id.len() > u8::MAX as usize {
+        return None;
+    }
+    let mut envelope = Vec::with_capacity(1 + peer_id.len() + frame.len());
+    envelope.push(peer_id.len() as u8);
+    envelope.extend_from_slice(peer_id);
+    envelope.extend_from_slice(frame);
+    Some(envelope)
+}
+
+pub fn decode_envelope(envelope: &[u8]) -> Option<(String, &[u8])> {
+    let (len, rest) = envelope.split_first()?;
+    let len = *len as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (peer_id, frame) = rest.split_at(len);
+    let peer_id = String::from_utf8(peer_id.to_vec()).ok()?;
+    Some((peer_id, frame))
+}
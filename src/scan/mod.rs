@@ -0,0 +1,115 @@
+// ============================================
+//                 Structures
+// ============================================
+
+// What happens to the other groups while the priority group is busy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityMode {
+    // Other groups are silenced
+    Interrupt,
+    // Other groups keep playing, the playback mixer's ducker attenuates
+    // them like it does for any priority talker
+    Duck,
+}
+
+// Groups monitored at once, like scanning channels on a radio
+#[derive(Debug, Clone)]
+pub struct ScanConfig {
+    pub groups: Vec<String>,
+    pub priority_group: Option<String>,
+    pub mode: PriorityMode,
+}
+
+// Audio heard while scanning, tagged with the group it came from. An
+// emergency call from outside the scanned groups has no group.
+#[derive(Debug, Clone)]
+pub struct ScannedAudio {
+    pub group: Option<String>,
+    pub emergency: bool,
+    pub peer_id: String,
+    pub data: Vec<u8>,
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl ScanConfig {
+    pub fn new(groups: Vec<String>) -> Self {
+        Self {
+            groups,
+            priority_group: None,
+            mode: PriorityMode::Interrupt,
+        }
+    }
+    pub fn with_priority(mut self, group: &str, mode: PriorityMode) -> Self {
+        if !self.groups.iter().any(|g| g == group) {
+            self.groups.push(group.to_string());
+        }
+        self.priority_group = Some(group.to_string());
+        self.mode = mode;
+        self
+    }
+
+    // Whether audio from a group plays while the priority group is busy or not
+    pub fn route(&self, group: &str, priority_active: bool) -> bool {
+        let is_priority = self.priority_group.as_deref() == Some(group);
+        is_priority || !priority_active || self.mode == PriorityMode::Duck
+    }
+    // The group the playback mixer ducks the others for
+    pub fn ducking_group(&self) -> Option<String> {
+        match self.mode {
+            PriorityMode::Duck => self.priority_group.clone(),
+            PriorityMode::Interrupt => None,
+        }
+    }
+}
+
+// ============================================
+//                   Tests
+// ============================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(mode: PriorityMode) -> ScanConfig {
+        ScanConfig::new(vec!["ops".to_string(), "fire".to_string()]).with_priority("medic", mode)
+    }
+
+    #[test]
+    fn priority_group_joins_the_scan_once() {
+        let config = scan(PriorityMode::Interrupt).with_priority("medic", PriorityMode::Duck);
+        assert_eq!(config.groups, vec!["ops", "fire", "medic"]);
+        assert_eq!(config.priority_group.as_deref(), Some("medic"));
+        assert_eq!(config.mode, PriorityMode::Duck);
+    }
+
+    #[test]
+    fn route_per_mode() {
+        // (mode, group, priority active, plays)
+        let cases = [
+            (PriorityMode::Interrupt, "ops", false, true),
+            (PriorityMode::Interrupt, "ops", true, false),
+            (PriorityMode::Interrupt, "medic", true, true),
+            (PriorityMode::Interrupt, "medic", false, true),
+            (PriorityMode::Duck, "ops", false, true),
+            (PriorityMode::Duck, "ops", true, true),
+            (PriorityMode::Duck, "medic", true, true),
+        ];
+        for (mode, group, priority_active, expected) in cases {
+            assert_eq!(
+                scan(mode).route(group, priority_active), expected,
+                "{:?} {} priority active {}", mode, group, priority_active
+            );
+        }
+    }
+
+    #[test]
+    fn only_duck_mode_ducks() {
+        assert_eq!(scan(PriorityMode::Duck).ducking_group().as_deref(), Some("medic"));
+        assert_eq!(scan(PriorityMode::Interrupt).ducking_group(), None);
+        let mut plain = ScanConfig::new(vec!["ops".to_string()]);
+        plain.mode = PriorityMode::Duck;
+        assert_eq!(plain.ducking_group(), None);
+    }
+}
@@ -0,0 +1,193 @@
+// ============================================
+//                  Imports
+// ============================================
+use crate::events::PeerStats;
+use crate::packet::{self, AudioFrame};
+use std::time::Instant;
+use webrtc::ice::candidate::CandidatePairState;
+use webrtc::stats::{StatsReport, StatsReportType};
+
+// Frame timestamps count samples at 48 kHz
+const TIMESTAMP_RATE: f64 = 48000.0;
+
+// ============================================
+//                 Structures
+// ============================================
+
+// Audio frames received from one talker in one group
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    last_sequence: Option<u16>,
+    // Arrival time and timestamp of the previous frame, for jitter
+    last_arrival: Option<(Instant, u32)>,
+    pub frames_received: u64,
+    pub frames_lost: u64,
+    // Interarrival jitter as in RFC 3550, in timestamp units
+    jitter: f64,
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl StreamStats {
+    // Records a frame, false if it's a duplicate or arrived too late to play
+    pub fn on_frame(&mut self, frame: &AudioFrame, now: Instant) -> bool {
+        if let Some(previous) = self.last_sequence {
+            if !packet::is_newer_sequence(frame.sequence, previous) {
+                return false;
+            }
+            self.frames_lost += frame.sequence.wrapping_sub(previous).wrapping_sub(1) as u64;
+        }
+        self.last_sequence = Some(frame.sequence);
+        self.frames_received += 1;
+
+        if let Some((arrival, timestamp)) = self.last_arrival {
+            let elapsed = now.duration_since(arrival).as_secs_f64() * TIMESTAMP_RATE;
+            let advanced = frame.timestamp.wrapping_sub(timestamp) as f64;
+            self.jitter += ((elapsed - advanced).abs() - self.jitter) / 16.0;
+        }
+        // Silence between transmissions isn't jitter
+        self.last_arrival = if frame.is_end_of_transmission() {
+            None
+        } else {
+            Some((now, frame.timestamp))
+        };
+        true
+    }
+    pub fn jitter_ms(&self) -> f64 {
+        self.jitter / TIMESTAMP_RATE * 1000.0
+    }
+}
+
+impl PeerStats {
+    // Signal bars from 0 to 4 for a connection quality indicator
+    pub fn signal_bars(&self) -> u8 {
+        let rtt = match self.round_trip_time_ms {
+            Some(rtt) => rtt,
+            None => return 0,
+        };
+        let expected = self.packets_received + self.packets_lost;
+        let loss = if expected == 0 {
+            0.0
+        } else {
+            self.packets_lost as f64 / expected as f64
+        };
+        let jitter = self.jitter_ms.unwrap_or(0.0);
+        if rtt < 100.0 && loss < 0.01 && jitter < 20.0 {
+            4
+        } else if rtt < 200.0 && loss < 0.03 && jitter < 40.0 {
+            3
+        } else if rtt < 400.0 && loss < 0.08 && jitter < 80.0 {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+// ============================================
+//            Stats Report Parsing
+// ============================================
+
+// Fills in what the ICE agent knows from the nominated candidate pair
+pub fn apply_report(stats: &mut PeerStats, report: &StatsReport) {
+    let pair = report.reports.values()
+        .filter_map(|report| match report {
+            StatsReportType::CandidatePair(pair) => Some(pair),
+            _ => None,
+        })
+        .find(|pair| pair.nominated && pair.state == CandidatePairState::Succeeded);
+    let pair = match pair {
+        Some(pair) => pair,
+        None => return,
+    };
+    if pair.current_round_trip_time > 0.0 {
+        stats.round_trip_time_ms = Some(pair.current_round_trip_time * 1000.0);
+    }
+    stats.bytes_sent = pair.bytes_sent;
+    stats.bytes_received = pair.bytes_received;
+
+    let candidate_type = |id: &str| match report.reports.get(id) {
+        Some(StatsReportType::LocalCandidate(candidate))
+        | Some(StatsReportType::RemoteCandidate(candidate)) => Some(candidate.candidate_type.to_string()),
+        _ => None,
+    };
+    if let (Some(local), Some(remote)) = (
+        candidate_type(&pair.local_candidate_id),
+        candidate_type(&pair.remote_candidate_id),
+    ) {
+        stats.candidate_pair = Some(format!("{}/{}", local, remote));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::FLAG_END_OF_TRANSMISSION;
+    use std::time::Duration;
+
+    // 20 ms frames
+    const FRAME_SAMPLES: u32 = 960;
+    const FRAME_TIME: Duration = Duration::from_millis(20);
+
+    fn frame(sequence: u16) -> AudioFrame {
+        AudioFrame::new("alice", "ops", sequence, sequence as u32 * FRAME_SAMPLES, vec![0])
+    }
+
+    #[test]
+    fn frames_on_time_have_no_jitter() {
+        let mut stats = StreamStats::default();
+        let start = Instant::now();
+        for sequence in 0..10 {
+            assert!(stats.on_frame(&frame(sequence), start + FRAME_TIME * sequence as u32));
+        }
+        assert_eq!(stats.frames_received, 10);
+        assert_eq!(stats.frames_lost, 0);
+        assert!(stats.jitter_ms() < 0.001);
+    }
+
+    #[test]
+    fn a_late_frame_adds_a_sixteenth_of_its_delay() {
+        let mut stats = StreamStats::default();
+        let start = Instant::now();
+        stats.on_frame(&frame(0), start);
+        stats.on_frame(&frame(1), start + FRAME_TIME + Duration::from_millis(16));
+        assert!((stats.jitter_ms() - 1.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn gaps_count_as_lost_and_stragglers_are_dropped() {
+        let mut stats = StreamStats::default();
+        let start = Instant::now();
+        assert!(stats.on_frame(&frame(65534), start));
+        // Across the wrap, 65535 and 0 never arrived
+        assert!(stats.on_frame(&frame(1), start + FRAME_TIME * 3));
+        assert!(!stats.on_frame(&frame(1), start + FRAME_TIME * 3));
+        assert!(!stats.on_frame(&frame(0), start + FRAME_TIME * 4));
+        assert_eq!(stats.frames_received, 2);
+        assert_eq!(stats.frames_lost, 2);
+    }
+
+    #[test]
+    fn pauses_between_transmissions_are_not_jitter() {
+        let mut stats = StreamStats::default();
+        let start = Instant::now();
+        stats.on_frame(&frame(0).with_flags(FLAG_END_OF_TRANSMISSION), start);
+        stats.on_frame(&frame(1), start + Duration::from_secs(5));
+        assert_eq!(stats.jitter_ms(), 0.0);
+    }
+
+    #[test]
+    fn signal_bars_follow_the_worst_measure() {
+        let mut stats = PeerStats::default();
+        assert_eq!(stats.signal_bars(), 0);
+        stats.round_trip_time_ms = Some(50.0);
+        stats.packets_received = 1000;
+        assert_eq!(stats.signal_bars(), 4);
+        stats.packets_lost = 50;
+        assert_eq!(stats.signal_bars(), 2);
+        stats.jitter_ms = Some(100.0);
+        assert_eq!(stats.signal_bars(), 1);
+    }
+}
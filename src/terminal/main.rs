@@ -1,20 +1,29 @@
 // ============================================
 //          Dependencies and Imports
 // ============================================
+use std::collections::HashMap;
 use std::io;
 use std::io::Write;
 use rand::Rng;
-#[allow(unused_imports)]
 use wt_tools::audio;
+use wt_tools::auth;
+use wt_tools::call::CallState;
 use wt_tools::communication::WebRTCModule;
 use wt_tools::communication;
 use wt_tools::discovery;
+use wt_tools::events::{EventReceiver, SessionEvent};
+use wt_tools::ice;
+use wt_tools::identity;
 use wt_tools::db;
 use wt_tools::log;
 use wt_tools::metadata;
+use wt_tools::multicast;
+use wt_tools::scan::{PriorityMode, ScanConfig};
+use wt_tools::tls;
 use wt_tools::websocket;
 use wt_tools::websocket::WebSocketStream;
 use dialoguer::{theme::ColorfulTheme, Select};
+use futures::StreamExt;
 use tokio;
 use tokio::time::{sleep, Duration};
 #[allow(unused_imports)]
@@ -36,6 +45,16 @@ async fn main() -> std::io::Result<()> {
     let webrtc_module = WebRTCModule::new(&pool).await.unwrap();
     let mdns = discovery::start_mdns_responder().unwrap();
 
+    // Everything heard in the room plays through the module's mixer
+    let (_, output_device) = audio::initialize_audio_interface();
+    let _playback_stream = output_device.as_ref().and_then(|device| {
+        let config = audio::get_audio_config(device).ok()?;
+        audio::start_playback_stream(device, &config, webrtc_module.playback_mixer()).ok()
+    });
+
+    // Render session events as they happen
+    tokio::spawn(print_session_events(webrtc_module.subscribe()));
+
     // considerar usar HashMap
     let running_rooms = Arc::new(Mutex::new(Vec::new()));
 
@@ -48,9 +67,23 @@ async fn main() -> std::io::Result<()> {
                 //          Create Room
                 // ============================================
                 let room_name = get_input("Enter room name: ");
-                let creator_device_id = get_input("Enter your username: ");
-
-                let metadata = serde_json::json!({
+                let creator_device_id = webrtc_module.device_id().to_string();
+                ask_display_name(&webrtc_module).await;
+                // Large rooms send everything through the host instead of a full mesh
+                let mode = if get_input("Forward audio through this device for a large room? (y/n): ") == "y" {
+                    "sfu"
+                } else {
+                    "mesh"
+                };
+                // Peers have to know it to register, it never goes over the network
+                let room_secret = Some(get_input("Room password (leave empty for none): "))
+                    .filter(|secret| !secret.is_empty());
+
+                let mut metadata = serde_json::json!({
+                    // Peer arbitrating the floor in every group
+                    "host": creator_device_id.clone(),
+                    // "mesh" or "sfu", how peers connect to each other
+                    "mode": mode,
                     // List of all groups
                     "groups":{
                         // Groups names
@@ -67,6 +100,20 @@ async fn main() -> std::io::Result<()> {
                         },
                     },
                 });
+                if room_secret.is_some() {
+                    metadata[auth::PROTECTED_METADATA_KEY] = serde_json::json!(true);
+                }
+                // Signaling over wss://, joiners pin the published fingerprint
+                let mut scheme = "ws";
+                if get_input("Encrypt signaling with TLS? (y/n): ") == "y" {
+                    match websocket_stream.enable_tls().await {
+                        Ok(fingerprint) => {
+                            metadata[tls::TLS_METADATA_KEY] = serde_json::json!(fingerprint);
+                            scheme = "wss";
+                        }
+                        Err(e) => println!("Unable to enable TLS, signaling stays unencrypted: {}", e),
+                    }
+                }
                 
                 // Generate random port number
                 fn generate_port_number() -> u16 {
@@ -81,7 +128,7 @@ async fn main() -> std::io::Result<()> {
                 // Save info to database
                 //Convert the metadata into a hashmap
                 let metadata_map = metadata::json_to_metadata(&metadata.to_string());
-                let (_, ip_address) = discovery::broadcast_service(
+                let (service_info, ip_address) = discovery::broadcast_service(
                     mdns.clone(),
                     &pool,
                     &room_name,
@@ -90,6 +137,30 @@ async fn main() -> std::io::Result<()> {
                     port
                 ).unwrap();
 
+                webrtc_module.set_room_metadata(&room_name, metadata_map.clone()).await;
+                webrtc_module.set_multicast_interface(ip_address).await;
+                websocket_stream.set_room_secret(room_secret.clone()).await;
+                webrtc_module.set_room_secret(room_secret).await;
+
+                // Optional TURN relay for peers on isolated VLANs
+                let mut turn_relay = None;
+                if get_input("Run a TURN relay for isolated networks? (y/n): ") == "y" {
+                    match ice::TurnRelay::start(ip_address, ice::TURN_DEFAULT_PORT).await {
+                        Ok(relay) => {
+                            let mut ice_config = ice::IceConfig::default();
+                            ice_config.servers.push(relay.ice_server());
+                            webrtc_module.set_ice_config(ice_config).await;
+                            let metadata = webrtc_module.room_metadata().await;
+                            if let Err(e) = discovery::update_service_metadata(
+                                mdns.clone(), &service_info, &metadata) {
+                                println!("Unable to publish the TURN relay: {}", e);
+                            }
+                            turn_relay = Some(relay);
+                        }
+                        Err(e) => println!("Unable to start TURN relay: {}", e),
+                    }
+                }
+
                 let websocket_stream_clone = websocket_stream.clone();
                 let mut webrtc_module_clone = webrtc_module.clone();
                 let creator_device_id_clone = creator_device_id.clone();
@@ -97,8 +168,10 @@ async fn main() -> std::io::Result<()> {
                 let addr = format!("{}:{}", ip_address, port); // Use the selected network interface here
 
                 let room_task = tokio::spawn(async move {
+                    // Keeps the relay running as long as the room
+                    let _turn_relay = turn_relay;
                     websocket_stream_clone.start(&addr).await;
-                    let ws_addr = format!("ws://{}", addr);
+                    let ws_addr = format!("{}://{}", scheme, addr);
                     webrtc_module_clone.signaling_loop(
                         &ws_addr,
                         &creator_device_id_clone,
@@ -108,7 +181,7 @@ async fn main() -> std::io::Result<()> {
 
                 running_rooms.lock().unwrap().push(room_task);
 
-                room_menu().await;
+                room_menu(&webrtc_module).await;
 
             }
             1 => {
@@ -229,17 +302,120 @@ async fn start_network_services(
         }
     }
 
-    let device_id = get_input("Enter your username: ");
+    let device_id = webrtc_module.device_id().to_string();
+    ask_display_name(webrtc_module).await;
+    let room_secret = if auth::is_protected(&metadata) {
+        Some(get_input("Room password: "))
+    } else {
+        None
+    };
+
+    // Multicast groups need to know which interface to listen on
+    let has_multicast_groups = initial_groups.iter()
+        .any(|group| multicast::is_multicast_group(&metadata, group));
 
     let mut webrtc_module = webrtc_module.clone();
-    let addr = ws_url.replace("ws://","");
-    websocket_stream.start(&addr).await;
+    webrtc_module.set_room_metadata(&db::room_name_from_url(ws_url), metadata).await;
+    if has_multicast_groups {
+        if let Some(interface) = discovery::select_network_interface() {
+            webrtc_module.set_multicast_interface(interface).await;
+        }
+    }
+    websocket_stream.set_room_secret(room_secret.clone()).await;
+    webrtc_module.set_room_secret(room_secret).await;
+    let addr = ws_url.trim_start_matches("wss://").trim_start_matches("ws://");
+    websocket_stream.start(addr).await;
     webrtc_module.signaling_loop(
         ws_url,
         &device_id, 
         initial_groups).await.unwrap();
 }
 // ============================================
+//          Print Session Events Function
+// ============================================
+async fn print_session_events(mut events: EventReceiver) {
+    // Names to print peers by: <PeerId, DisplayName>
+    let mut names = HashMap::new();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                log::log_message(&format!("Missed {} session events", missed));
+                continue;
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        let previous_name = match &event {
+            SessionEvent::PeerJoined { peer_id, display_name: Some(display_name), .. }
+            | SessionEvent::DisplayNameChanged { peer_id, display_name } => {
+                let previous = identity::display_label(&names, peer_id);
+                names.insert(peer_id.clone(), display_name.clone());
+                Some(previous)
+            }
+            _ => None,
+        };
+        let left = match &event {
+            SessionEvent::PeerLeft { peer_id } => Some(peer_id.clone()),
+            _ => None,
+        };
+        let name = |peer_id: &str| identity::display_label(&names, peer_id);
+        match event {
+            SessionEvent::PeerJoined { peer_id, trusted, .. } => if trusted {
+                println!("{} joined", name(&peer_id))
+            } else {
+                println!("{} joined (unverified device)", name(&peer_id))
+            },
+            SessionEvent::PeerLeft { peer_id } => println!("{} left", name(&peer_id)),
+            SessionEvent::ConnectionStateChanged { peer_id, state } =>
+                println!("Connection to {}: {}", name(&peer_id), state),
+            SessionEvent::GroupMembershipChanged { peer_id, groups } =>
+                println!("{} is now in {}", name(&peer_id), groups.join(", ")),
+            SessionEvent::TalkStarted { peer_id, group } =>
+                println!("[{}] {} is talking", group, name(&peer_id)),
+            SessionEvent::TalkEnded { peer_id, group } =>
+                println!("[{}] {} stopped talking", group, name(&peer_id)),
+            SessionEvent::FloorDenied { group, holder } => match holder {
+                Some(holder) => println!("[{}] Busy, {} has the floor", group, name(&holder)),
+                None => println!("[{}] Busy", group),
+            },
+            SessionEvent::MessageReceived { peer_id, message } =>
+                println!("{}: {}", name(&peer_id), message),
+            SessionEvent::CommandReceived { peer_id, command } =>
+                println!("{} sent {:?}", name(&peer_id), command),
+            SessionEvent::MuteChanged { peer_id, muted } =>
+                println!("{} is {}", name(&peer_id), if muted { "muted" } else { "unmuted" }),
+            SessionEvent::EmergencyStarted { peer_id } =>
+                println!("\x07!!! EMERGENCY CALL FROM {} !!!", name(&peer_id)),
+            SessionEvent::EmergencyEnded { peer_id } =>
+                println!("Emergency call from {} ended", name(&peer_id)),
+            SessionEvent::CallStateChanged { peer_id, state } =>
+                println!("Private call with {}: {:?}", name(&peer_id), state),
+            SessionEvent::TransmitWarning { group, remaining } =>
+                println!("[{}] Transmit ends in {}s", group, remaining.as_secs()),
+            SessionEvent::TransmitCutOff { group, lockout } =>
+                println!("[{}] Transmit cut off, locked out for {}s", group, lockout.as_secs()),
+            // Too chatty for the screen, kept in the log for support
+            SessionEvent::StatsUpdated { peer_id, stats } =>
+                log::log_message(&format!("Stats for {} ({} bars): {:?}",
+                    peer_id, stats.signal_bars(), stats)),
+            SessionEvent::IdentityMismatch { peer_id, expected, presented } =>
+                println!("WARNING: {} is not the device we know ({} instead of {})",
+                    name(&peer_id), presented, expected),
+            SessionEvent::DeviceTrusted { peer_id } => println!("{} is now a trusted device", name(&peer_id)),
+            SessionEvent::DisplayNameChanged { peer_id, .. } => {
+                let previous_name = previous_name.unwrap_or_default();
+                let display_name = name(&peer_id);
+                if previous_name != peer_id && previous_name != display_name {
+                    println!("{} is now {}", previous_name, display_name);
+                }
+            }
+        }
+        if let Some(peer_id) = left {
+            names.remove(&peer_id);
+        }
+    }
+}
+// ============================================
 //          Display Rooms Function
 // ============================================
 fn display_rooms_to_user(rooms: &[discovery::Room]) {
@@ -250,18 +426,24 @@ fn display_rooms_to_user(rooms: &[discovery::Room]) {
 
     println!("Available rooms:");
     for (index, room) in rooms.iter().enumerate() {
-        println!("{}: {} at {}:{}", index + 1, room.name, room.address, room.port);
+        let protected = if auth::is_protected(&room.metadata) { " (password protected)" } else { "" };
+        println!("{}: {} at {}:{}{}", index + 1, room.name, room.address, room.port, protected);
     }
 }
 
 // ============================================
 //          Room Menu Function
 // ============================================
-async fn room_menu() {
+async fn room_menu(webrtc_module: &WebRTCModule) {
+    let mut scan: Option<tokio::task::JoinHandle<()>> = None;
     loop {
         let selections = &[
             "Select Group",
             "Create Group",
+            "Pair Device",
+            "Private Call",
+            if scan.is_some() { "Stop Scan" } else { "Scan Groups" },
+            "Change Display Name",
             "Back to Main Menu",
         ];
 
@@ -285,6 +467,27 @@ async fn room_menu() {
                 todo!()
             }
             2 => {
+                pair_device(webrtc_module).await;
+            }
+            3 => {
+                private_call(webrtc_module).await;
+            }
+            4 => {
+                match scan.take() {
+                    Some(task) => {
+                        stop_scan(webrtc_module, task).await;
+                        println!("Scan stopped");
+                    }
+                    None => scan = start_scan(webrtc_module).await,
+                }
+            }
+            5 => {
+                ask_display_name(webrtc_module).await;
+            }
+            6 => {
+                if let Some(task) = scan.take() {
+                    stop_scan(webrtc_module, task).await;
+                }
                 break;
             }
             _ => {
@@ -294,12 +497,149 @@ async fn room_menu() {
     }
 }
 
+// Both users compare the code on their screens before trusting each other
+async fn pair_device(webrtc_module: &WebRTCModule) {
+    let peers = webrtc_module.peer_list().await;
+    if peers.is_empty() {
+        println!("Nobody to pair with yet");
+        return;
+    }
+    let items: Vec<String> = peers.iter()
+        .map(|peer| if peer.trusted {
+            format!("{} (trusted)", peer.display_name)
+        } else {
+            format!("{} (unverified)", peer.display_name)
+        })
+        .collect();
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Pair with")
+        .default(0)
+        .items(&items)
+        .interact()
+        .unwrap();
+    let peer_id = &peers[selection].peer_id;
+    let display_name = &peers[selection].display_name;
+
+    let code = match webrtc_module.pairing_code(peer_id).await {
+        Some(code) => code,
+        None => {
+            println!("{} hasn't connected yet", display_name);
+            return;
+        }
+    };
+    println!("Pairing code: {}  {}", code.digits, code.emoji.join(" "));
+    if get_input(&format!("Does {} show the same code? (y/n): ", display_name)) == "y" {
+        if let Err(e) = webrtc_module.confirm_pairing(peer_id).await {
+            println!("Unable to pair with {}: {}", display_name, e);
+        }
+    } else {
+        println!("Codes differ, {} stays untrusted", display_name);
+    }
+}
+
+// Monitors several groups at once, naming each talker as they start
+async fn start_scan(webrtc_module: &WebRTCModule) -> Option<tokio::task::JoinHandle<()>> {
+    let groups: Vec<String> = get_input("Groups to scan (comma separated): ")
+        .split(',')
+        .map(|group| group.trim().to_string())
+        .filter(|group| !group.is_empty())
+        .collect();
+    if groups.is_empty() {
+        println!("No groups to scan");
+        return None;
+    }
+    let mut config = ScanConfig::new(groups);
+    let priority = get_input("Priority group (leave empty for none): ");
+    if !priority.is_empty() {
+        let modes = &["Silence the others", "Duck the others"];
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("While the priority group is busy")
+            .default(0)
+            .items(&modes[..])
+            .interact()
+            .unwrap();
+        let mode = if selection == 0 { PriorityMode::Interrupt } else { PriorityMode::Duck };
+        config = config.with_priority(&priority, mode);
+    }
+
+    let mut received = webrtc_module.start_scan(config).await;
+    Some(tokio::spawn(async move {
+        // The audio itself plays through the mixer
+        let mut last_heard = None;
+        while let Some(audio) = received.next().await {
+            let heard = (audio.group.clone(), audio.peer_id.clone());
+            if last_heard.as_ref() == Some(&heard) {
+                continue;
+            }
+            match (&audio.group, audio.emergency) {
+                (None, _) => println!("[scan] EMERGENCY from {}", audio.peer_id),
+                (Some(group), true) => println!("[scan] [{}] EMERGENCY from {}", group, audio.peer_id),
+                (Some(group), false) => println!("[scan] [{}] {}", group, audio.peer_id),
+            }
+            last_heard = Some(heard);
+        }
+    }))
+}
+async fn stop_scan(webrtc_module: &WebRTCModule, task: tokio::task::JoinHandle<()>) {
+    webrtc_module.stop_scan().await;
+    task.abort();
+}
+
+// Answers a ringing call, hangs up an ongoing one, or rings a peer
+async fn private_call(webrtc_module: &WebRTCModule) {
+    if let Some(call) = webrtc_module.current_call().await {
+        let result = if call.state == CallState::Ringing {
+            if get_input(&format!("Accept the call from {}? (y/n): ", call.peer_id)) == "y" {
+                webrtc_module.accept_call().await
+            } else {
+                webrtc_module.decline_call().await
+            }
+        } else if get_input(&format!("Hang up on {}? (y/n): ", call.peer_id)) == "y" {
+            webrtc_module.hang_up().await
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            println!("Call with {} failed: {}", call.peer_id, e);
+        }
+        return;
+    }
+
+    let peers = webrtc_module.peer_list().await;
+    if peers.is_empty() {
+        println!("Nobody to call yet");
+        return;
+    }
+    let items: Vec<&str> = peers.iter().map(|peer| peer.display_name.as_str()).collect();
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Call")
+        .default(0)
+        .items(&items)
+        .interact()
+        .unwrap();
+    // SFU rooms refuse calls to anyone but the host
+    if let Err(e) = webrtc_module.call_peer(&peers[selection].peer_id).await {
+        println!("Unable to call {}: {}", peers[selection].display_name, e);
+    }
+}
+
+// The name others see, the device id peers know us by never changes
+async fn ask_display_name(webrtc_module: &WebRTCModule) {
+    let name = get_input("Enter your display name (leave empty to keep the current one): ");
+    if name.is_empty() {
+        return;
+    }
+    if let Err(e) = webrtc_module.set_display_name(&name).await {
+        println!("Unable to set display name: {}", e);
+    }
+}
+
 async fn join_room(
     websocket_stream: &websocket::WebSocketStream,
     webrtc_module: &communication::WebRTCModule,
     pool: &db::SqlitePool
 ) {
-    let ws_url = get_input("Enter the WebSocket URL (ws://...): ");
+    let ws_url = get_input("Enter the WebSocket URL (ws:// or wss://...): ");
     let metadata = db::get_room_metadata(pool, &ws_url).unwrap_or_default();
 
     start_network_services(
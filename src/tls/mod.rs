@@ -0,0 +1,221 @@
+// ============================================
+//            TLS Signaling Transport
+// The host serves wss:// with a self-signed
+// certificate kept in the db. Its fingerprint is
+// published in the room metadata and clients only
+// accept the certificate matching it, no CA needed.
+// ============================================
+
+// ============================================
+//                  Imports
+// ============================================
+use crate::db;
+use crate::log;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use tokio_tungstenite::{client_async, WebSocketStream};
+
+// Room metadata key holding the signaling certificate's SHA-256 fingerprint
+pub const TLS_METADATA_KEY: &str = "tls_fingerprint";
+// Names the certificate is issued for, clients check the fingerprint instead
+const CERTIFICATE_NAMES: [&str; 1] = ["walkie-talkie.local"];
+
+// ============================================
+//                 Structures
+// ============================================
+
+// Connection to or from the signaling server, with or without TLS
+pub enum SignalingStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+// Accepts the one certificate whose fingerprint the room published
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+// ============================================
+//            Server Certificate
+// ============================================
+
+// Acceptor for the signaling server and the fingerprint to publish. The
+// certificate is created once and reused so clients can keep their pin.
+pub fn load_or_create_acceptor(pool: &db::SqlitePool)
+-> Result<(TlsAcceptor, String), Box<dyn std::error::Error>> {
+    let (certificate, private_key) = match db::load_signaling_certificate(pool)? {
+        Some(identity) => identity,
+        None => {
+            let names: Vec<String> = CERTIFICATE_NAMES.iter().map(|name| name.to_string()).collect();
+            let generated = rcgen::generate_simple_self_signed(names)?;
+            let certificate = generated.cert.der().to_vec();
+            let private_key = generated.key_pair.serialize_der();
+            db::store_signaling_certificate(pool, &certificate, &private_key)?;
+            log::log_message("Created a new signaling certificate");
+            (certificate, private_key)
+        }
+    };
+    let fingerprint = certificate_fingerprint(&certificate);
+
+    let config = ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from(certificate)],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(private_key)),
+        )?;
+    Ok((TlsAcceptor::from(Arc::new(config)), fingerprint))
+}
+
+// SHA-256 of the DER certificate as bare lowercase hex
+pub fn certificate_fingerprint(certificate: &[u8]) -> String {
+    hex::encode(Sha256::digest(certificate))
+}
+
+pub fn published_fingerprint(metadata: &HashMap<String, serde_json::Value>) -> Option<String> {
+    metadata.get(TLS_METADATA_KEY)
+        .and_then(|fingerprint| fingerprint.as_str())
+        .map(|fingerprint| fingerprint.to_lowercase())
+}
+
+// ============================================
+//              Connections
+// ============================================
+
+// Server side, the TLS handshake runs before the WebSocket one
+pub async fn accept(stream: TcpStream, acceptor: Option<&TlsAcceptor>) -> io::Result<SignalingStream> {
+    match acceptor {
+        Some(acceptor) => {
+            let stream = acceptor.accept(stream).await?;
+            Ok(SignalingStream::Tls(Box::new(TlsStream::Server(stream))))
+        }
+        None => Ok(SignalingStream::Plain(stream)),
+    }
+}
+
+// Client side. wss:// URLs need the fingerprint the room published, a
+// certificate that doesn't match it fails the handshake.
+pub async fn connect(url: &str, fingerprint: Option<&str>)
+-> Result<WebSocketStream<SignalingStream>, Box<dyn std::error::Error>> {
+    let (secure, rest) = if let Some(rest) = url.strip_prefix("wss://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("ws://") {
+        (false, rest)
+    } else {
+        return Err(format!("Unsupported signaling URL {}", url).into());
+    };
+    let authority = rest.split('/').next().unwrap_or_default();
+    let tcp_stream = TcpStream::connect(authority).await?;
+
+    let stream = if secure {
+        let fingerprint = fingerprint
+            .ok_or("The room doesn't publish a signaling certificate fingerprint")?;
+        let provider = Arc::new(crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                fingerprint: fingerprint.to_lowercase(),
+                provider,
+            }))
+            .with_no_client_auth();
+        let host = authority.rsplit_once(':').map_or(authority, |(host, _)| host);
+        let server_name = ServerName::try_from(host.to_string())?;
+        let stream = TlsConnector::from(Arc::new(config)).connect(server_name, tcp_stream).await?;
+        SignalingStream::Tls(Box::new(TlsStream::Client(stream)))
+    } else {
+        SignalingStream::Plain(tcp_stream)
+    };
+
+    let (ws_stream, _) = client_async(url, stream).await?;
+    Ok(ws_stream)
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if certificate_fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            log::log_message("Signaling certificate doesn't match the room's fingerprint");
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl AsyncRead for SignalingStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SignalingStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            SignalingStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SignalingStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            SignalingStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            SignalingStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SignalingStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            SignalingStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SignalingStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            SignalingStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+// ============================================
+//                  Imports
+// ============================================
+use std::collections::HashMap;
+
+// Room metadata key holding the topology, "mesh" or "sfu"
+pub const MODE_METADATA_KEY: &str = "mode";
+
+// ============================================
+//                 Structures
+// ============================================
+
+// How the peers of a room are wired together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    // Everyone connects to everyone, fine for small rooms
+    Mesh,
+    // Everyone connects to the room host, which forwards each group's
+    // audio to its members
+    Sfu,
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl Topology {
+    pub fn from_metadata(metadata: &HashMap<String, serde_json::Value>) -> Self {
+        match metadata.get(MODE_METADATA_KEY).and_then(|mode| mode.as_str()) {
+            Some("sfu") => Topology::Sfu,
+            _ => Topology::Mesh,
+        }
+    }
+
+    // The forwarding host, None for a mesh or a room without a host
+    pub fn forwarder<'a>(&self, host: Option<&'a str>) -> Option<&'a str> {
+        match self {
+            Topology::Mesh => None,
+            Topology::Sfu => host,
+        }
+    }
+
+    // Whether we open a peer connection to the remote peer at all
+    pub fn should_connect(&self, host: Option<&str>, local_peer_id: &str, remote_peer_id: &str) -> bool {
+        match self.forwarder(host) {
+            None => true,
+            Some(host) => host == local_peer_id || host == remote_peer_id,
+        }
+    }
+
+    // Groups to open a data channel for on the connection to a peer. The
+    // forwarding host carries every group its clients are in, a client
+    // sends all of its own groups to the host.
+    pub fn channel_groups(
+        &self,
+        host: Option<&str>,
+        local_peer_id: &str,
+        local_groups: &[String],
+        remote_groups: &[String],
+    ) -> Vec<String> {
+        match self.forwarder(host) {
+            Some(host) if host == local_peer_id => remote_groups.to_vec(),
+            Some(_) => local_groups.to_vec(),
+            None => local_groups.iter()
+                .filter(|group| remote_groups.contains(group))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+// ============================================
+//                   Tests
+// ============================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn mode_comes_from_metadata() {
+        let cases = [
+            (Some(serde_json::json!("sfu")), Topology::Sfu),
+            (Some(serde_json::json!("mesh")), Topology::Mesh),
+            (Some(serde_json::json!("star")), Topology::Mesh),
+            (Some(serde_json::json!(1)), Topology::Mesh),
+            (None, Topology::Mesh),
+        ];
+        for (mode, expected) in cases {
+            let metadata: HashMap<String, serde_json::Value> = mode.clone()
+                .map(|mode| (MODE_METADATA_KEY.to_string(), mode))
+                .into_iter()
+                .collect();
+            assert_eq!(Topology::from_metadata(&metadata), expected, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn should_connect_follows_the_forwarder() {
+        // (topology, host, local, remote, expected)
+        let cases = [
+            (Topology::Mesh, Some("host"), "alice", "bob", true),
+            (Topology::Mesh, None, "alice", "bob", true),
+            (Topology::Sfu, None, "alice", "bob", true),
+            (Topology::Sfu, Some("host"), "alice", "bob", false),
+            (Topology::Sfu, Some("host"), "alice", "host", true),
+            (Topology::Sfu, Some("host"), "host", "bob", true),
+        ];
+        for (topology, host, local, remote, expected) in cases {
+            assert_eq!(
+                topology.should_connect(host, local, remote), expected,
+                "{:?} host {:?}: {} -> {}", topology, host, local, remote
+            );
+        }
+    }
+
+    #[test]
+    fn channel_groups_per_role() {
+        let local = groups(&["ops", "medic"]);
+        let remote = groups(&["ops", "fire"]);
+        // (topology, host, local peer, expected)
+        let cases = [
+            (Topology::Mesh, Some("host"), "alice", groups(&["ops"])),
+            (Topology::Sfu, None, "alice", groups(&["ops"])),
+            // The host carries every group of its client
+            (Topology::Sfu, Some("host"), "host", groups(&["ops", "fire"])),
+            // A client sends all of its groups to the host
+            (Topology::Sfu, Some("host"), "alice", groups(&["ops", "medic"])),
+        ];
+        for (topology, host, local_peer_id, expected) in cases {
+            assert_eq!(
+                topology.channel_groups(host, local_peer_id, &local, &remote), expected,
+                "{:?} host {:?} as {}", topology, host, local_peer_id
+            );
+        }
+        assert!(Topology::Mesh.channel_groups(None, "alice", &local, &groups(&["fire"])).is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+// ============================================
+//                  Imports
+// ============================================
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Silence longer than this ends a transmission and resets the timer
+const TRANSMIT_GAP: Duration = Duration::from_secs(1);
+
+// ============================================
+//                 Structures
+// ============================================
+
+// Transmit timeout (TOT) settings for a group
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransmitLimits {
+    // Longest a single transmission may last
+    pub max_duration: Duration,
+    // How long before the cut off the talker is warned
+    pub warning_before: Duration,
+    // How long the talker can't transmit after being cut off
+    pub lockout: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransmitCheck {
+    Allowed,
+    // Time left before the cut off, play the warning tone
+    Warning { remaining: Duration },
+    // The transmission hit the limit and was cut
+    CutOff { lockout: Duration },
+    // Still serving the lockout of a previous cut off
+    LockedOut { remaining: Duration },
+}
+
+#[derive(Debug, Default)]
+struct GroupTransmit {
+    started_at: Option<Instant>,
+    last_sent: Option<Instant>,
+    warned: bool,
+    locked_until: Option<Instant>,
+}
+
+// Keeps stuck push-to-talk buttons from blocking a channel
+#[derive(Debug, Default)]
+pub struct TransmitGuard {
+    groups: HashMap<String, GroupTransmit>,
+}
+
+// ============================================
+//              Implementation
+// ============================================
+
+impl Default for TransmitLimits {
+    fn default() -> Self {
+        Self {
+            max_duration: Duration::from_secs(60),
+            warning_before: Duration::from_secs(5),
+            lockout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl TransmitLimits {
+    pub fn with_max_duration(max_duration: Duration) -> Self {
+        let defaults = Self::default();
+        Self {
+            max_duration,
+            warning_before: defaults.warning_before.min(max_duration / 2),
+            lockout: defaults.lockout,
+        }
+    }
+}
+
+impl TransmitGuard {
+    // Time left on a lockout, None if the group is free to transmit
+    pub fn locked_out(&self, group: &str, now: Instant) -> Option<Duration> {
+        self.groups.get(group)?
+            .locked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    // Called for every packet we are about to send to a group
+    pub fn on_transmit(&mut self, group: &str, limits: &TransmitLimits, now: Instant) -> TransmitCheck {
+        let transmit = self.groups.entry(group.to_string()).or_default();
+        if let Some(until) = transmit.locked_until {
+            if now < until {
+                return TransmitCheck::LockedOut { remaining: until - now };
+            }
+            transmit.locked_until = None;
+        }
+
+        let is_new_transmission = transmit.last_sent
+            .is_none_or(|last| now.duration_since(last) > TRANSMIT_GAP);
+        if is_new_transmission || transmit.started_at.is_none() {
+            transmit.started_at = Some(now);
+            transmit.warned = false;
+        }
+        transmit.last_sent = Some(now);
+
+        let elapsed = transmit.started_at
+            .map_or(Duration::ZERO, |started| now.duration_since(started));
+        if elapsed >= limits.max_duration {
+            transmit.started_at = None;
+            transmit.last_sent = None;
+            transmit.locked_until = Some(now + limits.lockout);
+            return TransmitCheck::CutOff { lockout: limits.lockout };
+        }
+        if !transmit.warned && elapsed + limits.warning_before >= limits.max_duration {
+            transmit.warned = true;
+            return TransmitCheck::Warning { remaining: limits.max_duration - elapsed };
+        }
+        TransmitCheck::Allowed
+    }
+
+    // The talker let go of the button, the next transmission starts fresh
+    pub fn end_transmission(&mut self, group: &str) {
+        if let Some(transmit) = self.groups.get_mut(group) {
+            transmit.started_at = None;
+            transmit.last_sent = None;
+            transmit.warned = false;
+        }
+    }
+}
@@ -8,13 +8,26 @@ use tokio_tungstenite::accept_async;
 use futures::{StreamExt, SinkExt};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 use tokio::sync::Mutex;
+use crate::auth;
+use crate::control;
 use crate::log;
 use crate::db;
 use crate::discovery;
+use crate::relay;
+use crate::tls::{self, SignalingStream};
 use futures::stream::SplitSink;
+use tokio_rustls::TlsAcceptor;
 
-type PeerMap = Arc<Mutex<HashMap<String, Arc<Mutex<SplitSink<tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, Message>>>>>>;
+type PeerSink = Arc<Mutex<SplitSink<tokio_tungstenite::WebSocketStream<SignalingStream>, Message>>>;
+type PeerMap = Arc<Mutex<HashMap<String, PeerSink>>>;
+// <Token, Session>
+type Sessions = Arc<Mutex<HashMap<String, Session>>>;
+
+// How long a dropped peer can resume before everyone is told it left
+const RESUME_GRACE: Duration = Duration::from_secs(30);
 // ============================================
 //                 Structures
 // ============================================
@@ -22,8 +35,21 @@ type PeerMap = Arc<Mutex<HashMap<String, Arc<Mutex<SplitSink<tokio_tungstenite::
 #[derive(Clone)]
 pub struct WebSocketStream {
     peer_map: PeerMap,
+    // Secret peers have to prove they know before registering
+    room_secret: Arc<Mutex<Option<String>>>,
+    // Serves wss:// once set
+    tls_acceptor: Arc<Mutex<Option<TlsAcceptor>>>,
+    sessions: Sessions,
     pool: db::SqlitePool
 }
+
+// Handed out at registration, lets a peer whose socket dropped take its
+// place back without the others noticing
+struct Session {
+    peer_id: String,
+    // Set while the peer is gone
+    disconnected_at: Option<Instant>,
+}
 // ============================================
 //              Implementation
 // ============================================
@@ -32,6 +58,9 @@ impl WebSocketStream {
     pub fn new(pool: db::SqlitePool) -> Self {
         Self {
             peer_map: PeerMap::default(),
+            room_secret: Arc::new(Mutex::new(None)),
+            tls_acceptor: Arc::new(Mutex::new(None)),
+            sessions: Sessions::default(),
             pool
         }
     }
@@ -44,13 +73,36 @@ impl WebSocketStream {
     pub async fn start(&self, addr: &str) {
         let listener = TcpListener::bind(addr).await.expect("Failed to bind");
         log::log_message(&format!("WebSocket server listening on {}", addr));
+        if let Err(e) = db::clear_connected_peers(&self.pool) {
+            log::log_message(&format!("Failed to clear stale peers: {}", e));
+        }
 
         // Continuously accept incoming connections and handle them concurrently
         while let Ok((stream, _addr)) = listener.accept().await {
             let peer_map = self.peer_map.clone();
-            tokio::spawn(handle_connection(peer_map, stream));
+            let room_secret = self.room_secret.lock().await.clone();
+            let tls_acceptor = self.tls_acceptor.lock().await.clone();
+            let sessions = self.sessions.clone();
+            let pool = self.pool.clone();
+            tokio::spawn(async move {
+                match tls::accept(stream, tls_acceptor.as_ref()).await {
+                    Ok(stream) => handle_connection(peer_map, sessions, pool, stream, room_secret).await,
+                    Err(e) => log::log_message(&format!("TLS handshake failed: {}", e)),
+                }
+            });
         }
     }
+    // Serves wss:// from now on, returns the certificate fingerprint clients
+    // have to pin
+    pub async fn enable_tls(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let (acceptor, fingerprint) = tls::load_or_create_acceptor(&self.pool)?;
+        *self.tls_acceptor.lock().await = Some(acceptor);
+        Ok(fingerprint)
+    }
+    // Protects the room, None lets anyone register
+    pub async fn set_room_secret(&self, secret: Option<String>) {
+        *self.room_secret.lock().await = secret;
+    }
     // ============================================
     //              Relay Message
     // Relay a message to a specific peer
@@ -86,13 +138,26 @@ impl WebSocketStream {
 // ============================================
 //            Handle Connection
 // ============================================
-async fn handle_connection(peer_map: PeerMap, raw_stream: tokio::net::TcpStream) {
+async fn handle_connection(peer_map: PeerMap, sessions: Sessions, pool: db::SqlitePool,
+    raw_stream: SignalingStream, room_secret: Option<String>) {
     // Accept the WebSocket Connection
-    let ws_stream = accept_async(raw_stream).await.expect("Failed to accept");
+    let ws_stream = match accept_async(raw_stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            log::log_message(&format!("Failed to accept WebSocket connection: {}", e));
+            return;
+        }
+    };
     // Split the WebSocket
     #[allow(unused_mut)]
     let (mut write, mut read) = ws_stream.split();
     let write = Arc::new(Mutex::new(write));
+    // Peer id this connection registered with
+    let mut registered_peer_id: Option<String> = None;
+    // Registration waiting for the answer to our challenge: (register message, challenge)
+    let mut pending_registration: Option<(String, String)> = None;
+    // Close frame received, the peer left on purpose
+    let mut closed = false;
 
     // Continuously read messages from the stream
     while let Some(result) = read.next().await {
@@ -103,59 +168,116 @@ async fn handle_connection(peer_map: PeerMap, raw_stream: tokio::net::TcpStream)
                     Message::Text(text) => {
                         // Register a peer
                         if text.starts_with("register:") {
-                            // register:{peer_id}:{groups}
-                            let parts: Vec<&str> = text.splitn(3, ':').collect();
-                            let peer_id = parts[1].to_string();
-                            let _groups: Vec<String> = parts[2].split(',').
-                                map(|s| s.to_string()).collect();
-
-                            // Insert the Peer's Id and it's Write Sink into
-                            // the PeerMap HashMap<(String, Sink)>
-                            let mut peers = peer_map.lock().await;
-                            peers.insert(peer_id.clone(), write.clone());
-
-                                        //--------------TODO------------------
-                                        // - Write to the database information
-                                        // - Store User Permissions
-                                        //--------------TODO------------------
-
-                            // Send the list of peers to the newly connected peer
-                            let peer_list = peers.keys().cloned()
-                                .collect::<Vec<String>>().join(",");
-                            let new_peer = peers.get(&peer_id).unwrap();
-                            let mut new_peer = new_peer.lock().await;
-                            new_peer.send(Message::Text(peer_list)).await
-                                .expect("Failed to send peer list");
-
-                            // Notify existing peers about the new peer
-                            for(id, peer) in peers.iter() {
-                                if id != &peer_id {
-                                    let mut peer = peer.lock().await;
-                                    peer.send(Message::Text(
-                                        format!(
-                                            "new_peer:{}:{}",
-                                            peer_id,
-                                            parts[2]
-                                        )
-                                    )).await
-                                        .expect("Failed to notify peers about new peer");
+                            // register:{peer_id}:{groups}:{fingerprint}:{display_name}
+                            let parts: Vec<&str> = text.splitn(5, ':').collect();
+                            if registered_peer_id.is_some() || parts.len() < 3 || parts[1].is_empty() {
+                                continue;
+                            }
+                            // Admin commands address every peer with it
+                            if parts[1] == control::TARGET_ALL {
+                                reject(&write, &format!("peer id {} is reserved", parts[1])).await;
+                                break;
+                            }
+                            if peer_map.lock().await.contains_key(parts[1]) {
+                                reject(&write, &format!("peer id {} is already connected", parts[1])).await;
+                                break;
+                            }
+                            match &room_secret {
+                                // Protected room, the peer proves it knows the secret first
+                                Some(_) => {
+                                    let challenge = auth::generate_challenge();
+                                    if let Err(e) = write.lock().await
+                                        .send(Message::Text(format!("challenge:{}", challenge))).await {
+                                        log::log_message(&format!("Failed to send challenge: {}", e));
+                                        break;
+                                    }
+                                    pending_registration = Some((text.clone(), challenge));
+                                }
+                                None => {
+                                    registered_peer_id = register_peer(&peer_map, &sessions, &pool, &write, &text).await;
                                 }
                             }
 
+                        } else if let Some(response) = text.strip_prefix("auth:") {
+                            // auth:{response}
+                            let (register, challenge) = match pending_registration.take() {
+                                Some(pending) => pending,
+                                None => continue,
+                            };
+                            let peer_id = register.split(':').nth(1).unwrap_or_default();
+                            let secret = room_secret.as_deref().unwrap_or_default();
+                            if !auth::verify(secret, &challenge, peer_id, response) {
+                                log::log_message(&format!("{} failed the room secret challenge", peer_id));
+                                reject(&write, "wrong room password").await;
+                                break;
+                            }
+                            if peer_map.lock().await.contains_key(peer_id) {
+                                reject(&write, &format!("peer id {} is already connected", peer_id)).await;
+                                break;
+                            }
+                            registered_peer_id = register_peer(&peer_map, &sessions, &pool, &write, &register).await;
+
+                        } else if let Some(token) = text.strip_prefix("resume:") {
+                            // resume:{token}
+                            if registered_peer_id.is_some() {
+                                continue;
+                            }
+                            registered_peer_id = resume_session(&peer_map, &sessions, &write, token).await;
+                            if registered_peer_id.is_none() {
+                                // The client registers again from scratch
+                                if let Err(e) = write.lock().await
+                                    .send(Message::Text("session_expired".to_string())).await {
+                                    log::log_message(&format!("Failed to send session expiry: {}", e));
+                                    break;
+                                }
+                            }
+
+                        } else if registered_peer_id.is_none() {
+                            // Nothing but registration until the peer is in
+                            continue;
+                        } else if text.starts_with("group_update:") {
+                            // group_update:{peer_id}:{groups}, only ever for the sender itself
+                            let parts: Vec<&str> = text.splitn(3, ':').collect();
+                            if parts.len() == 3 && registered_peer_id.as_deref() == Some(parts[1]) {
+                                broadcast(&peer_map, parts[1], &text).await;
+                            }
+                        } else if text.starts_with("display_name:") {
+                            // display_name:{peer_id}:{name}, only ever for the sender itself
+                            let parts: Vec<&str> = text.splitn(3, ':').collect();
+                            if parts.len() == 3 && registered_peer_id.as_deref() == Some(parts[1]) {
+                                broadcast(&peer_map, parts[1], &text).await;
+                            }
                         } else {
                             let parts: Vec<&str> = text.splitn(4, ":").collect();
                             if parts.len() == 4 {
                                 let target_peer_id = parts[0];
+                                // Receivers go by the sender field, nobody speaks for someone else
+                                if registered_peer_id.as_deref() != Some(parts[2]) {
+                                    log::log_message(&format!("Dropped a {} message claiming to be from {}",
+                                        parts[1], parts[2]));
+                                    continue;
+                                }
                                 let message_content = format!("{}:{}:{}", parts[1], parts[2], parts[3]);
 
-                                let peers = peer_map.lock().await;
-                                if let Some(peer) = peers.get(target_peer_id) {
-                                    let mut peer = peer.lock().await;
-                                    peer.send(Message::Text(message_content.to_string())).await.expect("Failed to send message");
+                                let peer = peer_map.lock().await.get(target_peer_id).cloned();
+                                if let Some(peer) = peer {
+                                    if let Err(e) = peer.lock().await.send(Message::Text(message_content)).await {
+                                        log::log_message(&format!("Failed to send message to {}: {}", target_peer_id, e));
+                                    }
                                 }
                             }
                         }
                     }
+                    // Audio relayed for peers without a direct path
+                    Message::Binary(data) => {
+                        if let Some(sender) = &registered_peer_id {
+                            relay_audio(&peer_map, sender, &data).await;
+                        }
+                    }
+                    Message::Close(_) => {
+                        closed = true;
+                        break;
+                    }
                     _ => {}
                 }
             },
@@ -165,4 +287,323 @@ async fn handle_connection(peer_map: PeerMap, raw_stream: tokio::net::TcpStream)
             },
         }
     }
+
+    // Socket is gone, tell everyone else
+    if let Some(peer_id) = registered_peer_id {
+        let removed = {
+            let mut peers = peer_map.lock().await;
+            // Only if the entry is still this connection's
+            let is_current = peers.get(&peer_id).is_some_and(|sink| Arc::ptr_eq(sink, &write));
+            is_current && peers.remove(&peer_id).is_some()
+        };
+        if removed && closed {
+            log::log_message(&format!("Peer {} disconnected", peer_id));
+            sessions.lock().await.retain(|_, session| session.peer_id != peer_id);
+            forget_peer(&peer_map, &pool, &peer_id).await;
+        } else if removed {
+            // Probably roaming, give it time to resume
+            log::log_message(&format!("Lost {}, holding its session", peer_id));
+            for session in sessions.lock().await.values_mut() {
+                if session.peer_id == peer_id {
+                    session.disconnected_at = Some(Instant::now());
+                }
+            }
+            tokio::spawn(expire_session(peer_map.clone(), sessions.clone(), pool, peer_id));
+        }
+    }
+}
+// Sends a text message to every registered peer but one
+async fn broadcast(peer_map: &PeerMap, except: &str, text: &str) {
+    let peers: Vec<(String, PeerSink)> = peer_map.lock().await.iter()
+        .filter(|(id, _)| id.as_str() != except)
+        .map(|(id, peer)| (id.clone(), peer.clone()))
+        .collect();
+    for (id, peer) in peers {
+        if let Err(e) = peer.lock().await.send(Message::Text(text.to_string())).await {
+            log::log_message(&format!("Failed to send {} to {}: {}", text, id, e));
+        }
+    }
+}
+// ============================================
+//            Relay Audio
+// ============================================
+async fn relay_audio(peer_map: &PeerMap, sender: &str, envelope: &[u8]) {
+    let (target, frame) = match relay::decode_envelope(envelope) {
+        Some(decoded) => decoded,
+        None => return,
+    };
+    let forwarded = match relay::encode_envelope(sender, frame) {
+        Some(forwarded) => forwarded,
+        None => return,
+    };
+    let peer = peer_map.lock().await.get(&target).cloned();
+    if let Some(peer) = peer {
+        if let Err(e) = peer.lock().await.send(Message::Binary(forwarded)).await {
+            log::log_message(&format!("Failed to relay audio to {}: {}", target, e));
+        }
+    }
+}
+// ============================================
+//            Registration
+// ============================================
+
+// Adds the peer to the map, sends it the peer list and tells everyone else
+async fn register_peer(peer_map: &PeerMap, sessions: &Sessions, pool: &db::SqlitePool,
+    write: &PeerSink, text: &str) -> Option<String> {
+    // register:{peer_id}:{groups}:{fingerprint}:{display_name}
+    let parts: Vec<&str> = text.splitn(5, ':').collect();
+    let peer_id = parts[1].to_string();
+    let groups = parts[2];
+    // DTLS fingerprint, passed on so peers can check it
+    let fingerprint = parts.get(3).copied().unwrap_or_default();
+    let display_name = parts.get(4).copied().unwrap_or_default();
+
+    // Insert the Peer's Id and it's Write Sink into
+    // the PeerMap HashMap<(String, Sink)>
+    let mut peers = peer_map.lock().await;
+    peers.insert(peer_id.clone(), write.clone());
+
+    // Kept until the peer leaves for good, permissions live in the room metadata
+    if let Err(e) = db::store_connected_peer(pool, &peer_id, groups, fingerprint, display_name) {
+        log::log_message(&format!("Failed to store {}: {}", peer_id, e));
+    }
+
+    // Send the list of peers to the newly connected peer
+    let peer_list = peers.keys().cloned()
+        .collect::<Vec<String>>().join(",");
+    drop(peers);
+
+    // Token to resume with, a fresh registration replaces any old session
+    let token = auth::generate_session_token();
+    {
+        let mut sessions = sessions.lock().await;
+        sessions.retain(|_, session| session.peer_id != peer_id);
+        sessions.insert(token.clone(), Session { peer_id: peer_id.clone(), disconnected_at: None });
+    }
+    {
+        let mut write = write.lock().await;
+        for message in [format!("session:{}", token), peer_list] {
+            if let Err(e) = write.send(Message::Text(message)).await {
+                log::log_message(&format!("Failed to send registration to {}: {}", peer_id, e));
+                break;
+            }
+        }
+    }
+
+    // Notify existing peers about the new peer
+    broadcast(
+        peer_map,
+        &peer_id,
+        &format!(
+            "new_peer:{}:{}:{}:{}",
+            peer_id,
+            groups,
+            fingerprint,
+            display_name
+        )
+    ).await;
+    Some(peer_id)
+}
+
+// ============================================
+//            Sessions
+// ============================================
+
+// Puts a returning peer back in the map under its old id. Nobody else is
+// told, their connections to it carry on.
+async fn resume_session(peer_map: &PeerMap, sessions: &Sessions, write: &PeerSink, token: &str) -> Option<String> {
+    let peer_id = {
+        let mut sessions = sessions.lock().await;
+        let session = sessions.get_mut(token)?;
+        session.disconnected_at = None;
+        session.peer_id.clone()
+    };
+    // Replaces the old socket if the server hasn't noticed it died yet
+    peer_map.lock().await.insert(peer_id.clone(), write.clone());
+    if let Err(e) = write.lock().await.send(Message::Text("resumed".to_string())).await {
+        log::log_message(&format!("Failed to confirm session of {}: {}", peer_id, e));
+    }
+    log::log_message(&format!("{} resumed its session", peer_id));
+    Some(peer_id)
+}
+
+// Gives up on a dropped peer that didn't come back in time
+async fn expire_session(peer_map: PeerMap, sessions: Sessions, pool: db::SqlitePool, peer_id: String) {
+    tokio::time::sleep(RESUME_GRACE).await;
+    let expired = {
+        let mut sessions = sessions.lock().await;
+        let before = sessions.len();
+        // A peer that dropped again since has its own timer running
+        sessions.retain(|_, session| session.peer_id != peer_id
+            || session.disconnected_at.is_none_or(|at| at.elapsed() < RESUME_GRACE));
+        sessions.len() != before
+    };
+    if expired && !peer_map.lock().await.contains_key(&peer_id) {
+        log::log_message(&format!("Session of {} expired", peer_id));
+        forget_peer(&peer_map, &pool, &peer_id).await;
+    }
+}
+
+// The peer is gone for good: its row goes and everyone is told
+async fn forget_peer(peer_map: &PeerMap, pool: &db::SqlitePool, peer_id: &str) {
+    if let Err(e) = db::remove_connected_peer(pool, peer_id) {
+        log::log_message(&format!("Failed to remove {}: {}", peer_id, e));
+    }
+    broadcast(peer_map, peer_id, &format!("peer_left:{}", peer_id)).await;
+}
+
+// Tells the client why it can't join, then hangs up
+async fn reject(write: &PeerSink, reason: &str) {
+    let mut write = write.lock().await;
+    if let Err(e) = write.send(Message::Text(format!("rejected:{}", reason))).await {
+        log::log_message(&format!("Failed to send rejection: {}", e));
+    }
+    let _ = write.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream};
+
+    type Client = tokio_tungstenite::WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    // A server on a free local port with its own db
+    async fn start_server() -> (WebSocketStream, String, db::TestDb) {
+        let pool = db::TestDb::new();
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = format!("127.0.0.1:{}", port);
+        let server = WebSocketStream::new((*pool).clone());
+        let running = server.clone();
+        let bind_addr = addr.clone();
+        tokio::spawn(async move { running.start(&bind_addr).await });
+        (server, format!("ws://{}", addr), pool)
+    }
+
+    async fn connect(url: &str) -> Client {
+        loop {
+            match connect_async(url).await {
+                Ok((client, _)) => return client,
+                // Not listening yet
+                Err(_) => tokio::task::yield_now().await,
+            }
+        }
+    }
+
+    async fn next_text(client: &mut Client) -> String {
+        loop {
+            match client.next().await {
+                Some(Ok(Message::Text(text))) => return text,
+                Some(Ok(_)) => continue,
+                other => panic!("Expected a text message, got {:?}", other),
+            }
+        }
+    }
+
+    // Registers and returns the socket with the session token
+    async fn register(url: &str, peer_id: &str) -> (Client, String) {
+        let mut client = connect(url).await;
+        client.send(Message::Text(format!("register:{}:all:fp:{}", peer_id, peer_id))).await.unwrap();
+        let session = next_text(&mut client).await;
+        let token = session.strip_prefix("session:").unwrap().to_string();
+        next_text(&mut client).await;
+        (client, token)
+    }
+
+    #[tokio::test]
+    async fn closing_the_socket_forgets_the_peer() {
+        let (server, url, pool) = start_server().await;
+        let (mut alice, _) = register(&url, "alice").await;
+        let (mut bob, _) = register(&url, "bob").await;
+        assert_eq!(next_text(&mut alice).await, "new_peer:bob:all:fp:bob");
+        assert_eq!(db::load_connected_peers(&pool).unwrap(), vec!["alice", "bob"]);
+
+        bob.close(None).await.unwrap();
+        assert_eq!(next_text(&mut alice).await, "peer_left:bob");
+        assert_eq!(server.get_peer_list().await, vec!["alice"]);
+        assert_eq!(db::load_connected_peers(&pool).unwrap(), vec!["alice"]);
+    }
+
+    // Drops the socket without a close frame, as a phone losing its network
+    // would, and waits until the server notices
+    async fn lose(server: &WebSocketStream, client: Client, peer_id: &str) {
+        drop(client);
+        while server.get_peer_list().await.iter().any(|id| id == peer_id) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn resume(url: &str, token: &str) -> (Client, String) {
+        let mut client = connect(url).await;
+        client.send(Message::Text(format!("resume:{}", token))).await.unwrap();
+        let reply = next_text(&mut client).await;
+        (client, reply)
+    }
+
+    #[tokio::test]
+    async fn resuming_within_the_grace_keeps_the_peer() {
+        let (server, url, pool) = start_server().await;
+        let (mut alice, _) = register(&url, "alice").await;
+        let (bob, token) = register(&url, "bob").await;
+        next_text(&mut alice).await;
+
+        lose(&server, bob, "bob").await;
+        let (_bob, reply) = resume(&url, &token).await;
+        assert_eq!(reply, "resumed");
+        let mut peers = server.get_peer_list().await;
+        peers.sort();
+        assert_eq!(peers, vec!["alice", "bob"]);
+
+        // The expiry timer finds the session in use and leaves it
+        tokio::time::pause();
+        tokio::time::advance(RESUME_GRACE + Duration::from_secs(1)).await;
+        tokio::time::resume();
+        assert!(tokio::time::timeout(Duration::from_millis(100), next_text(&mut alice)).await.is_err());
+        assert_eq!(db::load_connected_peers(&pool).unwrap(), vec!["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn sessions_expire_after_the_grace() {
+        let (server, url, pool) = start_server().await;
+        let (mut alice, _) = register(&url, "alice").await;
+        let (bob, token) = register(&url, "bob").await;
+        next_text(&mut alice).await;
+
+        lose(&server, bob, "bob").await;
+        tokio::time::pause();
+        tokio::time::advance(RESUME_GRACE + Duration::from_secs(1)).await;
+        tokio::time::resume();
+        assert_eq!(next_text(&mut alice).await, "peer_left:bob");
+        assert_eq!(db::load_connected_peers(&pool).unwrap(), vec!["alice"]);
+
+        let (_bob, reply) = resume(&url, &token).await;
+        assert_eq!(reply, "session_expired");
+    }
+
+    #[tokio::test]
+    async fn peers_can_only_speak_for_themselves() {
+        let (_server, url, _pool) = start_server().await;
+        let (mut alice, _) = register(&url, "alice").await;
+        let (mut bob, _) = register(&url, "bob").await;
+        next_text(&mut alice).await;
+
+        for forged in ["bob:display_name:carol:Carol", "group_update:carol:ops", "display_name:carol:Carol"] {
+            alice.send(Message::Text(forged.to_string())).await.unwrap();
+        }
+        alice.send(Message::Text("bob:display_name:alice:Alice".to_string())).await.unwrap();
+        // Messages arrive in order, so the forged ones never got through
+        assert_eq!(next_text(&mut bob).await, "display_name:alice:Alice");
+    }
+
+    #[tokio::test]
+    async fn reserved_and_taken_ids_are_rejected() {
+        let (_server, url, _pool) = start_server().await;
+        let (_alice, _) = register(&url, "alice").await;
+
+        for peer_id in ["all", "alice"] {
+            let mut client = connect(&url).await;
+            client.send(Message::Text(format!("register:{}:all:fp:x", peer_id))).await.unwrap();
+            assert!(next_text(&mut client).await.starts_with("rejected:"));
+        }
+    }
 }
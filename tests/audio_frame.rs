@@ -0,0 +1,68 @@
+use proptest::prelude::*;
+use wt_tools::packet::{self, AudioFrame, FrameError, FLAG_END_OF_TRANSMISSION, FRAME_VERSION};
+
+fn any_frame() -> impl Strategy<Value = AudioFrame> {
+    (
+        any::<u8>(),
+        any::<u16>(),
+        any::<u32>(),
+        "[a-zA-Z0-9_-]{0,64}",
+        "\\PC{0,32}",
+        prop::collection::vec(any::<u8>(), 0..512),
+    )
+        .prop_map(|(flags, sequence, timestamp, sender, group, payload)| {
+            AudioFrame::new(&sender, &group, sequence, timestamp, payload).with_flags(flags)
+        })
+}
+
+proptest! {
+    #[test]
+    fn encoded_frames_decode_to_themselves(frame in any_frame()) {
+        let bytes = frame.encode().unwrap();
+        prop_assert_eq!(AudioFrame::decode(&bytes), Ok(frame));
+    }
+
+    #[test]
+    fn decoding_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..600)) {
+        let _ = AudioFrame::decode(&bytes);
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected(frame in any_frame(), cut in 0usize..1000) {
+        let bytes = frame.encode().unwrap();
+        let header_len = bytes.len() - frame.payload.len();
+        let cut = cut % header_len;
+        prop_assert_eq!(AudioFrame::decode(&bytes[..cut]), Err(FrameError::Truncated));
+    }
+
+    #[test]
+    fn other_versions_are_rejected(frame in any_frame(), version in any::<u8>()) {
+        prop_assume!(version != FRAME_VERSION);
+        let mut bytes = frame.encode().unwrap();
+        bytes[0] = version;
+        prop_assert_eq!(AudioFrame::decode(&bytes), Err(FrameError::UnsupportedVersion(version)));
+    }
+
+    #[test]
+    fn next_sequence_is_newer(sequence in any::<u16>(), step in 1u16..0x8000) {
+        let next = sequence.wrapping_add(step);
+        prop_assert!(packet::is_newer_sequence(next, sequence));
+        prop_assert!(!packet::is_newer_sequence(sequence, next));
+    }
+}
+
+#[test]
+fn end_of_transmission_flag_survives_encoding() {
+    let frame = AudioFrame::new("alice", "all", 7, 960, Vec::new())
+        .with_flags(FLAG_END_OF_TRANSMISSION);
+    let decoded = AudioFrame::decode(&frame.encode().unwrap()).unwrap();
+    assert!(decoded.is_end_of_transmission());
+    assert!(!decoded.is_emergency());
+}
+
+#[test]
+fn oversized_sender_is_rejected() {
+    let sender = "x".repeat(256);
+    let frame = AudioFrame::new(&sender, "all", 0, 0, Vec::new());
+    assert_eq!(frame.encode(), Err(FrameError::FieldTooLong("sender")));
+}